@@ -0,0 +1,515 @@
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_rapier3d::prelude::{RapierContext, QueryFilter};
+use crate::assets::GameState;
+use crate::character::{Armor, Health, ManaPool};
+use crate::damage::{DealDamage, owning_character};
+use crate::enemies::halo::Halo;
+use crate::enemies::spellcasting::{ArchiveSpell, BASE_SPELL_DAMAGE};
+use crate::fps_controller::{FpsController, LogicalPlayer, RenderPlayer};
+use crate::inventory::{Inventory, ItemType};
+use crate::magic::{Spec, Value};
+use crate::magic::bytecode;
+use crate::magic::intrinsics::IntrinsicSet;
+use crate::magic::mana::{Mana, ManaCosts, SpellError};
+use crate::magic::parser;
+use crate::magic::world::{TargetView, WorldView};
+use crate::netcode::{CharacterId, CharacterIds, Message, Peer, Session, SessionInfo};
+use crate::puzzle::{self, Effect, Family};
+use crate::status_effects::{ApplyStatusEffect, StatusEffects};
+use crate::ui::ActiveHotbarSlot;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+/// The most mana a single cast can use, however much the caster has.
+pub const CAST_MANA_LIMIT: Mana = 2000;
+/// How long a status effect from a cast of strength 1 lasts.
+pub const BASE_STATUS_SECONDS: f32 = 8.0;
+/// How far away a spell can hit something.
+pub const CAST_RANGE: f32 = 50.0;
+
+pub struct CastingPlugin;
+
+impl Plugin for CastingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<CastMessage>()
+            .add_event::<SpellCast>()
+            .add_event::<ReplayCast>()
+            .add_system(bind_spells.run_if(in_state(GameState::Ready)))
+            .add_system(cast_spells.run_if(in_state(GameState::Ready)))
+            .add_system(replay_casts.run_if(in_state(GameState::Ready)));
+    }
+}
+
+/// What happened the last time the player bound or cast a spell, which the
+/// UI shows for `CastMessage::SECONDS`.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct CastMessage {
+    pub text: String,
+    /// From `Time::elapsed_seconds`.
+    pub shown_at: f32,
+}
+
+impl CastMessage {
+    pub const SECONDS: f32 = 5.0;
+
+    fn show(&mut self, text: String, time: &Time) {
+        self.text = text;
+        self.shown_at = time.elapsed_seconds();
+    }
+}
+
+/// A spell written into a staff or book, along with the family of puzzles it
+/// was found to solve when it was bound.
+#[derive(Clone, Debug)]
+pub struct BoundSpell {
+    pub spell: Spec,
+    pub family: Family,
+}
+
+/// Sent for every cast that succeeds, so the effect can be shown.
+#[derive(Clone, Debug)]
+pub struct SpellCast {
+    pub caster: Entity,
+    pub effect: Effect,
+    /// Between 1 and 2; cheaper solutions are stronger.
+    pub strength: f32,
+    /// Where the caster was looking from.
+    pub transform: Transform,
+    /// The character the spell hit, if any.
+    pub target: Option<Entity>,
+}
+
+/// Everything that goes into a cast. Peers send these to each other instead
+/// of what the cast did, and work that out for themselves with `run`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CastRecord {
+    pub caster: CharacterId,
+    pub target: Option<CharacterId>,
+    pub spell: Spec,
+    pub family: Family,
+    /// Picks the puzzle the spell has to solve.
+    pub seed: u64,
+    pub budget: Mana,
+    /// The names of the intrinsics the caster was granted, which can differ
+    /// from what other peers were granted.
+    pub intrinsics: Vec<String>,
+    /// What the caster saw, which world queries are answered from.
+    pub world: WorldView,
+    /// Where the caster was looking from.
+    pub transform: Transform,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CastOutcome {
+    pub mana_spent: Mana,
+    pub result: Result<Option<Value>, SpellError>,
+    /// Between 1 and 2 if the spell solved its puzzle; cheaper solutions are
+    /// stronger.
+    pub strength: Option<f32>,
+}
+
+impl CastRecord {
+    /// The outcome only depends on the record, so all peers agree on it.
+    pub fn run(&self) -> CastOutcome {
+        let intrinsics = IntrinsicSet::from_names(self.intrinsics.iter().map(String::as_str));
+        let spell = self.family.optimize(&self.spell, &intrinsics);
+        let program = bytecode::compile(&spell, &ManaCosts::default(), &intrinsics);
+        let puzzle = self.family.generate(&mut ChaCha8Rng::seed_from_u64(self.seed));
+        let mut context = puzzle.inputs.clone();
+        let execution = program.run_in_world(&mut context, self.budget, &self.world);
+        let strength = if execution.result == Ok(Some(puzzle.answer)) {
+            let efficiency = 1.0 - execution.mana_spent as f32 / self.budget.max(1) as f32;
+            Some(1.0 + efficiency)
+        } else {
+            None
+        };
+        CastOutcome {
+            mana_spent: execution.mana_spent,
+            result: execution.result,
+            strength,
+        }
+    }
+}
+
+/// Send this to replay a cast another peer made.
+#[derive(Clone, Debug)]
+pub struct ReplayCast(pub CastRecord);
+
+/// Answers the world queries of spells as they're cast.
+#[derive(SystemParam)]
+pub struct WorldLookup<'w, 's> {
+    characters: Query<'w, 's, (
+        &'static Health,
+        Option<&'static Armor>,
+        Option<&'static StatusEffects>,
+    )>,
+    transforms: Query<'w, 's, &'static GlobalTransform>,
+    players: Query<'w, 's, Entity, With<LogicalPlayer>>,
+    enemies: Query<'w, 's, Entity, With<Halo>>,
+}
+
+impl WorldLookup<'_, '_> {
+    /// What `caster` can see when casting at `target`. Players' enemies are
+    /// the halos, and everyone else's are the players.
+    pub fn view(&self, caster: Entity, target: Option<Entity>) -> WorldView {
+        let position = |entity| {
+            self.transforms.get(entity).ok().map(|transform| transform.translation())
+        };
+        let caster_position = position(caster).unwrap_or(Vec3::ZERO);
+        let distance = |entity| {
+            position(entity).map_or(f32::MAX, |p: Vec3| p.distance(caster_position))
+        };
+        let target = target.and_then(|target| {
+            let (health, armor, effects) = self.characters.get(target).ok()?;
+            Some(TargetView {
+                armor: armor.map_or(0.0, |armor| armor.total(effects)),
+                health: health.current.max(0.0),
+                distance: distance(target),
+                status_effects: effects.map_or_else(Default::default, |effects| {
+                    effects.iter().map(|(effect, _)| effect).collect()
+                }),
+            })
+        });
+        let enemy_distances = if self.players.contains(caster) {
+            self.enemies.iter().map(distance).collect()
+        } else {
+            self.players.iter().map(distance).collect()
+        };
+        WorldView { target, enemy_distances }
+    }
+}
+
+// Pressing B while holding a staff or book binds whatever spell is in the
+// editor to it.
+fn bind_spells(
+    keyboard: Res<Input<KeyCode>>,
+    active_slot: Res<ActiveHotbarSlot>,
+    intrinsics: Option<Res<IntrinsicSet>>,
+    screen_activated: Res<crate::crt::ScreenActivated>,
+    screens: Query<&crate::editor::Screen>,
+    mut players: Query<(&mut Inventory, &FpsController), (With<LogicalPlayer>, Without<Peer>)>,
+    time: Res<Time>,
+    mut message: ResMut<CastMessage>,
+) {
+    if !keyboard.just_pressed(KeyCode::B) {
+        return;
+    }
+    let Ok((mut inventory, controller)) = players.get_single_mut() else { return; };
+    if !controller.enable_input {
+        return;
+    }
+    let Some(ref mut item) = inventory.hotbar[active_slot.index] else { return; };
+    if !matches!(item.item_type, ItemType::Staff | ItemType::Book) {
+        return;
+    }
+    let screen = match screen_activated.entity {
+        Some(entity) => screens.get(entity).ok(),
+        None => screens.iter().next(),
+    };
+    let Some(screen) = screen else { return; };
+    let spell = match parser::parse_document(screen.editor.document()) {
+        Ok(spell) => spell,
+        Err(error) => {
+            warn!("Couldn't bind the spell: {}", error);
+            message.show(format!("Couldn't bind the spell: {}", error), &time);
+            return;
+        },
+    };
+    let intrinsics = intrinsics.map_or_else(IntrinsicSet::default, |intrinsics| (*intrinsics).clone());
+    let classified = puzzle::classify(
+        &spell, rand::thread_rng().gen(), &ManaCosts::default(), &intrinsics, CAST_MANA_LIMIT);
+    match classified {
+        Some((family, _)) => {
+            info!("Bound a spell that solves {:?}", family);
+            message.show(format!("Bound a spell that solves {:?}", family), &time);
+            item.spell = Some(BoundSpell { spell, family });
+        },
+        None => {
+            info!("That spell doesn't solve any puzzle");
+            message.show("That spell doesn't solve any puzzle".to_string(), &time);
+        },
+    }
+}
+
+// What a successful cast does, whether it was made here or replayed.
+#[derive(SystemParam)]
+struct CastEffects<'w> {
+    casts: EventWriter<'w, SpellCast>,
+    damage_events: EventWriter<'w, DealDamage>,
+    status_events: EventWriter<'w, ApplyStatusEffect>,
+    archive_events: EventWriter<'w, ArchiveSpell>,
+}
+
+impl CastEffects<'_> {
+    fn apply(&mut self, caster: Entity, target: Option<Entity>, record: &CastRecord, strength: f32) {
+        self.archive_events.send(ArchiveSpell(record.spell.clone()));
+        let effect = record.family.effect();
+        if let Some(target) = target {
+            match effect {
+                Effect::Status(status_effect) => self.status_events.send(ApplyStatusEffect {
+                    target,
+                    effect: status_effect,
+                    seconds: BASE_STATUS_SECONDS * strength,
+                }),
+                Effect::FleshCircle | Effect::BubblesCircle => self.damage_events.send(DealDamage {
+                    target,
+                    source: Some(caster),
+                    amount: BASE_SPELL_DAMAGE * strength,
+                    status_effect: None,
+                }),
+            }
+        }
+        self.casts.send(SpellCast {
+            caster,
+            effect,
+            strength,
+            transform: record.transform,
+            target,
+        });
+    }
+}
+
+// A cast solves a fresh puzzle from the bound spell's family. Whatever mana it
+// spends is gone even if it gets the answer wrong. Books crumble after one
+// cast.
+fn cast_spells(
+    mouse: Res<Input<MouseButton>>,
+    active_slot: Res<ActiveHotbarSlot>,
+    intrinsics: Option<Res<IntrinsicSet>>,
+    rapier_context: Res<RapierContext>,
+    mut session: ResMut<Session>,
+    cameras: Query<&Transform, (With<RenderPlayer>, Without<Peer>)>,
+    characters: Query<(), With<Health>>,
+    parents: Query<&Parent>,
+    mut players: Query<
+        (Entity, &mut Inventory, &mut ManaPool, &FpsController),
+        (With<LogicalPlayer>, Without<Peer>),
+    >,
+    ids: CharacterIds,
+    world: WorldLookup,
+    mut effects: CastEffects,
+    time: Res<Time>,
+    mut message: ResMut<CastMessage>,
+) {
+    if !mouse.just_pressed(MouseButton::Left) {
+        return;
+    }
+    let Ok((caster, mut inventory, mut mana, controller)) = players.get_single_mut() else {
+        return;
+    };
+    let Ok(camera) = cameras.get_single() else { return; };
+    if !controller.enable_input {
+        return;
+    }
+    let local = session.info.as_ref().map(SessionInfo::id);
+    let Some(caster_id) = ids.id(caster, local.as_ref()) else { return; };
+    let Some(item) = inventory.hotbar[active_slot.index].clone() else { return; };
+    let Some(bound) = item.spell else { return; };
+    if item.item_type == ItemType::Book {
+        inventory.hotbar[active_slot.index] = None;
+    }
+
+    // Only characters that other peers can name can be hit, or they'd
+    // disagree about what the spell did.
+    let target = rapier_context.cast_ray(
+        camera.translation, camera.forward(), CAST_RANGE, true,
+        QueryFilter::default().exclude_rigid_body(caster),
+    ).and_then(|(entity, _)| {
+        let target = owning_character(entity, |entity| characters.contains(entity), &parents)?;
+        Some((target, ids.id(target, local.as_ref())?))
+    });
+
+    let record = CastRecord {
+        caster: caster_id,
+        target: target.as_ref().map(|(_, id)| id.clone()),
+        spell: bound.spell,
+        family: bound.family,
+        seed: rand::thread_rng().gen(),
+        budget: mana.current.min(CAST_MANA_LIMIT),
+        intrinsics: intrinsics.map_or_else(Vec::new, |intrinsics| {
+            intrinsics.iter().map(|intrinsic| intrinsic.name.to_string()).collect()
+        }),
+        world: world.view(caster, target.as_ref().map(|(entity, _)| *entity)),
+        transform: *camera,
+    };
+    let outcome = record.run();
+    mana.current = mana.current.saturating_sub(outcome.mana_spent);
+    if let Some(info) = &mut session.info {
+        info.broadcast(&Message::SpellCast(record.clone()));
+    }
+    match outcome.strength {
+        Some(strength) => effects.apply(caster, target.map(|(entity, _)| entity), &record, strength),
+        None => {
+            let reason = match outcome.result {
+                Err(error) => error.to_string(),
+                Ok(_) => "wrong answer".to_string(),
+            };
+            info!("The spell fizzled: {}", reason);
+            message.show(format!("The spell fizzled: {}", reason), &time);
+        },
+    }
+}
+
+// Casts are replayed with the budget the caster sent, so every peer works out
+// the same strength. A budget no caster could have sent is dropped rather than
+// rewritten. Whatever mana the caster has here is only an estimate, so it
+// doesn't change the outcome.
+fn replay_casts(
+    mut replays: EventReader<ReplayCast>,
+    session: Res<Session>,
+    ids: CharacterIds,
+    mut mana_pools: Query<&mut ManaPool>,
+    mut effects: CastEffects,
+) {
+    let local = session.info.as_ref().map(SessionInfo::id);
+    for ReplayCast(record) in replays.iter() {
+        if record.budget > CAST_MANA_LIMIT {
+            warn!("Dropping a cast with a budget of {} mana", record.budget);
+            continue;
+        }
+        let Some(caster) = ids.entity(&record.caster, local.as_ref()) else { continue; };
+        let target = record.target.as_ref().and_then(|target| ids.entity(target, local.as_ref()));
+        let outcome = record.run();
+        if let Ok(mut mana) = mana_pools.get_mut(caster) {
+            mana.current = mana.current.saturating_sub(outcome.mana_spent);
+        }
+        if let Some(strength) = outcome.strength {
+            effects.apply(caster, target, record, strength);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::{ENEMY_MAX_HEALTH, PLAYER_MANA};
+    use crate::enemies::EnemyId;
+    use crate::magic::StatusEffect;
+    use bevy::ecs::event::Events;
+
+    fn caster() -> Peer {
+        Peer { id: "caster".to_string() }
+    }
+
+    // A peer that sees the caster as a remote player. Spawning in a different
+    // order gives the same characters different entities, and each peer
+    // picks its own level seed.
+    fn peer(enemy_first: bool, level_seed: u64) -> App {
+        let mut app = App::new();
+        app
+            .insert_resource(Session::default())
+            .insert_resource(IntrinsicSet::for_run(level_seed))
+            .add_event::<ReplayCast>()
+            .add_event::<SpellCast>()
+            .add_event::<DealDamage>()
+            .add_event::<ApplyStatusEffect>()
+            .add_event::<ArchiveSpell>()
+            .add_system(replay_casts);
+        let enemy = (EnemyId(0), Health::new(ENEMY_MAX_HEALTH));
+        let player = (LogicalPlayer(1), caster(), ManaPool::new(PLAYER_MANA, 0));
+        if enemy_first {
+            app.world.spawn(enemy);
+            app.world.spawn(player);
+        } else {
+            app.world.spawn(player);
+            app.world.spawn(enemy);
+        }
+        app
+    }
+
+    // What a peer saw the cast do: the damage it dealt and the mana the
+    // caster has left.
+    fn outcome(app: &mut App) -> (Vec<(f32, Option<StatusEffect>)>, Mana) {
+        let events = app.world.resource::<Events<DealDamage>>();
+        let damage = events.get_reader().iter(events)
+            .map(|event| (event.amount, event.status_effect))
+            .collect();
+        let mana = app.world.query::<&ManaPool>().single(&app.world).current;
+        (damage, mana)
+    }
+
+    fn record() -> CastRecord {
+        let mut status_effects = std::collections::BTreeSet::new();
+        status_effects.insert(StatusEffect::Poison);
+        CastRecord {
+            caster: CharacterId::Player(caster()),
+            target: Some(CharacterId::Enemy(EnemyId(0))),
+            spell: parser::parse("c = popcount(x) + target_has_status(0) * 0; return c;").unwrap(),
+            family: Family::Popcount,
+            seed: 0xdeed,
+            budget: CAST_MANA_LIMIT,
+            intrinsics: vec!["popcount".to_string(), "target_has_status".to_string()],
+            world: WorldView {
+                target: Some(TargetView {
+                    armor: 5.0,
+                    health: ENEMY_MAX_HEALTH,
+                    distance: 3.0,
+                    status_effects,
+                }),
+                enemy_distances: vec![3.0],
+            },
+            transform: Transform::default(),
+        }
+    }
+
+    #[test]
+    fn peers_replay_casts_identically() {
+        let record = record();
+        // The second peer gets the record the way it would over the network.
+        let bytes = bincode::serialize(&Message::SpellCast(record.clone())).unwrap();
+        let Message::SpellCast(received) = bincode::deserialize::<Message>(&bytes).unwrap() else {
+            panic!("the message changed on the way");
+        };
+
+        // The peers were granted different intrinsics, and neither got both
+        // of the ones the caster used.
+        let granted = |seed| {
+            let intrinsics = IntrinsicSet::for_run(seed);
+            intrinsics.get("popcount").is_some() && intrinsics.get("target_has_status").is_some()
+        };
+        assert!(!granted(1) && !granted(2));
+        let mut peers = [peer(false, 1), peer(true, 2)];
+        peers[0].world.send_event(ReplayCast(record));
+        peers[1].world.send_event(ReplayCast(received));
+        for app in peers.iter_mut() {
+            app.update();
+        }
+        let outcomes: Vec<_> = peers.iter_mut().map(outcome).collect();
+        assert_eq!(outcomes[0], outcomes[1]);
+        let (ref damage, mana) = outcomes[0];
+        assert_eq!(damage.len(), 1);
+        assert!(damage[0].0 > BASE_SPELL_DAMAGE);
+        assert!(mana < PLAYER_MANA);
+    }
+
+    #[test]
+    fn replays_only_spend_mana_the_caster_has() {
+        // The caster had more mana left than this peer thinks, which doesn't
+        // change what the spell does.
+        let record = record();
+        let cast = record.run();
+        let strength = cast.strength.unwrap();
+        let mut app = peer(false, 1);
+        let mut pool = app.world.query::<&mut ManaPool>();
+        pool.single_mut(&mut app.world).current = 3;
+        app.world.send_event(ReplayCast(record));
+        app.update();
+        let (damage, mana) = outcome(&mut app);
+        assert!(cast.mana_spent > 3);
+        assert_eq!(damage, vec![(BASE_SPELL_DAMAGE * strength, None)]);
+        assert_eq!(mana, 0);
+    }
+
+    #[test]
+    fn replays_drop_budgets_over_the_limit() {
+        let mut app = peer(false, 1);
+        app.world.send_event(ReplayCast(CastRecord { budget: CAST_MANA_LIMIT + 1, ..record() }));
+        app.update();
+        let (damage, mana) = outcome(&mut app);
+        assert!(damage.is_empty());
+        assert_eq!(mana, PLAYER_MANA);
+    }
+}
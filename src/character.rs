@@ -1,17 +1,291 @@
-use std::collections::BTreeSet;
-use std::collections::BTreeMap;
+use bevy::prelude::*;
+use crate::assets::GameState;
+use crate::enemies::halo::Halo;
+use crate::fps_controller::LogicalPlayer;
+use crate::magic::StatusEffect;
+use crate::magic::mana::Mana;
+use crate::status_effects::{StatusEffectTick, StatusEffects};
 
-pub struct Stats {
+pub const PLAYER_MAX_HEALTH: f32 = 100.0;
+pub const ENEMY_MAX_HEALTH: f32 = 50.0;
+pub const PLAYER_MANA: Mana = 5000;
+pub const PLAYER_MANA_PER_SECOND: Mana = 250;
+/// How much exposure it takes to gain the first level of a tolerance. Each
+/// level after that takes as much more again.
+pub const EXPOSURE_PER_LEVEL: f32 = 20.0;
+/// How much of the damage each level of tolerance takes away.
+pub const RESISTANCE_PER_LEVEL: f32 = 0.1;
+/// Tolerance never takes away more of the damage than this.
+pub const MAX_RESISTANCE: f32 = 0.75;
+/// Skin this tough doesn't get stung at all.
+pub const STING_PROOF_SKIN_TOUGHNESS: u32 = 5;
+
+pub struct CharacterPlugin;
+
+impl Plugin for CharacterPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_system(add_characters)
+            .add_system(regenerate_mana.run_if(in_state(GameState::Ready)))
+            .add_system(apply_status_ticks.run_if(in_state(GameState::Ready)))
+            .add_system(clamp_health
+                        .run_if(in_state(GameState::Ready))
+                        .after(apply_status_ticks));
+    }
+}
+
+/// Something that gets better the more it's exposed to.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Tolerance {
+    pub level: u32,
+    /// Exposure since the last level up.
+    pub exposure: f32,
+}
 
-    poison_tolerance: PoisonTolerance,
+impl Tolerance {
+    /// Returns how many levels were gained.
+    pub fn expose(&mut self, amount: f32) -> u32 {
+        let mut gained = 0;
+        self.exposure += amount;
+        while self.exposure >= self.exposure_for_next_level() {
+            self.exposure -= self.exposure_for_next_level();
+            self.level += 1;
+            gained += 1;
+        }
+        gained
+    }
+
+    pub fn exposure_for_next_level(&self) -> f32 {
+        EXPOSURE_PER_LEVEL * (self.level + 1) as f32
+    }
+
+    /// The fraction of damage this takes away.
+    pub fn resistance(&self) -> f32 {
+        (self.level as f32 * RESISTANCE_PER_LEVEL).min(MAX_RESISTANCE)
+    }
+}
+
+#[derive(Component, Clone, Debug, Default)]
+pub struct Stats {
+    pub poison_tolerance: Tolerance,
     // Affected by eating spicy foods, walking on hot surfaces, getting set on
     // fire, etc.
-    fire_tolerance: FireTolerance,
-    skin_toughness: SkinToughness,
+    pub fire_tolerance: Tolerance,
+    pub skin_toughness: Tolerance,
+}
+
+impl Stats {
+    /// The tolerance that protects against `effect`, if there is one.
+    pub fn tolerance_mut(&mut self, effect: StatusEffect) -> Option<&mut Tolerance> {
+        match effect {
+            StatusEffect::Poison => Some(&mut self.poison_tolerance),
+            StatusEffect::Fire => Some(&mut self.fire_tolerance),
+            StatusEffect::Stinging => Some(&mut self.skin_toughness),
+            _ => None,
+        }
+    }
+
+    /// How much of `damage` from `effect` gets through, before armor.
+    pub fn resist(&self, effect: StatusEffect, damage: f32) -> f32 {
+        match effect {
+            StatusEffect::Poison => damage * (1.0 - self.poison_tolerance.resistance()),
+            StatusEffect::Fire => damage * (1.0 - self.fire_tolerance.resistance()),
+            StatusEffect::Stinging if self.skin_toughness.level >= STING_PROOF_SKIN_TOUGHNESS => 0.0,
+            StatusEffect::Stinging => damage * (1.0 - self.skin_toughness.resistance()),
+            _ => damage,
+        }
+    }
+}
+
+#[derive(Component, Clone, Debug)]
+pub struct Health {
+    pub current: f32,
+    /// Max health before status effects.
+    pub base_max: f32,
+}
+
+impl Health {
+    pub fn new(max: f32) -> Self {
+        Health { current: max, base_max: max }
+    }
+
+    /// Never less than 1, however frail the status effects make you.
+    pub fn max(&self, effects: Option<&StatusEffects>) -> f32 {
+        let modifier = effects.map_or(0.0, |effects| effects.max_health_modifier());
+        (self.base_max + modifier).max(1.0)
+    }
+}
+
+#[derive(Component, Clone, Debug, Default)]
+pub struct Armor {
+    /// Armor before status effects.
+    pub base: f32,
+}
+
+impl Armor {
+    pub fn total(&self, effects: Option<&StatusEffects>) -> f32 {
+        let modifier = effects.map_or(0.0, |effects| effects.armor_modifier());
+        (self.base + modifier).max(0.0)
+    }
 }
 
+/// Mana for casting spells, which comes back over time.
+#[derive(Component, Clone, Debug)]
+pub struct ManaPool {
+    pub current: Mana,
+    pub max: Mana,
+    pub per_second: Mana,
+    // Mana regenerated since the last whole point.
+    fraction: f32,
+}
+
+impl ManaPool {
+    pub fn new(max: Mana, per_second: Mana) -> Self {
+        ManaPool { current: max, max, per_second, fraction: 0.0 }
+    }
+
+    pub fn regenerate(&mut self, seconds: f32) {
+        self.fraction += self.per_second as f32 * seconds;
+        let whole = self.fraction.floor();
+        self.fraction -= whole;
+        self.current = self.current.saturating_add(whole as Mana).min(self.max);
+    }
+}
+
+#[derive(Bundle, Clone, Debug)]
 pub struct Character {
-    model: Model,
-    stats: Stats,
-    status_effects: BTreeSet<StatusEffect>,
+    pub health: Health,
+    pub armor: Armor,
+    pub stats: Stats,
+    pub status_effects: StatusEffects,
+}
+
+impl Character {
+    pub fn new(max_health: f32) -> Self {
+        Character {
+            health: Health::new(max_health),
+            armor: Armor::default(),
+            stats: Stats::default(),
+            status_effects: StatusEffects::default(),
+        }
+    }
+}
+
+fn add_characters(
+    mut commands: Commands,
+    players: Query<Entity, (Added<LogicalPlayer>, Without<Health>)>,
+    enemies: Query<Entity, (Added<Halo>, Without<Health>)>,
+) {
+    for entity in players.iter() {
+        commands.entity(entity).insert((
+            Character::new(PLAYER_MAX_HEALTH),
+            ManaPool::new(PLAYER_MANA, PLAYER_MANA_PER_SECOND),
+        ));
+    }
+    for entity in enemies.iter() {
+        commands.entity(entity).insert(Character::new(ENEMY_MAX_HEALTH));
+    }
+}
+
+// Damage over time goes through `damage`; only healing is dealt with here.
+fn apply_status_ticks(
+    mut ticks: EventReader<StatusEffectTick>,
+    mut characters: Query<(&mut Health, Option<&StatusEffects>)>,
+) {
+    for tick in ticks.iter() {
+        if tick.health <= 0.0 {
+            continue;
+        }
+        if let Ok((mut health, effects)) = characters.get_mut(tick.entity) {
+            health.current = (health.current + tick.health).min(health.max(effects));
+        }
+    }
+}
+
+fn regenerate_mana(
+    time: Res<Time>,
+    mut pools: Query<&mut ManaPool>,
+) {
+    for mut pool in pools.iter_mut() {
+        pool.regenerate(time.delta_seconds());
+    }
+}
+
+fn clamp_health(
+    mut characters: Query<(&mut Health, Option<&StatusEffects>), Changed<StatusEffects>>,
+) {
+    for (mut health, effects) in characters.iter_mut() {
+        let max = health.max(effects);
+        if health.current > max {
+            health.current = max;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Every frame takes `seconds`.
+    fn app(seconds: f32) -> App {
+        let mut time = Time::default();
+        time.update();
+        let last_update = time.last_update().unwrap();
+        time.update_with_instant(last_update + Duration::from_secs_f32(seconds));
+        let mut app = App::new();
+        app
+            .insert_resource(time)
+            .add_system(regenerate_mana)
+            .add_system(clamp_health);
+        app
+    }
+
+    #[test]
+    fn tolerance_levels_up_with_exposure() {
+        let mut tolerance = Tolerance::default();
+        assert_eq!(tolerance.expose(15.0), 0);
+        // 20 for the first level, then 40 for the second.
+        assert_eq!(tolerance.expose(50.0), 2);
+        assert_eq!(tolerance, Tolerance { level: 2, exposure: 5.0 });
+        assert_eq!(tolerance.resistance(), 2.0 * RESISTANCE_PER_LEVEL);
+        assert_eq!(Tolerance { level: 20, exposure: 0.0 }.resistance(), MAX_RESISTANCE);
+    }
+
+    #[test]
+    fn mana_regenerates_up_to_the_max() {
+        let mut app = app(0.25);
+        let mut pool = ManaPool::new(100, 10);
+        pool.current = 90;
+        let entity = app.world.spawn(pool).id();
+        let current = |app: &mut App| {
+            app.update();
+            app.world.get::<ManaPool>(entity).unwrap().current
+        };
+        // Half a point is kept over for the next frame.
+        assert_eq!(current(&mut app), 92);
+        assert_eq!(current(&mut app), 95);
+        for _ in 0 .. 10 {
+            current(&mut app);
+        }
+        assert_eq!(current(&mut app), 100);
+    }
+
+    #[test]
+    fn health_is_clamped_when_status_effects_lower_the_max() {
+        let mut app = app(0.25);
+        let entity = app.world.spawn((Health::new(100.0), StatusEffects::default())).id();
+        app.update();
+        assert_eq!(app.world.get::<Health>(entity).unwrap().current, 100.0);
+
+        let mut effects = app.world.get_mut::<StatusEffects>(entity).unwrap();
+        effects.apply(StatusEffect::Frailty, 10.0);
+        app.update();
+        assert_eq!(app.world.get::<Health>(entity).unwrap().current, 75.0);
+
+        // Losing the effect raises the max, but not health itself.
+        *app.world.get_mut::<StatusEffects>(entity).unwrap() = StatusEffects::default();
+        app.update();
+        assert_eq!(app.world.get::<Health>(entity).unwrap().current, 75.0);
+    }
 }
@@ -122,6 +122,7 @@ pub fn create_bubbles_circle(
     fonts: &Res<Assets<Font>>,
     font_atlas_sets: &Res<Assets<FontAtlasSet>>,
     texture_atlases: &Res<Assets<TextureAtlas>>,
+    strength: f32,
 ) {
     let bubbles_size = 0.3;
 
@@ -133,7 +134,7 @@ pub fn create_bubbles_circle(
     let font_texture_atlas: &TextureAtlas =
         texture_atlases.get(&font_atlas.texture_atlas).unwrap();
 
-    let number_of_missiles = 20;
+    let number_of_missiles = (20.0 * strength).round() as usize;
 
     let missile_material = materials.add(StandardMaterial {
         base_color: Color::rgb(1.0, 1.0, 1.0),
@@ -26,6 +26,7 @@ pub fn create_flesh_circle(
     materials: &mut ResMut<Assets<StandardMaterial>>,
     flesh_circle_materials: &mut ResMut<Assets<FleshCircleMaterial>>,
     transform: &Transform,
+    strength: f32,
 ) {
     let laser_bloom = 10.0;
     let laser_material = materials.add(StandardMaterial {
@@ -41,7 +42,7 @@ pub fn create_flesh_circle(
     use rand::Rng;
     let mut rng = rand::thread_rng();
     let mut lasers = Vec::new();
-    let number_of_lasers = 5;
+    let number_of_lasers = (5.0 * strength).round() as usize;
     for i in 0 .. number_of_lasers {
         let r_squared: f32 =
             rng.sample::<f32, _>(rand::distributions::Open01)
@@ -1,6 +1,8 @@
 use crate::assets::{GameState, FontAssets};
+use crate::casting::SpellCast;
 use crate::fps_controller::RenderPlayer;
 use crate::netcode::Peer;
+use crate::puzzle::Effect;
 use bevy::prelude::*;
 use bevy::text::*;
 use bevy_rapier3d::prelude::RapierContext;
@@ -19,7 +21,8 @@ impl Plugin for CirclePlugin {
             .add_system(load_font_atlas.in_schedule(OnEnter(GameState::Ready)))
             .add_system(flesh::update_flesh_circles.run_if(in_state(GameState::Ready)))
             .add_system(bubbles::update_bubbles_circles.run_if(in_state(GameState::Ready)))
-            .add_system(debug_circles.run_if(in_state(GameState::Ready)));
+            .add_system(debug_circles.run_if(in_state(GameState::Ready)))
+            .add_system(cast_circles.run_if(in_state(GameState::Ready)));
     }
 }
 
@@ -57,20 +60,58 @@ pub fn debug_circles(
     texture_atlases: Res<Assets<TextureAtlas>>,
 ) {
     let cam = camera.single();
-    let transform = cam
-        .mul_transform(Transform::from_translation(Vec3::new(0.0, 0.0, -0.5)))
-        .mul_transform(
-            Transform::from_rotation(Quat::from_rotation_x(3.0 * f32::PI() / 2.0)));
+    let transform = circle_transform(cam);
     if keyboard.just_pressed(KeyCode::Key1) {
         flesh::create_flesh_circle(
             &time, &mut commands, &mut meshes, &mut materials,
-            &mut flesh_circle_materials, &transform);
+            &mut flesh_circle_materials, &transform, 1.0);
     }
     if keyboard.just_pressed(KeyCode::Key2) {
         bubbles::create_bubbles_circle(
             &time, &mut commands, &mut meshes, &mut materials,
             &mut bubbles_circle_materials, &transform,
             &rapier_context,
-            &font_assets, &fonts, &font_atlas_sets, &texture_atlases);
+            &font_assets, &fonts, &font_atlas_sets, &texture_atlases, 1.0);
+    }
+}
+
+/// The circle a spell appears in, just in front of its caster.
+pub fn circle_transform(caster_view: &Transform) -> Transform {
+    caster_view
+        .mul_transform(Transform::from_translation(Vec3::new(0.0, 0.0, -0.5)))
+        .mul_transform(
+            Transform::from_rotation(Quat::from_rotation_x(3.0 * f32::PI() / 2.0)))
+}
+
+pub fn cast_circles(
+    time: Res<Time>,
+    mut casts: EventReader<SpellCast>,
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut flesh_circle_materials: ResMut<Assets<flesh::FleshCircleMaterial>>,
+    mut bubbles_circle_materials: ResMut<Assets<bubbles::BubblesCircleMaterial>>,
+
+    rapier_context: Res<RapierContext>,
+
+    font_assets: Res<FontAssets>,
+    fonts: Res<Assets<Font>>,
+    font_atlas_sets: Res<Assets<FontAtlasSet>>,
+    texture_atlases: Res<Assets<TextureAtlas>>,
+) {
+    for cast in casts.iter() {
+        let transform = circle_transform(&cast.transform);
+        match cast.effect {
+            Effect::FleshCircle => flesh::create_flesh_circle(
+                &time, &mut commands, &mut meshes, &mut materials,
+                &mut flesh_circle_materials, &transform, cast.strength),
+            Effect::BubblesCircle => bubbles::create_bubbles_circle(
+                &time, &mut commands, &mut meshes, &mut materials,
+                &mut bubbles_circle_materials, &transform,
+                &rapier_context,
+                &font_assets, &fonts, &font_atlas_sets, &texture_atlases,
+                cast.strength),
+            Effect::Status(_) => {},
+        }
     }
 }
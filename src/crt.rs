@@ -1,8 +1,12 @@
 use bevy::prelude::*;
+use bevy::render::{Extract, ExtractSchedule, RenderApp, RenderSet};
+use bevy::render::render_asset::RenderAssets;
+use bevy::render::renderer::RenderQueue;
 use bevy_mod_outline::OutlineBundle;
 use bevy_rapier3d::prelude::Collider;
 use crate::interact::Interactable;
 use crate::key_translator::TranslatedKey;
+use crate::magic::intrinsics::IntrinsicSet;
 
 pub struct CrtPlugin;
 
@@ -10,9 +14,17 @@ impl Plugin for CrtPlugin {
     fn build(&self, app: &mut App) {
         app
             .insert_resource(ScreenActivated { entity: None })
+            .init_resource::<crate::editor::Clipboard>()
             .add_plugin(MaterialPlugin::<CrtMaterial>::default())
+            .init_resource::<TextureWrites>()
             .add_startup_system(create_screen)
             .add_system(run_editor);
+        if let Ok(render_app) = app.get_sub_app_mut(RenderApp) {
+            render_app
+                .init_resource::<TextureWrites>()
+                .add_system(extract_texture_writes.in_schedule(ExtractSchedule))
+                .add_system(write_textures.in_set(RenderSet::Queue));
+        }
     }
 }
 
@@ -27,6 +39,7 @@ fn create_screen(
     mut crt_materials: ResMut<Assets<CrtMaterial>>,
     mut images: ResMut<Assets<Image>>,
     asset_server: Res<AssetServer>,
+    clipboard: Res<crate::editor::Clipboard>,
 ) {
     use bevy::render::render_resource::*;
 
@@ -43,8 +56,12 @@ fn create_screen(
         overlay_texture: Some(potato),
     });
 
+    let mut editor = crate::editor::Editor::with_filesystem(
+        Box::new(crate::editor::DiskFilesystem::new(crate::editor::SAVE_DIRECTORY)));
+    editor.set_clipboard(clipboard.clone());
+
     commands.spawn((
-        crate::editor::Screen::new(crate::editor::Editor::new()),
+        crate::editor::Screen::new(editor),
         MaterialMeshBundle {
             mesh: meshes.add(
                 Mesh::from(shape::Plane { size: 0.625, ..default() })),
@@ -58,13 +75,99 @@ fn create_screen(
     ));
 }
 
+// Parts of screen textures to overwrite, which go straight to the GPU so that
+// a keystroke doesn't upload the whole screen again. In the render world,
+// these are the writes still waiting for their texture.
+//
+// The main-world `Image` keeps its original data: updating it would mark the
+// asset modified and upload all of it anyway. Whenever it is modified by
+// something else, the GPU copy is rebuilt from it, so `run_editor` writes the
+// whole screen again.
+#[derive(Clone, Default, Resource)]
+struct TextureWrites(Vec<TextureWrite>);
+
+#[derive(Clone)]
+struct TextureWrite {
+    image: Handle<Image>,
+    // In texture pixels.
+    region: crate::editor::Region,
+    // RGBA, a row at a time.
+    data: Vec<u8>,
+}
+
+fn extract_texture_writes(
+    mut pending: ResMut<TextureWrites>,
+    writes: Extract<Res<TextureWrites>>,
+) {
+    pending.0.extend(writes.0.iter().cloned());
+}
+
+fn write_textures(
+    mut pending: ResMut<TextureWrites>,
+    images: Res<RenderAssets<Image>>,
+    render_queue: Res<RenderQueue>,
+) {
+    use bevy::render::render_resource::*;
+
+    pending.0.retain(|write| {
+        let Some(gpu_image) = images.get(&write.image) else { return true; };
+        let region = write.region;
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &gpu_image.texture,
+                mip_level: 0,
+                origin: Origin3d { x: region.x as u32, y: region.y as u32, z: 0 },
+                aspect: TextureAspect::All,
+            },
+            &write.data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(4 * region.width as u32),
+                rows_per_image: None,
+            },
+            Extent3d {
+                width: region.width as u32,
+                height: region.height as u32,
+                depth_or_array_layers: 1,
+            });
+        false
+    });
+}
+
 fn run_editor(
-    mut images: ResMut<Assets<Image>>,
-    mut materials: ResMut<Assets<CrtMaterial>>,
+    materials: Res<Assets<CrtMaterial>>,
+    mut writes: ResMut<TextureWrites>,
     mut screens: Query<(&mut crate::editor::Screen, &Handle<CrtMaterial>)>,
     screen_activated: Res<ScreenActivated>,
     mut keyboard_events: EventReader<TranslatedKey>,
+    mut image_events: EventReader<AssetEvent<Image>>,
+    intrinsics: Option<Res<IntrinsicSet>>,
 ) {
+    // Last frame's writes have been extracted by now.
+    writes.0.clear();
+
+    let modified: Vec<Handle<Image>> = image_events.iter()
+        .filter_map(|event| match event {
+            AssetEvent::Modified { ref handle } => Some(handle.clone()),
+            _ => None,
+        })
+        .collect();
+    if !modified.is_empty() {
+        for (screen, material_handle) in screens.iter() {
+            let image = screen_image(&materials, material_handle);
+            let Some(rasterized) = screen.editor.raster() else { continue; };
+            if modified.contains(&image) {
+                let region = crate::editor::Region {
+                    x: 0,
+                    y: 0,
+                    width: rasterized.width,
+                    height: rasterized.height,
+                };
+                writes.0.push(texture_write(image, rasterized, region));
+            }
+        }
+    }
+
     if let Some(entity) = screen_activated.entity {
         let (mut screen, material_handle) = screens.get_mut(entity).unwrap();
 
@@ -72,6 +175,11 @@ fn run_editor(
 
         for key in keyboard_events.iter() {
             if key.pressed {
+                // Spells started from the editor may use this run's
+                // intrinsics.
+                if let Some(ref intrinsics) = intrinsics {
+                    screen.editor.set_intrinsics((**intrinsics).clone());
+                }
                 screen.editor.process_keypress(key.key);
                 needs_rerender = true;
             }
@@ -83,29 +191,43 @@ fn run_editor(
 
         screen.editor.refresh_screen();
 
-        let rasterized = screen.editor.rasterize().unwrap();
-
-        let image_handle =
-            materials.get_mut(material_handle).unwrap()
-            .color_texture.clone().unwrap();
-        let image: &mut Image = images.get_mut(&image_handle).unwrap();
-
-        {
-            let mut index = 0;
-            for y in 0 .. rasterized.height {
-                for x in 0 .. rasterized.width {
-                    let [a, r, g, b] =
-                        rasterized.get((rasterized.width - 1) - x, y).to_le_bytes();
-                    image.data[index + 0] = r;
-                    image.data[index + 1] = g;
-                    image.data[index + 2] = b;
-                    image.data[index + 3] = a;
-                    index += 4;
-                }
-            }
+        let regions = screen.editor.rasterize().unwrap();
+        let rasterized = screen.editor.raster().unwrap();
+        let image = screen_image(&materials, material_handle);
+
+        for region in regions {
+            writes.0.push(texture_write(image.clone(), rasterized, region));
         }
+    }
+}
 
+fn screen_image(
+    materials: &Assets<CrtMaterial>,
+    material_handle: &Handle<CrtMaterial>,
+) -> Handle<Image> {
+    materials.get(material_handle).unwrap()
+        .color_texture.clone().unwrap()
+}
+
+// Copies a region of the rasterized screen into a write for its texture.
+fn texture_write(
+    image: Handle<Image>,
+    rasterized: &crate::editor::Rasterized,
+    region: crate::editor::Region,
+) -> TextureWrite {
+    // The texture is mirrored left to right.
+    let mut data = Vec::with_capacity(region.width * region.height * 4);
+    for y in region.y .. region.y + region.height {
+        for x in (region.x .. region.x + region.width).rev() {
+            let [a, r, g, b] = rasterized.get(x, y).to_le_bytes();
+            data.extend_from_slice(&[r, g, b, a]);
+        }
     }
+    let region = crate::editor::Region {
+        x: rasterized.width - (region.x + region.width),
+        ..region
+    };
+    TextureWrite { image, region, data }
 }
 
 use bevy::reflect::TypeUuid;
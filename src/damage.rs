@@ -0,0 +1,300 @@
+use bevy::prelude::*;
+use crate::assets::GameState;
+use crate::character::{Armor, Health, Stats};
+use crate::enemies::spellcasting::EnemySpellCast;
+use crate::fps_controller::LogicalPlayer;
+use crate::magic::StatusEffect;
+use crate::projectile::{Projectile, ProjectileImpact};
+use crate::puzzle::Effect;
+use crate::status_effects::{StatusEffectExpired, StatusEffectTick, StatusEffects};
+use std::collections::HashSet;
+
+/// Armor takes away `armor / (armor + ARMOR_SCALE)` of a hit, so this much
+/// armor halves it.
+pub const ARMOR_SCALE: f32 = 50.0;
+
+pub struct DamagePlugin;
+
+impl Plugin for DamagePlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .init_resource::<RunStats>()
+            .add_event::<DealDamage>()
+            .add_event::<Died>()
+            .add_systems(
+                (
+                    projectile_damage.run_if(in_state(GameState::Ready)),
+                    spell_damage.run_if(in_state(GameState::Ready)),
+                    status_effect_damage.run_if(in_state(GameState::Ready)),
+                    resolve_damage.run_if(in_state(GameState::Ready)),
+                    handle_deaths.run_if(in_state(GameState::Ready)),
+                ).chain()
+            );
+    }
+}
+
+/// Send this to hurt something. Damage to an entity without `Health` goes to
+/// its nearest ancestor that has it, so hitting any part of a model counts.
+#[derive(Clone, Debug)]
+pub struct DealDamage {
+    pub target: Entity,
+    pub source: Option<Entity>,
+    pub amount: f32,
+    /// Damage over time from a status effect, which goes around armor but is
+    /// resisted by the matching tolerance. Everything else is a hit, which
+    /// armor reduces.
+    pub status_effect: Option<StatusEffect>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Died {
+    pub entity: Entity,
+    pub killer: Option<Entity>,
+}
+
+/// Kills made by players and deaths of players so far this run.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct RunStats {
+    pub kills: u32,
+    pub deaths: u32,
+}
+
+/// How much of a hit gets through `armor`.
+pub fn armor_reduction(amount: f32, armor: f32) -> f32 {
+    amount * ARMOR_SCALE / (armor.max(0.0) + ARMOR_SCALE)
+}
+
+/// The nearest of `entity` and its ancestors that `is_character` accepts.
+pub fn owning_character(
+    entity: Entity,
+    is_character: impl Fn(Entity) -> bool,
+    parents: &Query<&Parent>,
+) -> Option<Entity> {
+    let mut entity = entity;
+    while !is_character(entity) {
+        entity = parents.get(entity).ok()?.get();
+    }
+    Some(entity)
+}
+
+fn projectile_damage(
+    mut commands: Commands,
+    mut impacts: EventReader<ProjectileImpact>,
+    projectiles: Query<&Projectile>,
+    mut damage_events: EventWriter<DealDamage>,
+) {
+    let mut spent = HashSet::new();
+    for impact in impacts.iter() {
+        if !spent.insert(impact.projectile_entity) {
+            continue;
+        }
+        let Ok(projectile) = projectiles.get(impact.projectile_entity) else {
+            continue;
+        };
+        damage_events.send(DealDamage {
+            target: impact.hit_entity,
+            source: projectile.owner,
+            amount: projectile.damage,
+            status_effect: None,
+        });
+        commands.entity(impact.projectile_entity).despawn_recursive();
+    }
+}
+
+// Spells that cause a status effect only do that, the same as the player's.
+fn spell_damage(
+    mut casts: EventReader<EnemySpellCast>,
+    mut damage_events: EventWriter<DealDamage>,
+) {
+    for cast in casts.iter() {
+        if matches!(cast.effect, Effect::Status(_)) {
+            continue;
+        }
+        damage_events.send(DealDamage {
+            target: cast.target,
+            source: Some(cast.caster),
+            amount: cast.damage,
+            status_effect: None,
+        });
+    }
+}
+
+fn status_effect_damage(
+    mut ticks: EventReader<StatusEffectTick>,
+    mut damage_events: EventWriter<DealDamage>,
+) {
+    for tick in ticks.iter() {
+        if tick.health < 0.0 {
+            damage_events.send(DealDamage {
+                target: tick.entity,
+                source: None,
+                amount: -tick.health,
+                status_effect: Some(tick.effect),
+            });
+        }
+    }
+}
+
+fn resolve_damage(
+    mut damage_events: EventReader<DealDamage>,
+    mut characters: Query<(
+        &mut Health,
+        Option<&Armor>,
+        Option<&mut Stats>,
+        Option<&StatusEffects>,
+    )>,
+    parents: Query<&Parent>,
+    mut deaths: EventWriter<Died>,
+) {
+    // Anything that dies is only dealt with after this, so it could otherwise
+    // die more than once.
+    let mut dead = HashSet::new();
+    for event in damage_events.iter() {
+        let is_character = |entity| characters.contains(entity);
+        let Some(target) = owning_character(event.target, is_character, &parents) else {
+            continue;
+        };
+        if dead.contains(&target) {
+            continue;
+        }
+        let Ok((mut health, armor, stats, effects)) = characters.get_mut(target) else {
+            continue;
+        };
+        let damage = match (event.status_effect, stats) {
+            (Some(effect), Some(mut stats)) => {
+                let damage = stats.resist(effect, event.amount);
+                // Tolerances level up from the damage they let through.
+                if let Some(tolerance) = stats.tolerance_mut(effect) {
+                    tolerance.expose(damage);
+                }
+                damage
+            },
+            (Some(_), None) => event.amount,
+            (None, _) => {
+                let armor = armor.map_or(0.0, |armor| armor.total(effects));
+                armor_reduction(event.amount, armor)
+            },
+        };
+        health.current -= damage;
+        if health.current <= 0.0 {
+            dead.insert(target);
+            deaths.send(Died { entity: target, killer: event.source });
+        }
+    }
+}
+
+// Enemies are removed; players get back up with full health and nothing
+// afflicting them. Only deaths at a player's hands count as kills.
+fn handle_deaths(
+    mut commands: Commands,
+    mut deaths: EventReader<Died>,
+    mut players: Query<(&mut Health, &mut StatusEffects), With<LogicalPlayer>>,
+    mut run_stats: ResMut<RunStats>,
+    mut expired_events: EventWriter<StatusEffectExpired>,
+) {
+    for died in deaths.iter() {
+        match players.get_mut(died.entity) {
+            Ok((mut health, mut effects)) => {
+                info!("Player died");
+                run_stats.deaths += 1;
+                let effects = std::mem::take(&mut *effects);
+                for (effect, _) in effects.iter() {
+                    expired_events.send(StatusEffectExpired { entity: died.entity, effect });
+                }
+                health.current = health.base_max;
+            },
+            Err(_) => {
+                if matches!(died.killer, Some(killer) if players.contains(killer)) {
+                    run_stats.kills += 1;
+                }
+                commands.entity(died.entity).despawn_recursive();
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character::Tolerance;
+
+    fn app() -> App {
+        let mut app = App::new();
+        app
+            .init_resource::<RunStats>()
+            .add_event::<EnemySpellCast>()
+            .add_event::<DealDamage>()
+            .add_event::<Died>()
+            .add_event::<StatusEffectExpired>()
+            .add_systems((spell_damage, resolve_damage, handle_deaths).chain());
+        app
+    }
+
+    fn hit(app: &mut App, target: Entity, source: Option<Entity>, amount: f32, status_effect: Option<StatusEffect>) {
+        app.world.send_event(DealDamage { target, source, amount, status_effect });
+        app.update();
+    }
+
+    fn health(app: &App, entity: Entity) -> f32 {
+        app.world.get::<Health>(entity).unwrap().current
+    }
+
+    #[test]
+    fn armor_reduces_hits_but_not_status_damage() {
+        let mut app = app();
+        let target = app.world.spawn((Health::new(100.0), Armor { base: ARMOR_SCALE })).id();
+        hit(&mut app, target, None, 20.0, None);
+        assert_eq!(health(&app, target), 90.0);
+        hit(&mut app, target, None, 20.0, Some(StatusEffect::Fire));
+        assert_eq!(health(&app, target), 70.0);
+    }
+
+    #[test]
+    fn tolerance_resists_status_damage_and_grows_from_it() {
+        let mut app = app();
+        let stats = Stats {
+            poison_tolerance: Tolerance { level: 2, exposure: 0.0 },
+            ..Default::default()
+        };
+        let target = app.world.spawn((Health::new(100.0), stats)).id();
+        hit(&mut app, target, None, 10.0, Some(StatusEffect::Poison));
+        assert_eq!(health(&app, target), 92.0);
+        let stats = app.world.get::<Stats>(target).unwrap();
+        assert_eq!(stats.poison_tolerance, Tolerance { level: 2, exposure: 8.0 });
+
+        // Only the matching tolerance helps.
+        hit(&mut app, target, None, 10.0, Some(StatusEffect::Fire));
+        assert_eq!(health(&app, target), 82.0);
+    }
+
+    #[test]
+    fn enemy_status_spells_only_apply_the_status() {
+        let mut app = app();
+        let caster = app.world.spawn_empty().id();
+        let target = app.world.spawn(Health::new(100.0)).id();
+        for effect in [Effect::Status(StatusEffect::Fire), Effect::FleshCircle] {
+            app.world.send_event(EnemySpellCast { caster, target, effect, damage: 10.0 });
+        }
+        app.update();
+        assert_eq!(health(&app, target), 90.0);
+    }
+
+    #[test]
+    fn only_kills_by_players_count() {
+        let mut app = app();
+        let player = app.world.spawn((LogicalPlayer(0), Health::new(100.0), StatusEffects::default())).id();
+        let enemies: Vec<Entity> = (0 .. 3).map(|_| app.world.spawn(Health::new(10.0)).id()).collect();
+        hit(&mut app, enemies[0], Some(player), 50.0, None);
+        hit(&mut app, enemies[1], Some(enemies[2]), 50.0, None);
+        hit(&mut app, enemies[2], None, 50.0, Some(StatusEffect::Fire));
+        assert!(enemies.iter().all(|enemy| app.world.get_entity(*enemy).is_none()));
+        let stats = app.world.resource::<RunStats>();
+        assert_eq!((stats.kills, stats.deaths), (1, 0));
+
+        // Players get back up, and dying doesn't count as a kill.
+        hit(&mut app, player, Some(player), 500.0, None);
+        assert_eq!(health(&app, player), 100.0);
+        let stats = app.world.resource::<RunStats>();
+        assert_eq!((stats.kills, stats.deaths), (1, 1));
+    }
+}
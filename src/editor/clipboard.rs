@@ -0,0 +1,62 @@
+// The clipboard shared by every screen, so that players can pass snippets of
+// spells between them. With the `os_clipboard` feature, copies also go to the
+// operating system's clipboard when there is one, and text copied outside the
+// game can be pasted until something is copied inside it.
+
+use bevy::prelude::*;
+use std::sync::{Arc, Mutex};
+
+/// Clones share the same contents.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct Clipboard {
+    contents: Arc<Mutex<String>>,
+    #[cfg(feature = "os_clipboard")]
+    os: Arc<Mutex<OsClipboard>>,
+}
+
+// On X11 the text set on a clipboard is only offered to other programs while
+// that clipboard exists, so one is kept for as long as the game runs.
+#[cfg(feature = "os_clipboard")]
+#[derive(Default)]
+struct OsClipboard(Option<arboard::Clipboard>);
+
+#[cfg(feature = "os_clipboard")]
+impl OsClipboard {
+    // Connects on first use, since every editor starts out with a clipboard
+    // of its own that's usually replaced straight away.
+    fn connect(&mut self) -> Option<&mut arboard::Clipboard> {
+        if self.0.is_none() {
+            self.0 = arboard::Clipboard::new().ok();
+        }
+        self.0.as_mut()
+    }
+}
+
+#[cfg(feature = "os_clipboard")]
+impl std::fmt::Debug for OsClipboard {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_tuple("OsClipboard").field(&self.0.is_some()).finish()
+    }
+}
+
+impl Clipboard {
+    pub fn get(&self) -> String {
+        let contents = self.contents.lock().unwrap().clone();
+        #[cfg(feature = "os_clipboard")]
+        if contents.is_empty() {
+            if let Some(os) = self.os.lock().unwrap().connect() {
+                return os.get_text().unwrap_or_default();
+            }
+        }
+        contents
+    }
+
+    pub fn set(&self, text: &str) {
+        #[cfg(feature = "os_clipboard")]
+        if let Some(os) = self.os.lock().unwrap().connect() {
+            // Failing to reach the OS clipboard still leaves the in-game one.
+            let _ = os.set_text(text);
+        }
+        *self.contents.lock().unwrap() = text.to_string();
+    }
+}
@@ -23,13 +23,36 @@
 
 use crate::editor::FileType;
 use crate::editor::Position;
+use crate::editor::highlighting::Underline;
+use crate::editor::history::{Edit, EditKind, History};
+use unicode_segmentation::UnicodeSegmentation;
 use crate::editor::Row;
 use crate::editor::SearchDirection;
 
 #[derive(Clone, Default)]
 pub struct Document {
     rows: Vec<Row>,
-    dirty: bool,
+    history: History,
+    // Counts changes, so that anything worked out from the contents can tell
+    // when it's out of date.
+    revision: u64,
+}
+
+impl From<&str> for Document {
+    /// The inverse of `contents`, so that saving and opening a document
+    /// leaves it as it was, down to any empty last row.
+    fn from(contents: &str) -> Self {
+        let rows = if contents.is_empty() {
+            Vec::new()
+        } else {
+            contents.split('\n').map(Row::from).collect()
+        };
+        Self {
+            rows,
+            history: History::default(),
+            revision: 0,
+        }
+    }
 }
 
 impl Document {
@@ -45,6 +68,14 @@ impl Document {
         self.rows.len()
     }
 
+    pub fn contents(&self) -> String {
+        self.rows
+            .iter()
+            .map(|row| row.as_str())
+            .collect::<Vec<&str>>()
+            .join("\n")
+    }
+
     fn insert_newline(&mut self, at: &Position) {
         if at.y > self.rows.len() {
             return;
@@ -58,22 +89,57 @@ impl Document {
         self.rows.insert(at.y + 1, new_row);
     }
 
-    pub fn insert(&mut self, at: &Position, c: char) {
-        if at.y > self.rows.len() {
-            return;
-        }
-        self.dirty = true;
-        if c == '\n' {
+    // Inserts without recording it, and returns whether a row was added to
+    // the end.
+    fn insert_text(&mut self, at: &Position, text: &str) -> bool {
+        let appended = at.y == self.rows.len();
+        if text == "\n" {
             self.insert_newline(at);
-        } else if at.y == self.rows.len() {
+        } else if appended {
             let mut row = Row::default();
-            row.insert(0, c);
+            row.insert_str(0, text);
             self.rows.push(row);
         } else {
-            let row = &mut self.rows[at.y];
-            row.insert(at.x, c);
+            self.rows[at.y].insert_str(at.x, text);
+        }
+        self.unhighlight_rows(at.y);
+        self.revision += 1;
+        appended
+    }
+
+    // Deletes without recording it, and returns what was deleted.
+    fn delete_text(&mut self, at: &Position) -> Option<String> {
+        let len = self.rows.len();
+        if at.y >= len {
+            return None;
         }
+        let text = if at.x == self.rows[at.y].len() && at.y + 1 < len {
+            let next_row = self.rows.remove(at.y + 1);
+            self.rows[at.y].append(&next_row);
+            "\n".to_string()
+        } else {
+            let row = &mut self.rows[at.y];
+            let text = row.grapheme(at.x)?.to_string();
+            row.delete(at.x);
+            text
+        };
         self.unhighlight_rows(at.y);
+        self.revision += 1;
+        Some(text)
+    }
+
+    pub fn insert(&mut self, at: &Position, c: char) {
+        if at.y > self.rows.len() {
+            return;
+        }
+        let text = c.to_string();
+        let appended = self.insert_text(at, &text);
+        let after = if c == '\n' {
+            Position { x: 0, y: at.y + 1 }
+        } else {
+            Position { x: at.x + 1, y: at.y }
+        };
+        self.history.record(EditKind::Typing, Edit::Insert { at: at.clone(), text, appended }, at, &after);
     }
 
     fn unhighlight_rows(&mut self, start: usize) {
@@ -84,24 +150,142 @@ impl Document {
     }
 
     pub fn delete(&mut self, at: &Position) {
-        let len = self.rows.len();
-        if at.y >= len {
-            return;
+        if let Some(text) = self.delete_text(at) {
+            self.history.record(EditKind::Deleting, Edit::Delete { at: at.clone(), text }, at, at);
         }
-        self.dirty = true;
-        if at.x == self.rows[at.y].len() && at.y + 1 < len {
-            let next_row = self.rows.remove(at.y + 1);
-            let row = &mut self.rows[at.y];
-            row.append(&next_row);
+    }
+
+    /// Deletes whatever is before `cursor`, and returns where the cursor ends
+    /// up.
+    pub fn backspace(&mut self, cursor: &Position) -> Position {
+        let at = if cursor.x > 0 {
+            Position { x: cursor.x - 1, y: cursor.y }
+        } else if cursor.y > 0 {
+            let y = cursor.y - 1;
+            Position { x: self.rows.get(y).map_or(0, Row::len), y }
         } else {
-            let row = &mut self.rows[at.y];
-            row.delete(at.x);
+            return cursor.clone();
+        };
+        if let Some(text) = self.delete_text(&at) {
+            self.history.record(EditKind::Backspacing, Edit::Delete { at: at.clone(), text }, cursor, &at);
         }
-        self.unhighlight_rows(at.y);
+        at
+    }
+
+    /// The text from `start` up to `end`, with rows joined by newlines.
+    pub fn text(&self, start: &Position, end: &Position) -> String {
+        let mut text = String::new();
+        for y in start.y ..= end.y {
+            let Some(row) = self.rows.get(y) else { break; };
+            let from = if y == start.y { start.x } else { 0 };
+            let to = if y == end.y { end.x } else { row.len() };
+            text.extend(row.as_str().graphemes(true).skip(from).take(to.saturating_sub(from)));
+            if y < end.y {
+                text.push('\n');
+            }
+        }
+        text
+    }
+
+    /// Deletes from `start` up to `end`, to be undone all at once.
+    pub fn delete_range(&mut self, start: &Position, end: &Position) {
+        let length = self.text(start, end).len();
+        let mut deleted = 0;
+        self.history.seal();
+        while deleted < length {
+            let Some(text) = self.delete_text(start) else { break; };
+            deleted += text.len();
+            self.history.record(EditKind::Cutting, Edit::Delete { at: start.clone(), text }, start, start);
+        }
+    }
+
+    /// Inserts `text` at `at`, to be undone all at once, and returns where it
+    /// ends.
+    pub fn insert_str(&mut self, at: &Position, text: &str) -> Position {
+        if at.y > self.rows.len() {
+            return at.clone();
+        }
+        self.history.seal();
+        let mut cursor = at.clone();
+        for grapheme in text.graphemes(true).filter(|grapheme| *grapheme != "\r") {
+            let grapheme = if grapheme == "\r\n" { "\n" } else { grapheme };
+            let appended = self.insert_text(&cursor, grapheme);
+            let after = if grapheme == "\n" {
+                Position { x: 0, y: cursor.y + 1 }
+            } else {
+                Position { x: cursor.x + 1, y: cursor.y }
+            };
+            let edit = Edit::Insert { at: cursor.clone(), text: grapheme.to_string(), appended };
+            self.history.record(EditKind::Pasting, edit, &cursor, &after);
+            cursor = after;
+        }
+        cursor
+    }
+
+    /// Reverts the last group of edits, and returns where the cursor was
+    /// before them.
+    pub fn undo(&mut self) -> Option<Position> {
+        let group = self.history.undo()?;
+        for edit in group.edits.iter().rev() {
+            match edit {
+                Edit::Insert { ref at, appended: true, .. } => {
+                    self.rows.pop();
+                    self.unhighlight_rows(at.y);
+                    self.revision += 1;
+                },
+                Edit::Insert { ref at, .. } => {
+                    self.delete_text(at);
+                },
+                Edit::Delete { ref at, ref text } => {
+                    self.insert_text(at, text);
+                },
+            }
+        }
+        Some(group.cursor_before)
+    }
+
+    /// Applies the last undone group of edits again, and returns where the
+    /// cursor was after them.
+    pub fn redo(&mut self) -> Option<Position> {
+        let group = self.history.redo()?;
+        for edit in group.edits.iter() {
+            match edit {
+                Edit::Insert { ref at, ref text, .. } => {
+                    self.insert_text(at, text);
+                },
+                Edit::Delete { ref at, .. } => {
+                    self.delete_text(at);
+                },
+            }
+        }
+        Some(group.cursor_after)
     }
 
     pub fn is_dirty(&self) -> bool {
-        self.dirty
+        self.history.is_dirty()
+    }
+
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    /// Replaces every underline with one from each `start` up to its `end`.
+    /// Later underlines are drawn over earlier ones.
+    pub fn underline(&mut self, underlines: &[(Position, Position, Underline)]) {
+        for row in &mut self.rows {
+            row.clear_underlines();
+        }
+        for (start, end, underline) in underlines {
+            for (y, row) in self.rows.iter_mut().enumerate().take(end.y + 1).skip(start.y) {
+                let from = if y == start.y { start.x } else { 0 };
+                let to = if y == end.y { end.x } else { row.len() };
+                row.underline(from .. to, *underline);
+            }
+        }
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.history.mark_saved();
     }
 
     pub fn find(&self, query: &str, at: &Position, direction: SearchDirection) -> Option<Position> {
@@ -140,7 +324,12 @@ impl Document {
         None
     }
 
-    pub fn highlight(&mut self, word: &Option<String>, until: Option<usize>) {
+    /// Forgets how every row was highlighted, for when the file type changes.
+    pub fn unhighlight(&mut self) {
+        self.unhighlight_rows(0);
+    }
+
+    pub fn highlight(&mut self, file_type: &FileType, word: &Option<String>, until: Option<usize>) {
         let mut start_with_comment = false;
         let until = if let Some(until) = until {
             if until.saturating_add(1) < self.rows.len() {
@@ -153,8 +342,7 @@ impl Document {
         };
         for row in &mut self.rows[..until] {
             start_with_comment = row.highlight(
-                // FileType::from(".rs").highlighting_options(), // TODO: clean this up
-                FileType::default().highlighting_options(),
+                file_type.highlighting_options(),
                 word,
                 start_with_comment,
             );
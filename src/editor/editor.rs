@@ -21,22 +21,48 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::editor::Clipboard;
 use crate::editor::Document;
+use crate::editor::FileType;
+use crate::editor::Filesystem;
+use crate::editor::MemoryFilesystem;
 use crate::editor::Row;
 use crate::editor::Terminal;
 use crate::editor::Rasterized;
+use crate::editor::Region;
+use crate::editor::highlighting::Underline;
+use crate::magic::{Context, Spec, Value, Variable};
+use crate::magic::checker::{self, Severity};
+use crate::magic::debugger::Debugger;
+use crate::magic::intrinsics::IntrinsicSet;
+use crate::magic::mana::{Mana, ManaCosts};
+use crate::magic::optimizer;
+use crate::magic::parser::{self, parse_with_spans, SpanTable};
+use crate::magic::printer::print_program;
+use crate::puzzle::Family;
 use crate::terminal_key::Key;
 use std::time::Duration;
 use std::time::Instant;
-use std::collections::HashMap;
+use std::collections::BTreeSet;
+use std::ops::Range;
 use bevy::input::Input;
 use bevy::input::keyboard::KeyCode;
 use bevy::time::Time;
 use bevy::render::color::Color;
+use unicode_segmentation::UnicodeSegmentation;
 
 const STATUS_FG_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
 const STATUS_BG_COLOR: Color = Color::rgb(0.94, 0.94, 0.94);
+const CURRENT_LINE_BG_COLOR: Color = Color::rgb(0.2, 0.35, 0.2);
+const BREAKPOINT_BG_COLOR: Color = Color::rgb(0.45, 0.1, 0.1);
 const QUIT_TIMES: u8 = 3;
+const DEBUG_MANA_BUDGET: Mana = 100_000;
+const DEBUG_PANEL_WIDTH: usize = 24;
+const DEBUG_HELP: &str =
+    "F10/n = over | F11/s = into | F5/c = continue | F9/b = breakpoint | Esc = stop";
+const LISTING_HELP: &str = "Up/Down = select | Enter = open | Esc = back";
+const SELECTED_FILE_BG_COLOR: Color = Color::rgb(0.2, 0.2, 0.45);
+const SELECTED_TEXT_BG_COLOR: Color = Color::rgb(0.3, 0.3, 0.55);
 
 #[derive(PartialEq, Copy, Clone)]
 pub enum SearchDirection {
@@ -44,7 +70,7 @@ pub enum SearchDirection {
     Backward,
 }
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
 pub struct Position {
     pub x: usize,
     pub y: usize,
@@ -73,49 +99,142 @@ impl StatusMessage {
 enum PromptMode {
     Save,
     Search,
+    Debug,
+}
+
+// The saved files, shown instead of the document while choosing one to open.
+struct FileListing {
+    names: Vec<String>,
+    selected: usize,
+}
+
+// The optimized spell, shown instead of the document until Esc. It scrolls on
+// its own, since it's usually shorter than the document.
+struct OptimizedView {
+    document: Document,
+    offset: Position,
+}
+
+// The most mana the spell being edited could spend.
+enum ManaEstimate {
+    // The spell doesn't parse.
+    Unknown,
+    AtMost(Mana),
+    Unbounded,
+}
+
+// Something the parser or checker found wrong with the spell.
+struct Problem {
+    start: Position,
+    end: Position,
+    severity: Severity,
+    message: String,
+}
+
+// What the parser and checker made of one revision of the document.
+struct Diagnostics {
+    revision: u64,
+    problems: Vec<Problem>,
+    mana: ManaEstimate,
 }
 
-#[derive(Default)]
 pub struct Editor {
     should_quit: bool,
     quit_times: u8,
     terminal: Terminal,
     cursor_position: Position,
+    // Where shift-selection started; the selection runs from here to the
+    // cursor.
+    selection_anchor: Option<Position>,
     offset: Position,
     document: Document,
     status_message: StatusMessage,
     highlighted_word: Option<String>,
     open_file: String,
-    filesystem: HashMap<String, Document>,
+    file_type: FileType,
+    filesystem: Box<dyn Filesystem>,
+    listing: Option<FileListing>,
+    clipboard: Clipboard,
     prompt_mode: Option<PromptMode>,
     prompt_string: String,
+    breakpoints: BTreeSet<usize>,
+    debugger: Option<Debugger>,
+    intrinsics: IntrinsicSet,
+    optimized: Option<OptimizedView>,
+    // None when the document has to be analyzed again whatever its revision.
+    diagnostics: Option<Diagnostics>,
+}
+
+impl Default for Editor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Editor {
+    /// Saved files only last as long as the editor does.
     pub fn new() -> Self {
+        Self::with_filesystem(Box::new(MemoryFilesystem::default()))
+    }
+
+    pub fn with_filesystem(filesystem: Box<dyn Filesystem>) -> Self {
         let mut initial_status =
-            "HELP: Ctrl-F = find | Ctrl-S = save | Ctrl-Q = quit";
+            "HELP: Ctrl-F find | Ctrl-S save | Ctrl-O open | Ctrl-C/X/V copy/cut/paste | Ctrl-Z undo | Ctrl-Y redo | Ctrl-D debug | Ctrl-P optimize | Ctrl-Q quit";
 
         Self {
             should_quit: false,
             terminal: Terminal::default(),
             document: Document::default(),
             cursor_position: Position::default(),
+            selection_anchor: None,
             offset: Position::default(),
             status_message: StatusMessage::from(initial_status),
             quit_times: QUIT_TIMES,
             highlighted_word: None,
-            open_file: "untitled.txt".to_string(),
-            filesystem: HashMap::new(),
+            open_file: "untitled.spell".to_string(),
+            file_type: FileType::spell(),
+            filesystem,
+            listing: None,
+            clipboard: Clipboard::default(),
             prompt_mode: None,
             prompt_string: "".to_string(),
+            breakpoints: BTreeSet::new(),
+            debugger: None,
+            intrinsics: IntrinsicSet::default(),
+            optimized: None,
+            diagnostics: None,
         }
     }
 
-    pub fn rasterize(&self) -> Option<Rasterized> {
+    /// The intrinsics that spells run in the debugger may call.
+    pub fn set_intrinsics(&mut self, intrinsics: IntrinsicSet) {
+        let names = |set: &IntrinsicSet| set.iter().map(|intrinsic| intrinsic.name).collect::<Vec<_>>();
+        if names(&self.intrinsics) != names(&intrinsics) {
+            self.diagnostics = None;
+        }
+        self.intrinsics = intrinsics;
+    }
+
+    /// Editors given clones of the same clipboard can copy and paste between
+    /// each other.
+    pub fn set_clipboard(&mut self, clipboard: Clipboard) {
+        self.clipboard = clipboard;
+    }
+
+    pub fn document(&self) -> &Document {
+        &self.document
+    }
+
+    /// Rasterizes what changed on screen since the last call, and returns
+    /// where it is in `raster`.
+    pub fn rasterize(&mut self) -> Option<Vec<Region>> {
         self.terminal.rasterize()
     }
 
+    pub fn raster(&self) -> Option<&Rasterized> {
+        self.terminal.raster()
+    }
+
     pub fn refresh_screen(&mut self) {
         self.terminal.cursor_hide();
         self.terminal.set_cursor_position(&Position::default());
@@ -125,14 +244,24 @@ impl Editor {
             self.terminal.carriage_return();
             self.terminal.newline();
         } else {
+            self.analyze();
             self.document.highlight(
+                &self.file_type,
                 &self.highlighted_word,
                 Some(
                     self.offset
                         .y
                         .saturating_add(self.terminal.size().height as usize)),
             );
-            self.draw_rows();
+            if self.debugger.is_some() {
+                self.draw_debugger_rows();
+            } else if self.optimized.is_some() {
+                self.draw_optimized_rows();
+            } else if self.listing.is_some() {
+                self.draw_listing_rows();
+            } else {
+                self.draw_rows();
+            }
             self.draw_status_bar();
             self.draw_message_bar();
             self.terminal.set_cursor_position(&Position {
@@ -153,8 +282,24 @@ impl Editor {
                 self.search_keypress(pressed_key);
                 return;
             },
+            Some(PromptMode::Debug) => {
+                self.debug_prompt_keypress(pressed_key);
+                return;
+            },
             None => {},
         }
+        if self.debugger.is_some() {
+            self.debugger_keypress(pressed_key);
+            return;
+        }
+        if self.optimized.is_some() {
+            self.optimized_keypress(pressed_key);
+            return;
+        }
+        if self.listing.is_some() {
+            self.listing_keypress(pressed_key);
+            return;
+        }
 
         match pressed_key {
             Key::Ctrl('q') => {
@@ -173,22 +318,53 @@ impl Editor {
                 self.status_message =
                     StatusMessage::from("Save as: ");
             },
+            Key::Ctrl('o') => self.show_listing(),
             Key::Ctrl('f') => {
                 self.prompt_mode = Some(PromptMode::Search);
                 self.status_message =
                     StatusMessage::from("Search (ESC to cancel, arrows to navigate): ");
             },
+            Key::Ctrl('d') => {
+                self.prompt_mode = Some(PromptMode::Debug);
+                self.status_message =
+                    StatusMessage::from("Debug with inputs: ");
+            },
+            Key::F(9) => self.toggle_breakpoint(),
+            Key::Ctrl('p') => self.show_optimized(),
+            Key::Ctrl('c') => self.copy(),
+            Key::Ctrl('x') => {
+                self.copy();
+                self.delete_selection();
+            },
+            Key::Ctrl('v') => {
+                self.delete_selection();
+                let text = self.clipboard.get();
+                self.cursor_position = self.document.insert_str(&self.cursor_position, &text);
+            },
             Key::Char(c) => {
+                self.delete_selection();
                 self.document.insert(&self.cursor_position, c);
                 self.move_cursor(Key::Right);
             }
+            Key::Delete | Key::Backspace if self.selection().is_some() => self.delete_selection(),
             Key::Delete => self.document.delete(&self.cursor_position),
             Key::Backspace => {
-                if self.cursor_position.x > 0 || self.cursor_position.y > 0 {
-                    self.move_cursor(Key::Left);
-                    self.document.delete(&self.cursor_position);
-                }
+                self.cursor_position = self.document.backspace(&self.cursor_position);
             }
+            Key::ShiftLeft => self.extend_selection(Key::Left),
+            Key::ShiftRight => self.extend_selection(Key::Right),
+            Key::ShiftUp => self.extend_selection(Key::Up),
+            Key::ShiftDown => self.extend_selection(Key::Down),
+            Key::ShiftHome => self.extend_selection(Key::Home),
+            Key::ShiftEnd => self.extend_selection(Key::End),
+            Key::Ctrl('z') => match self.document.undo() {
+                Some(cursor) => self.cursor_position = cursor,
+                None => self.status_message = StatusMessage::from("Nothing to undo."),
+            },
+            Key::Ctrl('y') => match self.document.redo() {
+                Some(cursor) => self.cursor_position = cursor,
+                None => self.status_message = StatusMessage::from("Nothing to redo."),
+            },
             Key::Up
             | Key::Down
             | Key::Left
@@ -196,9 +372,15 @@ impl Editor {
             | Key::PageUp
             | Key::PageDown
             | Key::End
-            | Key::Home => self.move_cursor(pressed_key),
+            | Key::Home => {
+                self.selection_anchor = None;
+                self.move_cursor(pressed_key);
+            },
             _ => (),
         }
+        if matches!(pressed_key, Key::Ctrl('z') | Key::Ctrl('y')) {
+            self.selection_anchor = None;
+        }
 
         self.scroll();
         if self.quit_times < QUIT_TIMES {
@@ -207,6 +389,39 @@ impl Editor {
         }
     }
 
+    /// The selected text runs from the first position up to the second.
+    fn selection(&self) -> Option<(Position, Position)> {
+        let anchor = self.selection_anchor.clone()?;
+        let cursor = self.cursor_position.clone();
+        match (anchor.y, anchor.x).cmp(&(cursor.y, cursor.x)) {
+            std::cmp::Ordering::Less => Some((anchor, cursor)),
+            std::cmp::Ordering::Equal => None,
+            std::cmp::Ordering::Greater => Some((cursor, anchor)),
+        }
+    }
+
+    fn extend_selection(&mut self, key: Key) {
+        if self.selection_anchor.is_none() {
+            self.selection_anchor = Some(self.cursor_position.clone());
+        }
+        self.move_cursor(key);
+    }
+
+    fn copy(&mut self) {
+        if let Some((start, end)) = self.selection() {
+            self.clipboard.set(&self.document.text(&start, &end));
+        }
+    }
+
+    fn delete_selection(&mut self) {
+        let selection = self.selection();
+        self.selection_anchor = None;
+        if let Some((start, end)) = selection {
+            self.document.delete_range(&start, &end);
+            self.cursor_position = start;
+        }
+    }
+
     fn save_keypress(&mut self, pressed_key: Key) {
         match pressed_key {
             Key::Esc => {
@@ -215,13 +430,23 @@ impl Editor {
                 return;
             },
             Key::Char('\n') => {
-                self.open_file = self.prompt_string.clone();
                 self.prompt_mode = None;
-                self.prompt_string = "".to_string();
-                self.filesystem.insert(self.open_file.clone(),
-                                       self.document.clone());
-                self.status_message =
-                    StatusMessage::from("File saved successfully.");
+                let name = std::mem::take(&mut self.prompt_string);
+                match self.filesystem.write(&name, &self.document.contents()) {
+                    Ok(()) => {
+                        self.file_type = FileType::from(&name);
+                        self.document.unhighlight();
+                        self.diagnostics = None;
+                        self.open_file = name;
+                        self.document.mark_saved();
+                        self.status_message =
+                            StatusMessage::from("File saved successfully.");
+                    },
+                    Err(error) => {
+                        self.status_message =
+                            StatusMessage::from(&format!("Couldn't save: {}", error));
+                    },
+                }
             },
             _ => {
                 self.prompt_keypress("Save as: ", pressed_key);
@@ -232,6 +457,318 @@ impl Editor {
     fn search_keypress(&mut self, pressed_key: Key) {
     }
 
+    fn show_listing(&mut self) {
+        let names = match self.filesystem.list() {
+            Ok(names) => names,
+            Err(error) => {
+                self.status_message =
+                    StatusMessage::from(&format!("Couldn't list files: {}", error));
+                return;
+            },
+        };
+        if names.is_empty() {
+            self.status_message = StatusMessage::from("No saved files.");
+            return;
+        }
+        let selected = names.iter().position(|name| *name == self.open_file).unwrap_or(0);
+        self.listing = Some(FileListing { names, selected });
+        self.status_message = StatusMessage::from(if self.document.is_dirty() {
+            "WARNING! Opening a file loses unsaved changes. Enter = open | Esc = back"
+        } else {
+            LISTING_HELP
+        });
+    }
+
+    fn listing_keypress(&mut self, pressed_key: Key) {
+        let Some(ref mut listing) = self.listing else { return; };
+        match pressed_key {
+            Key::Up => listing.selected = listing.selected.saturating_sub(1),
+            Key::Down => {
+                listing.selected = listing.selected.saturating_add(1)
+                    .min(listing.names.len().saturating_sub(1));
+            },
+            Key::Char('\n') => {
+                let name = listing.names[listing.selected].clone();
+                self.listing = None;
+                self.open(name);
+            },
+            Key::Esc | Key::Ctrl('o') => {
+                self.listing = None;
+                self.status_message = StatusMessage::from("");
+            },
+            _ => (),
+        }
+    }
+
+    fn optimized_keypress(&mut self, pressed_key: Key) {
+        let height = self.terminal.size().height;
+        let Some(ref mut optimized) = self.optimized else { return; };
+        let last = optimized.document.len().saturating_sub(1);
+        let offset = &mut optimized.offset;
+        match pressed_key {
+            Key::Up => offset.y = offset.y.saturating_sub(1),
+            Key::Down => offset.y = offset.y.saturating_add(1).min(last),
+            Key::PageUp => offset.y = offset.y.saturating_sub(height),
+            Key::PageDown => offset.y = offset.y.saturating_add(height).min(last),
+            Key::Left => offset.x = offset.x.saturating_sub(1),
+            Key::Right => offset.x = offset.x.saturating_add(1),
+            Key::Esc | Key::Ctrl('p') => {
+                self.optimized = None;
+                self.status_message = StatusMessage::from("");
+            },
+            _ => (),
+        }
+    }
+
+    fn open(&mut self, name: String) {
+        match self.filesystem.read(&name) {
+            Ok(contents) => {
+                self.document = Document::from(contents.as_str());
+                self.cursor_position = Position::default();
+                self.selection_anchor = None;
+                self.offset = Position::default();
+                self.breakpoints.clear();
+                self.status_message = StatusMessage::from(&format!("Opened {}.", name));
+                self.file_type = FileType::from(&name);
+                self.diagnostics = None;
+                self.open_file = name;
+            },
+            Err(error) => {
+                self.status_message =
+                    StatusMessage::from(&format!("Couldn't open: {}", error));
+            },
+        }
+    }
+
+    fn debug_prompt_keypress(&mut self, pressed_key: Key) {
+        match pressed_key {
+            Key::Esc => {
+                self.prompt_mode = None;
+                self.prompt_string = "".to_string();
+                self.status_message = StatusMessage::from("");
+            },
+            Key::Char('\n') => {
+                self.prompt_mode = None;
+                let inputs = std::mem::take(&mut self.prompt_string);
+                self.start_debugger(&inputs);
+            },
+            _ => {
+                self.prompt_keypress("Debug with inputs: ", pressed_key);
+            },
+        }
+    }
+
+    // Inputs are written like `x = 5, k = 2`.
+    fn parse_inputs(inputs: &str) -> Option<Context> {
+        let mut context = Context::new();
+        for binding in inputs.split(',').filter(|binding| !binding.trim().is_empty()) {
+            let (name, value) = binding.split_once('=')?;
+            let value: Value = value.trim().parse().ok()?;
+            context.insert(Variable::new(name.trim()), value);
+        }
+        Some(context)
+    }
+
+    fn start_debugger(&mut self, inputs: &str) {
+        let Some(context) = Self::parse_inputs(inputs) else {
+            self.status_message =
+                StatusMessage::from("Inputs should look like: x = 5, k = 2");
+            return;
+        };
+        let (spec, spans) = match parse_with_spans(&self.document.contents()) {
+            Ok(parsed) => parsed,
+            Err(error) => {
+                self.status_message = StatusMessage::from(&error.to_string());
+                return;
+            },
+        };
+        let mut debugger = Debugger::new(
+            spec, &spans, context, ManaCosts::default(),
+            self.intrinsics.clone(), DEBUG_MANA_BUDGET);
+        debugger.breakpoints = self.breakpoints.clone();
+        self.debugger = Some(debugger);
+        self.follow_debugger();
+    }
+
+    // Treats every variable the spell reads before assigning as an input.
+    fn show_optimized(&mut self) {
+        let spec = match parse_with_spans(&self.document.contents()) {
+            Ok((spec, _)) => spec,
+            Err(error) => {
+                self.status_message = StatusMessage::from(&error.to_string());
+                return;
+            },
+        };
+        let inputs = optimizer::free_variables(&spec);
+        let optimized = optimizer::optimize(&spec, &inputs, &self.intrinsics);
+        let worst_case = |spec: &Spec| checker::check(
+            spec, &SpanTable::default(), &inputs, &ManaCosts::default(), &self.intrinsics,
+        ).worst_case_mana;
+        let message = match (worst_case(&spec), worst_case(&optimized)) {
+            (Some(before), Some(after)) => format!(
+                "Worst case {} mana, {} optimized (saves {}). Esc = back",
+                before, after, before.saturating_sub(after)),
+            (None, Some(after)) => format!(
+                "Worst case unbounded, {} mana optimized. Esc = back", after),
+            (_, None) => "Worst case unbounded. Esc = back".to_string(),
+        };
+        self.optimized = Some(OptimizedView {
+            document: Document::from(print_program(&optimized).as_str()),
+            offset: Position::default(),
+        });
+        self.status_message = StatusMessage::from(&message);
+    }
+
+    // Parses and checks the spell again if it changed since the last time,
+    // and underlines what's wrong with it. Variables that any family of
+    // puzzle is given count as inputs.
+    fn analyze(&mut self) {
+        let revision = self.document.revision();
+        if matches!(self.diagnostics, Some(ref diagnostics) if diagnostics.revision == revision) {
+            return;
+        }
+        let mut problems = Vec::new();
+        let mut mana = ManaEstimate::Unknown;
+        if self.file_type.is_spell() {
+            match parse_with_spans(&self.document.contents()) {
+                Ok((spec, spans)) => {
+                    let inputs: Vec<Variable> = Family::ALL.iter()
+                        .flat_map(|family| family.inputs())
+                        .collect::<BTreeSet<_>>()
+                        .iter()
+                        .map(|name| Variable::new(name))
+                        .collect();
+                    let analysis = checker::check(
+                        &spec, &spans, &inputs, &ManaCosts::default(), &self.intrinsics);
+                    mana = match analysis.worst_case_mana {
+                        Some(worst_case) => ManaEstimate::AtMost(worst_case),
+                        None => ManaEstimate::Unbounded,
+                    };
+                    problems = analysis.diagnostics.iter()
+                        .map(|diagnostic| Problem {
+                            start: self.document_position(diagnostic.span.start),
+                            end: self.document_position(diagnostic.span.end),
+                            severity: diagnostic.severity(),
+                            message: diagnostic.kind.to_string(),
+                        })
+                        .collect();
+                },
+                Err(error) => {
+                    let start = self.document_position(parser::Position {
+                        line: error.line,
+                        column: error.column,
+                    });
+                    problems.push(Problem {
+                        end: Position { x: start.x.saturating_add(1), y: start.y },
+                        start,
+                        severity: Severity::Error,
+                        message: error.kind.to_string(),
+                    });
+                },
+            }
+        }
+        // Errors are drawn over warnings.
+        let underlines: Vec<_> = [Severity::Warning, Severity::Error].iter()
+            .flat_map(|severity| problems.iter().filter(move |problem| problem.severity == *severity))
+            .map(|problem| (problem.start.clone(), problem.end.clone(), underline_for(problem.severity)))
+            .collect();
+        self.document.underline(&underlines);
+        self.diagnostics = Some(Diagnostics { revision, problems, mana });
+    }
+
+    // Parser positions count characters from 1, and the document's count
+    // graphemes from 0.
+    fn document_position(&self, at: parser::Position) -> Position {
+        let y = at.line.saturating_sub(1);
+        let column = at.column.saturating_sub(1);
+        let x = match self.document.row(y) {
+            Some(row) => {
+                let mut characters = 0;
+                row.as_str().graphemes(true)
+                    .take_while(|grapheme| {
+                        characters += grapheme.chars().count();
+                        characters <= column
+                    })
+                    .count()
+            },
+            None => column,
+        };
+        Position { x, y }
+    }
+
+    // The worst problem touching the cursor, if it's on the document.
+    fn problem_at_cursor(&self) -> Option<&Problem> {
+        if self.prompt_mode.is_some()
+            || self.debugger.is_some()
+            || self.optimized.is_some()
+            || self.listing.is_some()
+        {
+            return None;
+        }
+        let diagnostics = self.diagnostics.as_ref()?;
+        let cursor = (self.cursor_position.y, self.cursor_position.x);
+        diagnostics.problems.iter()
+            .filter(|problem| {
+                (problem.start.y, problem.start.x) <= cursor && cursor <= (problem.end.y, problem.end.x)
+            })
+            .max_by_key(|problem| problem.severity)
+    }
+
+    fn toggle_breakpoint(&mut self) {
+        let line = self.cursor_position.y.saturating_add(1);
+        if !self.breakpoints.remove(&line) {
+            self.breakpoints.insert(line);
+        }
+        if let Some(ref mut debugger) = self.debugger {
+            debugger.breakpoints = self.breakpoints.clone();
+        }
+    }
+
+    fn debugger_keypress(&mut self, pressed_key: Key) {
+        let Some(ref mut debugger) = self.debugger else { return; };
+        match pressed_key {
+            Key::F(10) | Key::Char('n') => debugger.step_over(),
+            Key::F(11) | Key::Char('s') => debugger.step_into(),
+            Key::F(5) | Key::Char('c') => debugger.resume(),
+            Key::F(9) | Key::Char('b') => {
+                self.toggle_breakpoint();
+                return;
+            },
+            Key::Esc | Key::Char('q') => {
+                self.debugger = None;
+                self.status_message = StatusMessage::from("");
+                return;
+            },
+            Key::Up | Key::Down | Key::PageUp | Key::PageDown => {
+                self.move_cursor(pressed_key);
+                self.scroll();
+                return;
+            },
+            _ => return,
+        }
+        self.follow_debugger();
+    }
+
+    // Moves the cursor to the statement the debugger is stopped at, and says
+    // what happened.
+    fn follow_debugger(&mut self) {
+        let Some(ref debugger) = self.debugger else { return; };
+        if let Some(span) = debugger.current_span() {
+            self.cursor_position = Position {
+                x: span.start.column.saturating_sub(1),
+                y: span.start.line.saturating_sub(1),
+            };
+        }
+        let message = match debugger.result() {
+            None => DEBUG_HELP.to_string(),
+            Some(Ok(Some(value))) => format!("Returned {}. Esc = stop", value),
+            Some(Ok(None)) => "Finished without returning. Esc = stop".to_string(),
+            Some(Err(error)) => format!("Spell failed: {}. Esc = stop", error),
+        };
+        self.status_message = StatusMessage::from(&message);
+        self.scroll();
+    }
+
     // fn search(&mut self) {
     //     let old_position = self.cursor_position.clone();
     //     let mut direction = SearchDirection::Forward;
@@ -362,31 +899,45 @@ impl Editor {
         let padding = width.saturating_sub(len) / 2;
         let spaces = " ".repeat(padding.saturating_sub(1));
         welcome_message = format!("~{}{}", spaces, welcome_message);
-        welcome_message.truncate(width);
+        truncate_chars(&mut welcome_message, width);
         self.terminal.write(&welcome_message);
         self.terminal.carriage_return();
         self.terminal.newline();
     }
 
-    pub fn draw_row(&mut self, row: &Row) {
+    pub fn draw_row(&mut self, row: &Row, selected: Range<usize>) {
         let width = self.terminal.size().width as usize;
         let start = self.offset.x;
         let end = self.offset.x.saturating_add(width);
-        row.render(&mut self.terminal, start, end);
+        row.render_selected(&mut self.terminal, start, end, selected.clone(), SELECTED_TEXT_BG_COLOR);
+        // A selected line break shows as a space past the end of the row.
+        if selected.end > row.len() && (start ..= end).contains(&row.len()) {
+            self.terminal.set_bg_color(SELECTED_TEXT_BG_COLOR);
+            self.terminal.write(" ");
+            self.terminal.reset_bg_color();
+        }
         self.terminal.carriage_return();
         self.terminal.newline();
     }
 
     fn draw_rows(&mut self) {
         let height = self.terminal.size().height;
+        let selection = self.selection();
         for terminal_row in 0..height {
             self.terminal.clear_current_line();
-            let optional_row = self
-                .document
-                .row(self.offset.y.saturating_add(terminal_row as usize))
-                .cloned();
+            let y = self.offset.y.saturating_add(terminal_row as usize);
+            let optional_row = self.document.row(y).cloned();
             if let Some(row) = optional_row {
-                self.draw_row(&row);
+                // Past the end of the row stands for its line break.
+                let selected = match selection {
+                    Some((ref start, ref end)) if (start.y ..= end.y).contains(&y) => {
+                        let from = if y == start.y { start.x } else { 0 };
+                        let to = if y == end.y { end.x } else { row.len() + 1 };
+                        from .. to
+                    },
+                    _ => 0 .. 0,
+                };
+                self.draw_row(&row, selected);
             } else if self.document.is_empty() && terminal_row == height / 3 {
                 self.draw_welcome_message();
             } else {
@@ -397,6 +948,98 @@ impl Editor {
         }
     }
 
+    // The source on the left, with the current line and breakpoints marked,
+    // and the spell's variables on the right.
+    fn draw_debugger_rows(&mut self) {
+        let Some(ref debugger) = self.debugger else { return; };
+        let height = self.terminal.size().height;
+        let width = self.terminal.size().width;
+        let source_width = width.saturating_sub(DEBUG_PANEL_WIDTH);
+        let current_line = debugger.current_span().map(|span| span.start.line);
+        let next = match debugger.current() {
+            Some(Spec::Assign(ref variable, _)) => format!("Next: {} = ...", variable.name()),
+            Some(Spec::For(ref variable, _, _, _)) => format!("Next: for {}", variable.name()),
+            Some(Spec::If(_, _, _)) => "Next: if".to_string(),
+            Some(Spec::Return(ref variable)) => format!("Next: return {}", variable.name()),
+            Some(Spec::Block(_)) | None => "Finished".to_string(),
+        };
+        let mut panel = vec![next, "".to_string(), "Variables".to_string()];
+        panel.extend(debugger.context().iter()
+            .map(|(variable, value)| format!("{} = {}", variable.name(), value)));
+
+        for terminal_row in 0 .. height {
+            self.terminal.clear_current_line();
+            let index = self.offset.y.saturating_add(terminal_row);
+            let line = index.saturating_add(1);
+            if current_line == Some(line) {
+                self.terminal.set_bg_color(CURRENT_LINE_BG_COLOR);
+            } else if self.breakpoints.contains(&line) {
+                self.terminal.set_bg_color(BREAKPOINT_BG_COLOR);
+            }
+            if let Some(row) = self.document.row(index) {
+                let end = self.offset.x.saturating_add(source_width);
+                row.render(&mut self.terminal, self.offset.x, end);
+            } else {
+                self.terminal.write("~");
+            }
+            let written = self.terminal.get_cursor_position().x;
+            self.terminal.write(&" ".repeat(source_width.saturating_sub(written)));
+            self.terminal.reset_bg_color();
+            self.terminal.set_cursor_position(
+                &Position { x: source_width, y: terminal_row });
+            let mut entry = format!("| {}", panel.get(terminal_row).map_or("", String::as_str));
+            truncate_chars(&mut entry, DEBUG_PANEL_WIDTH);
+            self.terminal.write(&entry);
+            self.terminal.carriage_return();
+            self.terminal.newline();
+        }
+    }
+
+    fn draw_optimized_rows(&mut self) {
+        let Some(ref optimized) = self.optimized else { return; };
+        let height = self.terminal.size().height;
+        let width = self.terminal.size().width;
+        for terminal_row in 0 .. height {
+            self.terminal.clear_current_line();
+            let index = optimized.offset.y.saturating_add(terminal_row);
+            if let Some(row) = optimized.document.row(index) {
+                let end = optimized.offset.x.saturating_add(width);
+                row.render(&mut self.terminal, optimized.offset.x, end);
+            } else {
+                self.terminal.write("~");
+            }
+            self.terminal.carriage_return();
+            self.terminal.newline();
+        }
+    }
+
+    fn draw_listing_rows(&mut self) {
+        let Some(ref listing) = self.listing else { return; };
+        let height = self.terminal.size().height;
+        let width = self.terminal.size().width;
+        // Keeps the selected file on screen.
+        let first = listing.selected.saturating_sub(height.saturating_sub(1));
+        for terminal_row in 0 .. height {
+            self.terminal.clear_current_line();
+            let index = first.saturating_add(terminal_row);
+            match listing.names.get(index) {
+                Some(name) => {
+                    if index == listing.selected {
+                        self.terminal.set_bg_color(SELECTED_FILE_BG_COLOR);
+                    }
+                    let mut entry = format!("  {}", name);
+                    truncate_chars(&mut entry, width);
+                    self.terminal.write(&entry);
+                    self.terminal.write(&" ".repeat(width.saturating_sub(entry.chars().count())));
+                    self.terminal.reset_bg_color();
+                },
+                None => self.terminal.write("~"),
+            }
+            self.terminal.carriage_return();
+            self.terminal.newline();
+        }
+    }
+
     fn draw_status_bar(&mut self) {
         let mut status;
         let width = self.terminal.size().width as usize;
@@ -407,7 +1050,7 @@ impl Editor {
         };
 
         let mut file_name = self.open_file.to_string();
-        file_name.truncate(20);
+        truncate_chars(&mut file_name, 20);
         status = format!(
             "{} - {} lines{}",
             file_name,
@@ -415,16 +1058,38 @@ impl Editor {
             modified_indicator
         );
 
-        let line_indicator = format!(
-            "{}/{}",
+        let mana = match self.diagnostics {
+            Some(Diagnostics { mana: ManaEstimate::AtMost(mana), .. }) => format!(" | mana <= {}", mana),
+            Some(Diagnostics { mana: ManaEstimate::Unbounded, .. }) => " | mana unbounded".to_string(),
+            _ => "".to_string(),
+        };
+        let mut line_indicator = format!(
+            "{}{} | {}/{}",
+            self.file_type.name(),
+            mana,
             self.cursor_position.y.saturating_add(1),
             self.document.len()
         );
+        if self.optimized.is_some() {
+            status = format!("OPTIMIZED {}", file_name);
+        }
+        if let Some(ref listing) = self.listing {
+            status = "OPEN".to_string();
+            line_indicator = format!("{}/{}", listing.selected + 1, listing.names.len());
+        }
+        if let Some(ref debugger) = self.debugger {
+            status = format!("DEBUG {}", file_name);
+            line_indicator = format!(
+                "mana {}/{}",
+                debugger.mana_spent(),
+                debugger.budget()
+            );
+        }
 
-        let len = status.len() + line_indicator.len();
+        let len = status.chars().count() + line_indicator.chars().count();
         status.push_str(&" ".repeat(width.saturating_sub(len)));
         status = format!("{}{}", status, line_indicator);
-        status.truncate(width);
+        truncate_chars(&mut status, width);
         self.terminal.set_bg_color(STATUS_BG_COLOR);
         self.terminal.set_fg_color(STATUS_FG_COLOR);
         self.terminal.write(&status);
@@ -436,6 +1101,18 @@ impl Editor {
 
     fn draw_message_bar(&mut self) {
         self.terminal.clear_current_line();
+        if let Some(problem) = self.problem_at_cursor() {
+            let label = match problem.severity {
+                Severity::Warning => "warning",
+                Severity::Error => "error",
+            };
+            let mut text = format!("{}: {}", label, problem.message);
+            truncate_chars(&mut text, self.terminal.size().width);
+            self.terminal.set_fg_color(underline_for(problem.severity).to_color());
+            self.terminal.write(&text);
+            self.terminal.reset_fg_color();
+            return;
+        }
         let message = &self.status_message;
 
         let mut should_write_message = false;
@@ -449,7 +1126,7 @@ impl Editor {
 
         if should_write_message {
             let mut text = message.text.clone();
-            text.truncate(self.terminal.size().width as usize);
+            truncate_chars(&mut text, self.terminal.size().width as usize);
             self.terminal.write(&text);
         }
     }
@@ -457,8 +1134,7 @@ impl Editor {
     fn prompt_keypress(&mut self, prefix: &str, pressed_key: Key) -> bool {
         let result = match pressed_key {
             Key::Backspace => {
-                self.prompt_string.truncate(
-                    self.prompt_string.len().saturating_sub(1));
+                self.prompt_string.pop();
                 true
             },
             Key::Char(c) => {
@@ -508,3 +1184,194 @@ impl Editor {
     //     Ok(Some(result))
     // }
 }
+
+// Names and messages can have any characters in them, so unlike
+// `String::truncate` this never cuts one in half.
+fn truncate_chars(text: &mut String, width: usize) {
+    if let Some((end, _)) = text.char_indices().nth(width) {
+        text.truncate(end);
+    }
+}
+
+fn underline_for(severity: Severity) -> Underline {
+    match severity {
+        Severity::Warning => Underline::Warning,
+        Severity::Error => Underline::Error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_text(editor: &mut Editor, text: &str) {
+        for c in text.chars() {
+            editor.process_keypress(Key::Char(c));
+        }
+    }
+
+    #[test]
+    fn saved_files_can_be_opened() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "x = 1;\nreturn x;");
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "spell\n");
+        assert!(!editor.document().is_dirty());
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "../spell\n");
+        assert_eq!(editor.open_file, "spell");
+
+        type_text(&mut editor, "y = 2;");
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "another\n");
+        editor.process_keypress(Key::Ctrl('o'));
+        assert_eq!(editor.listing.as_ref().map(|listing| listing.names.len()), Some(2));
+        editor.process_keypress(Key::Down);
+        editor.process_keypress(Key::Down);
+        editor.process_keypress(Key::Char('\n'));
+        assert_eq!(editor.open_file, "spell");
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn x;");
+        editor.refresh_screen();
+    }
+
+    #[test]
+    fn long_names_are_cut_between_characters() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "return x;");
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "aéééééééééé");
+        editor.process_keypress(Key::Backspace);
+        type_text(&mut editor, "éé\n");
+        assert_eq!(editor.open_file, format!("a{}", "é".repeat(11)));
+        editor.refresh_screen();
+        editor.process_keypress(Key::Ctrl('o'));
+        editor.refresh_screen();
+    }
+
+    #[test]
+    fn empty_last_rows_survive_saving() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "return x;\n");
+        assert_eq!(editor.document().len(), 2);
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "spell\n");
+        editor.process_keypress(Key::Ctrl('o'));
+        editor.process_keypress(Key::Char('\n'));
+        assert_eq!(editor.document().len(), 2);
+        assert_eq!(editor.document().contents(), "return x;\n");
+    }
+
+    #[test]
+    fn undo_and_redo_whole_words() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "x = 1;\nreturn");
+        editor.process_keypress(Key::Ctrl('s'));
+        type_text(&mut editor, "spell\n");
+        type_text(&mut editor, " x;");
+        editor.process_keypress(Key::Backspace);
+        editor.process_keypress(Key::Backspace);
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn ");
+
+        editor.process_keypress(Key::Ctrl('z'));
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn x;");
+        assert_eq!(editor.cursor_position, Position { x: 9, y: 1 });
+        editor.process_keypress(Key::Ctrl('z'));
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn");
+        assert_eq!(editor.cursor_position, Position { x: 6, y: 1 });
+        assert!(!editor.document().is_dirty());
+        editor.process_keypress(Key::Ctrl('y'));
+        assert!(editor.document().is_dirty());
+        assert_eq!(editor.cursor_position, Position { x: 9, y: 1 });
+
+        for _ in 0 .. 10 {
+            editor.process_keypress(Key::Ctrl('z'));
+        }
+        assert_eq!(editor.document().contents(), "");
+        assert!(editor.document().is_dirty());
+        for _ in 0 .. 10 {
+            editor.process_keypress(Key::Ctrl('y'));
+        }
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn ");
+
+        // Typing after an undo forgets what was undone.
+        editor.process_keypress(Key::Ctrl('z'));
+        type_text(&mut editor, "!");
+        editor.process_keypress(Key::Ctrl('y'));
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn x;!");
+    }
+
+    // The OS clipboard is shared with whatever else is running.
+    #[cfg(not(feature = "os_clipboard"))]
+    #[test]
+    fn cut_and_paste_between_screens() {
+        let clipboard = Clipboard::default();
+        let mut editor = Editor::new();
+        editor.set_clipboard(clipboard.clone());
+        type_text(&mut editor, "x = 1;\nreturn x;");
+        editor.process_keypress(Key::Up);
+        editor.process_keypress(Key::Home);
+        editor.process_keypress(Key::ShiftDown);
+        editor.process_keypress(Key::Ctrl('x'));
+        assert_eq!(editor.document().contents(), "return x;");
+        assert_eq!(clipboard.get(), "x = 1;\n");
+        editor.refresh_screen();
+
+        let mut other = Editor::new();
+        other.set_clipboard(clipboard);
+        type_text(&mut other, "y");
+        other.process_keypress(Key::ShiftLeft);
+        other.process_keypress(Key::Ctrl('v'));
+        other.process_keypress(Key::Ctrl('v'));
+        assert_eq!(other.document().contents(), "x = 1;\nx = 1;\n");
+        assert_eq!(other.cursor_position, Position { x: 0, y: 2 });
+        // The paste is undone in one go, but not the selection it replaced.
+        other.process_keypress(Key::Ctrl('z'));
+        assert_eq!(other.document().contents(), "x = 1;\n");
+        other.process_keypress(Key::Ctrl('z'));
+        other.process_keypress(Key::Ctrl('z'));
+        assert_eq!(other.document().contents(), "y");
+
+        editor.process_keypress(Key::Ctrl('z'));
+        assert_eq!(editor.document().contents(), "x = 1;\nreturn x;");
+    }
+
+    #[test]
+    fn optimized_spells_scroll_on_their_own() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "y = x * 8;\nz = y;\nreturn y;");
+        editor.process_keypress(Key::Ctrl('p'));
+        let lines = editor.optimized.as_ref().unwrap().document.len();
+        for _ in 0 .. lines + 3 {
+            editor.process_keypress(Key::Down);
+        }
+        editor.process_keypress(Key::Right);
+        let offset = &editor.optimized.as_ref().unwrap().offset;
+        assert_eq!(*offset, Position { x: 1, y: lines - 1 });
+        assert_eq!(editor.offset, Position::default());
+        editor.refresh_screen();
+
+        editor.process_keypress(Key::Esc);
+        assert!(editor.optimized.is_none());
+        assert_eq!(editor.document().contents(), "y = x * 8;\nz = y;\nreturn y;");
+    }
+
+    #[test]
+    fn diagnostics_follow_the_cursor() {
+        let mut editor = Editor::new();
+        type_text(&mut editor, "y = x + ;");
+        editor.refresh_screen();
+        let problem = editor.problem_at_cursor().unwrap();
+        assert!(problem.severity == Severity::Error);
+        assert_eq!(problem.start, Position { x: 8, y: 0 });
+        assert!(matches!(editor.diagnostics, Some(Diagnostics { mana: ManaEstimate::Unknown, .. })));
+
+        editor.process_keypress(Key::Backspace);
+        type_text(&mut editor, "1;\nreturn z;");
+        editor.refresh_screen();
+        let problem = editor.problem_at_cursor().unwrap();
+        assert_eq!(problem.message, "`z` is used before it's assigned");
+        assert!(matches!(editor.diagnostics, Some(Diagnostics { mana: ManaEstimate::AtMost(_), .. })));
+        editor.process_keypress(Key::Up);
+        assert!(editor.problem_at_cursor().is_none());
+    }
+}
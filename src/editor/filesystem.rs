@@ -0,0 +1,147 @@
+// Where the editor keeps saved files. Files are plain text with flat names;
+// there are no directories.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// Where spells are saved, relative to the working directory. It has a
+/// directory of its own so that saving a spell can't replace the game's other
+/// files in `saves`.
+pub const SAVE_DIRECTORY: &str = "saves/editor";
+
+#[derive(Debug, Error)]
+pub enum FilesystemError {
+    #[error("`{0}` isn't a valid file name")]
+    InvalidName(String),
+    #[error("no file named `{0}`")]
+    NotFound(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub trait Filesystem: Send + Sync {
+    /// The names of all the files, in order.
+    fn list(&self) -> Result<Vec<String>, FilesystemError>;
+    fn read(&self, name: &str) -> Result<String, FilesystemError>;
+    /// Creates the file if it doesn't exist, and replaces it if it does.
+    fn write(&mut self, name: &str, contents: &str) -> Result<(), FilesystemError>;
+}
+
+/// Names can't be empty, hidden or reach outside the filesystem.
+pub fn check_name(name: &str) -> Result<(), FilesystemError> {
+    let valid = !name.is_empty()
+        && !name.starts_with('.')
+        && !name.contains(|c: char| c == '/' || c == '\\' || c.is_control());
+    if valid {
+        Ok(())
+    } else {
+        Err(FilesystemError::InvalidName(name.to_string()))
+    }
+}
+
+/// Keeps files in the `root` directory, which is only created once something
+/// is saved.
+#[derive(Clone, Debug)]
+pub struct DiskFilesystem {
+    root: PathBuf,
+}
+
+impl DiskFilesystem {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        DiskFilesystem { root: root.into() }
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, FilesystemError> {
+        check_name(name)?;
+        Ok(self.root.join(name))
+    }
+}
+
+impl Filesystem for DiskFilesystem {
+    fn list(&self) -> Result<Vec<String>, FilesystemError> {
+        let entries = match std::fs::read_dir(&self.root) {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Some(name) = entry.file_name().to_str() {
+                if check_name(name).is_ok() {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+
+    fn read(&self, name: &str) -> Result<String, FilesystemError> {
+        match std::fs::read_to_string(self.path(name)?) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound =>
+                Err(FilesystemError::NotFound(name.to_string())),
+            result => Ok(result?),
+        }
+    }
+
+    fn write(&mut self, name: &str, contents: &str) -> Result<(), FilesystemError> {
+        let path = self.path(name)?;
+        std::fs::create_dir_all(&self.root)?;
+        // Written to the side first so that a crash can't leave half a file.
+        let partial = self.root.join(format!(".{}.partial", name));
+        std::fs::write(&partial, contents)?;
+        std::fs::rename(&partial, path)?;
+        Ok(())
+    }
+}
+
+/// Forgets everything when dropped; for tests.
+#[derive(Clone, Debug, Default)]
+pub struct MemoryFilesystem {
+    files: BTreeMap<String, String>,
+}
+
+impl Filesystem for MemoryFilesystem {
+    fn list(&self) -> Result<Vec<String>, FilesystemError> {
+        Ok(self.files.keys().cloned().collect())
+    }
+
+    fn read(&self, name: &str) -> Result<String, FilesystemError> {
+        check_name(name)?;
+        self.files.get(name).cloned().ok_or_else(|| FilesystemError::NotFound(name.to_string()))
+    }
+
+    fn write(&mut self, name: &str, contents: &str) -> Result<(), FilesystemError> {
+        check_name(name)?;
+        self.files.insert(name.to_string(), contents.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saves_survive_restarts() {
+        let root = std::env::temp_dir().join(format!("deeper-saves-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let mut filesystem = DiskFilesystem::new(&root);
+        assert!(filesystem.list().unwrap().is_empty());
+        filesystem.write("fireball", "x = 1;\nreturn x;").unwrap();
+        filesystem.write("antidote", "return k;").unwrap();
+        assert!(matches!(filesystem.write("../escape", ""), Err(FilesystemError::InvalidName(_))));
+
+        let restarted = DiskFilesystem::new(&root);
+        assert_eq!(restarted.list().unwrap(), vec!["antidote", "fireball"]);
+        assert_eq!(restarted.read("fireball").unwrap(), "x = 1;\nreturn x;");
+        assert!(matches!(restarted.read("missing"), Err(FilesystemError::NotFound(_))));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -21,6 +21,9 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use crate::magic::intrinsics::INTRINSICS;
+use crate::magic::world::WorldQuery;
+
 pub struct FileType {
     name: String,
     hl_opts: HighlightingOptions,
@@ -33,8 +36,13 @@ pub struct HighlightingOptions {
     characters: bool,
     comments: bool,
     multiline_comments: bool,
+    // Whether numbers can have `0x` or `0b` prefixes and `_` separators.
+    radix_literals: bool,
     primary_keywords: Vec<String>,
     secondary_keywords: Vec<String>,
+    operators: Vec<String>,
+    intrinsics: Vec<String>,
+    world_queries: Vec<String>,
 }
 
 impl Default for FileType {
@@ -51,11 +59,20 @@ impl FileType {
         self.name.clone()
     }
 
+    /// Only spells are parsed and checked as they're edited.
+    pub fn is_spell(&self) -> bool {
+        self.name == "Spell"
+    }
+
     pub fn highlighting_options(&self) -> &HighlightingOptions {
         &self.hl_opts
     }
 
+    /// Spells are saved as `.spell` files, or without any extension.
     pub fn from(file_name: &str) -> Self {
+        if file_name.ends_with(".spell") || !file_name.contains('.') {
+            return Self::spell();
+        }
         if file_name.ends_with(".rs") {
             return Self {
                 name: String::from("Rust"),
@@ -134,11 +151,37 @@ impl FileType {
                         "f32".to_string(),
                         "f64".to_string(),
                     ],
+                    ..HighlightingOptions::default()
                 },
             };
         }
         Self::default()
     }
+
+    pub fn spell() -> Self {
+        Self {
+            name: String::from("Spell"),
+            hl_opts: HighlightingOptions {
+                numbers: true,
+                comments: true,
+                radix_literals: true,
+                primary_keywords: ["for", "in", "if", "else", "return"]
+                    .iter().map(|keyword| keyword.to_string()).collect(),
+                // Longest first, so that `<<` isn't taken for two `<`s.
+                operators: [
+                    "..=", "<<", ">>", "<=", ">=", "==", "!=",
+                    "<", ">", "=", "|", "&", "^", "+", "-", "*", "/", "%", "~",
+                ].iter().map(|operator| operator.to_string()).collect(),
+                intrinsics: INTRINSICS.iter()
+                    .map(|intrinsic| intrinsic.name.to_string())
+                    .collect(),
+                world_queries: WorldQuery::ALL.iter()
+                    .map(|query| query.name().to_string())
+                    .collect(),
+                ..HighlightingOptions::default()
+            },
+        }
+    }
 }
 
 impl HighlightingOptions {
@@ -169,4 +212,20 @@ impl HighlightingOptions {
     pub fn multiline_comments(&self) -> bool {
         self.multiline_comments
     }
+
+    pub fn radix_literals(&self) -> bool {
+        self.radix_literals
+    }
+
+    pub fn operators(&self) -> &Vec<String> {
+        &self.operators
+    }
+
+    pub fn intrinsics(&self) -> &Vec<String> {
+        &self.intrinsics
+    }
+
+    pub fn world_queries(&self) -> &Vec<String> {
+        &self.world_queries
+    }
 }
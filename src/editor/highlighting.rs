@@ -23,7 +23,7 @@
 
 use bevy::render::color::Color;
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Type {
     None,
     Number,
@@ -34,6 +34,11 @@ pub enum Type {
     MultilineComment,
     PrimaryKeywords,
     SecondaryKeywords,
+    Operator,
+    Intrinsic,
+    /// Stands out the most, since world queries cost far more than anything
+    /// else a spell can do.
+    WorldQuery,
 }
 
 impl Type {
@@ -46,7 +51,26 @@ impl Type {
             Type::Comment | Type::MultilineComment => Color::rgb_u8(133, 153, 0),
             Type::PrimaryKeywords => Color::rgb_u8(181, 137, 0),
             Type::SecondaryKeywords => Color::rgb_u8(42, 161, 152),
+            Type::Operator => Color::rgb_u8(147, 161, 161),
+            Type::Intrinsic => Color::rgb_u8(203, 75, 22),
+            Type::WorldQuery => Color::rgb_u8(220, 50, 47),
             _ => Color::rgb_u8(255, 255, 255),
         }
     }
 }
+
+/// Drawn under text with a problem, whatever its highlighting.
+#[derive(PartialEq, Clone, Copy)]
+pub enum Underline {
+    Warning,
+    Error,
+}
+
+impl Underline {
+    pub fn to_color(self) -> Color {
+        match self {
+            Underline::Warning => Color::rgb_u8(181, 137, 0),
+            Underline::Error => Color::rgb_u8(220, 50, 47),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+// The edits made to a document, grouped the way they're undone: a run of
+// typing or deleting in one place is undone a word at a time.
+
+use crate::editor::Position;
+
+/// One change to a document, with enough to undo it.
+#[derive(Clone, Debug)]
+pub enum Edit {
+    /// `text` is a grapheme, or "\n" for a line break. `appended` is whether
+    /// inserting it added a row to the end of the document.
+    Insert { at: Position, text: String, appended: bool },
+    Delete { at: Position, text: String },
+}
+
+impl Edit {
+    fn text(&self) -> &str {
+        match self {
+            Edit::Insert { ref text, .. } | Edit::Delete { ref text, .. } => text,
+        }
+    }
+}
+
+/// How an edit was made, since only edits made the same way are grouped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditKind {
+    Typing,
+    /// Deleting forwards, leaving the cursor where it is.
+    Deleting,
+    Backspacing,
+    /// Cut and paste make whole groups of their own, however many words they
+    /// span.
+    Cutting,
+    Pasting,
+}
+
+#[derive(Clone, Debug)]
+pub struct Group {
+    kind: EditKind,
+    pub edits: Vec<Edit>,
+    /// Where the cursor was before the first edit.
+    pub cursor_before: Position,
+    /// Where the cursor was after the last edit.
+    pub cursor_after: Position,
+}
+
+#[derive(Clone, Debug)]
+pub struct History {
+    undo: Vec<Group>,
+    redo: Vec<Group>,
+    // How many groups were on the undo stack when the document was last saved,
+    // or None if that state can't be got back to.
+    saved_at: Option<usize>,
+    // Whether the next edit has to start a new group.
+    sealed: bool,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            saved_at: Some(0),
+            sealed: true,
+        }
+    }
+}
+
+impl History {
+    pub fn record(&mut self, kind: EditKind, edit: Edit, cursor_before: &Position, cursor_after: &Position) {
+        if matches!(self.saved_at, Some(saved) if saved > self.undo.len()) {
+            // The saved state was undone, and is about to be forgotten.
+            self.saved_at = None;
+        }
+        self.redo.clear();
+        let at_save = self.saved_at == Some(self.undo.len());
+        let sealed = std::mem::replace(&mut self.sealed, false);
+        if let Some(group) = self.undo.last_mut() {
+            let previous = group.edits.last().map_or("", Edit::text);
+            // Whitespace after anything else starts a new word.
+            let new_word = !matches!(kind, EditKind::Cutting | EditKind::Pasting)
+                && is_whitespace(edit.text())
+                && !is_whitespace(previous);
+            if !sealed && !at_save && !new_word
+                && group.kind == kind
+                && group.cursor_after == *cursor_before
+            {
+                group.edits.push(edit);
+                group.cursor_after = cursor_after.clone();
+                return;
+            }
+        }
+        self.undo.push(Group {
+            kind,
+            edits: vec![edit],
+            cursor_before: cursor_before.clone(),
+            cursor_after: cursor_after.clone(),
+        });
+    }
+
+    /// Makes the next edit start a new group.
+    pub fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    /// Moves the last group to the redo stack and returns it, for the
+    /// document to revert.
+    pub fn undo(&mut self) -> Option<Group> {
+        let group = self.undo.pop()?;
+        self.redo.push(group.clone());
+        self.sealed = true;
+        Some(group)
+    }
+
+    /// Moves the last undone group back and returns it, for the document to
+    /// apply again.
+    pub fn redo(&mut self) -> Option<Group> {
+        let group = self.redo.pop()?;
+        self.undo.push(group.clone());
+        self.sealed = true;
+        Some(group)
+    }
+
+    pub fn mark_saved(&mut self) {
+        self.saved_at = Some(self.undo.len());
+    }
+
+    /// Undoing or redoing back to the last save makes the document clean
+    /// again.
+    pub fn is_dirty(&self) -> bool {
+        self.saved_at != Some(self.undo.len())
+    }
+}
+
+fn is_whitespace(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(char::is_whitespace)
+}
@@ -21,22 +21,32 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+mod clipboard;
 mod document;
 mod editor;
+mod filesystem;
 mod filetype;
 mod highlighting;
+mod history;
 mod row;
 mod terminal;
 
+pub use clipboard::Clipboard;
 pub use document::Document;
 pub use editor::Editor;
 pub use editor::Position;
 pub use editor::SearchDirection;
+pub use filesystem::DiskFilesystem;
+pub use filesystem::Filesystem;
+pub use filesystem::FilesystemError;
+pub use filesystem::MemoryFilesystem;
+pub use filesystem::SAVE_DIRECTORY;
 pub use filetype::FileType;
 pub use filetype::HighlightingOptions;
 pub use row::Row;
 pub use terminal::Terminal;
 pub use terminal::Rasterized;
+pub use terminal::Region;
 
 use bevy::prelude::*;
 
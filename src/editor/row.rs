@@ -25,7 +25,9 @@ use crate::editor::highlighting;
 use crate::editor::HighlightingOptions;
 use crate::editor::SearchDirection;
 use crate::editor::Terminal;
+use bevy::render::color::Color;
 use std::cmp;
+use std::ops::Range;
 use unicode_segmentation::UnicodeSegmentation;
 
 #[derive(Clone, Default)]
@@ -33,6 +35,8 @@ pub struct Row {
     string: String,
     highlighting: Vec<highlighting::Type>,
     pub is_highlighted: bool,
+    // Set from outside, unlike the highlighting, and not shifted by edits.
+    underlines: Vec<Option<highlighting::Underline>>,
     len: usize,
 }
 
@@ -42,6 +46,7 @@ impl From<&str> for Row {
             string: String::from(slice),
             highlighting: Vec::new(),
             is_highlighted: false,
+            underlines: Vec::new(),
             len: slice.graphemes(true).count(),
         }
     }
@@ -49,9 +54,23 @@ impl From<&str> for Row {
 
 impl Row {
     pub fn render(&self, terminal: &mut Terminal, start: usize, end: usize) {
+        self.render_selected(terminal, start, end, 0 .. 0, Color::NONE);
+    }
+
+    /// Renders the graphemes in `selected` on a `selection_color` background.
+    pub fn render_selected(
+        &self,
+        terminal: &mut Terminal,
+        start: usize,
+        end: usize,
+        selected: Range<usize>,
+        selection_color: Color,
+    ) {
         let end = cmp::min(end, self.string.len());
         let start = cmp::min(start, end);
         let mut current_highlighting = &highlighting::Type::None;
+        let mut current_underline = None;
+        let mut selecting = false;
         for (index, grapheme) in self.string[..]
             .graphemes(true)
             .enumerate()
@@ -67,6 +86,22 @@ impl Row {
                     current_highlighting = highlighting_type;
                     terminal.set_fg_color(highlighting_type.to_color());
                 }
+                let underline = self.underlines.get(index).copied().flatten();
+                if underline != current_underline {
+                    current_underline = underline;
+                    match underline {
+                        Some(underline) => terminal.set_underline_color(underline.to_color()),
+                        None => terminal.reset_underline(),
+                    }
+                }
+                if selected.contains(&index) != selecting {
+                    selecting = !selecting;
+                    if selecting {
+                        terminal.set_bg_color(selection_color);
+                    } else {
+                        terminal.reset_bg_color();
+                    }
+                }
                 if c == '\t' {
                     terminal.write("  ");
                 } else {
@@ -77,6 +112,10 @@ impl Row {
             }
         }
         terminal.reset_fg_color();
+        terminal.reset_underline();
+        if selecting {
+            terminal.reset_bg_color();
+        }
     }
 
     pub fn len(&self) -> usize {
@@ -107,6 +146,19 @@ impl Row {
         self.string = result;
     }
 
+    /// Like `insert`, for a grapheme that may be more than one `char`.
+    pub fn insert_str(&mut self, at: usize, grapheme: &str) {
+        let mut result: String = self.string[..].graphemes(true).take(at).collect();
+        result.push_str(grapheme);
+        result.extend(self.string[..].graphemes(true).skip(at));
+        self.len = result[..].graphemes(true).count();
+        self.string = result;
+    }
+
+    pub fn grapheme(&self, at: usize) -> Option<&str> {
+        self.string[..].graphemes(true).nth(at)
+    }
+
     pub fn delete(&mut self, at: usize) {
         if at >= self.len() {
             return;
@@ -151,13 +203,33 @@ impl Row {
             len: splitted_length,
             is_highlighted: false,
             highlighting: Vec::new(),
+            underlines: Vec::new(),
         }
     }
 
+    /// Underlines the graphemes in `range`, on top of any underline they
+    /// already have.
+    pub fn underline(&mut self, range: Range<usize>, underline: highlighting::Underline) {
+        if self.underlines.len() < self.len {
+            self.underlines.resize(self.len, None);
+        }
+        for index in range.take_while(|index| *index < self.len) {
+            self.underlines[index] = Some(underline);
+        }
+    }
+
+    pub fn clear_underlines(&mut self) {
+        self.underlines.clear();
+    }
+
     pub fn as_bytes(&self) -> &[u8] {
         self.string.as_bytes()
     }
 
+    pub fn as_str(&self) -> &str {
+        &self.string
+    }
+
     pub fn find(&self, query: &str, at: usize, direction: SearchDirection) -> Option<usize> {
         if at > self.len || query.is_empty() {
             return None;
@@ -296,6 +368,69 @@ impl Row {
         )
     }
 
+    // Like `highlight_keywords`, but only whole identifiers match, since names
+    // like `rotate_left` have underscores in them.
+    fn highlight_identifiers(
+        &mut self,
+        index: &mut usize,
+        chars: &[char],
+        names: &[String],
+        hl_type: highlighting::Type,
+    ) -> bool {
+        if *index > 0 && is_identifier_char(chars[*index - 1]) {
+            return false;
+        }
+        for name in names {
+            let end = index.saturating_add(name.len());
+            if matches!(chars.get(end), Some(c) if is_identifier_char(*c)) {
+                continue;
+            }
+            if self.highlight_str(index, name, chars, hl_type) {
+                return true;
+            }
+        }
+        false
+    }
+
+    fn highlight_intrinsics(
+        &mut self,
+        index: &mut usize,
+        opts: &HighlightingOptions,
+        chars: &[char],
+    ) -> bool {
+        self.highlight_identifiers(
+            index,
+            chars,
+            opts.intrinsics(),
+            highlighting::Type::Intrinsic,
+        )
+    }
+
+    fn highlight_world_queries(
+        &mut self,
+        index: &mut usize,
+        opts: &HighlightingOptions,
+        chars: &[char],
+    ) -> bool {
+        self.highlight_identifiers(
+            index,
+            chars,
+            opts.world_queries(),
+            highlighting::Type::WorldQuery,
+        )
+    }
+
+    fn highlight_operators(
+        &mut self,
+        index: &mut usize,
+        opts: &HighlightingOptions,
+        chars: &[char],
+    ) -> bool {
+        opts.operators().iter().any(|operator| {
+            self.highlight_str(index, operator, chars, highlighting::Type::Operator)
+        })
+    }
+
     fn highlight_char(
         &mut self,
         index: &mut usize,
@@ -416,7 +551,8 @@ impl Row {
                 self.highlighting.push(highlighting::Type::Number);
                 *index += 1;
                 if let Some(next_char) = chars.get(*index) {
-                    if *next_char != '.' && !next_char.is_ascii_digit() {
+                    let radix_char = opts.radix_literals() && is_identifier_char(*next_char);
+                    if *next_char != '.' && !next_char.is_ascii_digit() && !radix_char {
                         break;
                     }
                 } else {
@@ -470,8 +606,11 @@ impl Row {
                 || self.highlight_comment(&mut index, opts, *c, &chars)
                 || self.highlight_primary_keywords(&mut index, &opts, &chars)
                 || self.highlight_secondary_keywords(&mut index, &opts, &chars)
+                || self.highlight_intrinsics(&mut index, opts, &chars)
+                || self.highlight_world_queries(&mut index, opts, &chars)
                 || self.highlight_string(&mut index, opts, *c, &chars)
                 || self.highlight_number(&mut index, opts, *c, &chars)
+                || self.highlight_operators(&mut index, opts, &chars)
             {
                 continue;
             }
@@ -490,3 +629,51 @@ impl Row {
 fn is_separator(c: char) -> bool {
     c.is_ascii_punctuation() || c.is_ascii_whitespace()
 }
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::editor::FileType;
+    use highlighting::Type;
+
+    fn highlight(source: &str) -> Vec<Type> {
+        let mut row = Row::from(source);
+        row.highlight(FileType::spell().highlighting_options(), &None, false);
+        row.highlighting
+    }
+
+    #[test]
+    fn intrinsics_are_whole_identifiers() {
+        let highlighting = highlight("y = rotate_left(x, 1);");
+        assert_eq!(highlighting[4 .. 15], [Type::Intrinsic; 11]);
+        assert_eq!(highlighting[15], Type::None);
+        assert!(!highlight("y = my_rotate_left(x, 1);").contains(&Type::Intrinsic));
+        assert!(!highlight("y = rotate_leftx(x, 1);").contains(&Type::Intrinsic));
+    }
+
+    #[test]
+    fn radix_literals_are_one_number() {
+        assert_eq!(highlight("0x1F_00"), [Type::Number; 7]);
+        assert_eq!(highlight("y = 0b1_0;")[4 ..], [
+            Type::Number, Type::Number, Type::Number, Type::Number, Type::Number, Type::None,
+        ]);
+        // Digits in the middle of an identifier aren't numbers.
+        assert!(!highlight("x1 = y2;").contains(&Type::Number));
+    }
+
+    #[test]
+    fn longer_operators_win() {
+        let opts = FileType::spell();
+        let chars: Vec<char> = "<< 2".chars().collect();
+        let mut row = Row::from("<< 2");
+        let mut index = 0;
+        assert!(row.highlight_operators(&mut index, opts.highlighting_options(), &chars));
+        assert_eq!(index, 2);
+        assert_eq!(row.highlighting, [Type::Operator; 2]);
+        assert_eq!(highlight("x << 2")[2 .. 4], [Type::Operator; 2]);
+    }
+}
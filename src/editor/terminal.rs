@@ -25,11 +25,18 @@ use crate::editor::Position;
 use std::collections::HashMap;
 use bevy::render::color::Color;
 
+// In pixels, along the bottom of each character.
+const UNDERLINE_THICKNESS: usize = 2;
+// How many rasterized glyphs to keep before starting over. Screens only use a
+// few colors, so this is rarely reached.
+const GLYPH_CACHE_LIMIT: usize = 4096;
+
 #[derive(PartialEq, Eq, Hash, Clone)]
 struct Formatting {
     foreground_color: u32,
     background_color: u32,
     bold: bool,
+    underline_color: Option<u32>,
 }
 
 impl Formatting {
@@ -42,6 +49,7 @@ impl Formatting {
             foreground_color: foreground_color.as_rgba_u32(),
             background_color: background_color.as_rgba_u32(),
             bold,
+            underline_color: None,
         }
     }
 
@@ -72,6 +80,17 @@ impl Formatting {
     fn bold(&self) -> bool {
         self.bold
     }
+
+    fn underline_color(&self) -> Option<Color> {
+        self.underline_color.map(|color| {
+            let [r, g, b, a] = color.to_be_bytes();
+            Color::rgba_u8(r, g, b, a)
+        })
+    }
+
+    fn set_underline_color(&mut self, color: Option<Color>) {
+        self.underline_color = color.map(|color| color.as_rgba_u32());
+    }
 }
 
 impl Default for Formatting {
@@ -114,13 +133,41 @@ impl Rasterized {
 
     pub fn blit(&mut self, sprite: &Rasterized, upper_left: (usize, usize)) {
         let (upper_left_x, upper_left_y) = upper_left;
-        for delta_x in 0 .. sprite.width {
-            for delta_y in 0 .. sprite.height {
-                let x = upper_left_x + delta_x;
-                let y = upper_left_y + delta_y;
-                self.set(x, y, sprite.get(delta_x, delta_y));
+        for delta_y in 0 .. sprite.height {
+            let start = (upper_left_y + delta_y) * self.width + upper_left_x;
+            self.data[start .. start + sprite.width]
+                .copy_from_slice(&sprite.data[delta_y * sprite.width .. (delta_y + 1) * sprite.width]);
+        }
+    }
+}
+
+/// A rectangle of pixels in a rasterized terminal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+// Rasterized tiles, keyed by the tile and whether it's inverted, since the
+// same few are drawn over and over.
+#[derive(Clone, Default)]
+struct GlyphCache {
+    glyphs: HashMap<(TerminalTile, bool), Rasterized>,
+}
+
+impl GlyphCache {
+    fn get(&mut self, tile: &TerminalTile, inverted: bool) -> Option<&Rasterized> {
+        let key = (tile.clone(), inverted);
+        if !self.glyphs.contains_key(&key) {
+            if self.glyphs.len() >= GLYPH_CACHE_LIMIT {
+                self.glyphs.clear();
             }
+            let glyph = tile.rasterize(inverted)?;
+            self.glyphs.insert(key.clone(), glyph);
         }
+        self.glyphs.get(&key)
     }
 }
 
@@ -182,6 +229,14 @@ impl TerminalTile {
             }
         }
 
+        if let Some(color) = self.formatting.underline_color() {
+            for y in height.saturating_sub(UNDERLINE_THICKNESS) .. height {
+                for x in 0 .. width {
+                    result.set(x, y, color.as_rgba_u32());
+                }
+            }
+        }
+
         Some(result)
     }
 }
@@ -193,6 +248,11 @@ pub struct Terminal {
     cursor_position: Position,
     cursor_visible: bool,
     formatting: Formatting,
+    glyphs: GlyphCache,
+    // The last rasterization, and what each tile looked like in it and
+    // whether the cursor was on it.
+    raster: Option<Rasterized>,
+    drawn: Vec<Option<(TerminalTile, bool)>>,
 }
 
 impl Default for Terminal {
@@ -207,6 +267,9 @@ impl Default for Terminal {
             cursor_position: Position { x: 0, y: 0 },
             cursor_visible: true,
             formatting: Formatting::default(),
+            glyphs: GlyphCache::default(),
+            raster: None,
+            drawn: Vec::new(),
         }
     }
 }
@@ -219,35 +282,51 @@ impl Terminal {
         }
     }
 
-    pub fn rasterize(&self) -> Option<Rasterized> {
-        let mut cache: HashMap<TerminalTile, Rasterized> = HashMap::new();
-        for tile in self.screen.iter() {
-            cache.insert(tile.clone(), tile.rasterize(false)?);
-        }
-
+    /// Redraws the tiles that changed since the last time, and returns
+    /// where they are: one region for each row with changes, spanning them.
+    pub fn rasterize(&mut self) -> Option<Vec<Region>> {
         let (tile_width, tile_height) = {
-            let rasterized_tile = &cache[&self.screen[0]];
-            (rasterized_tile.width, rasterized_tile.height)
+            let glyph = self.glyphs.get(&self.screen[0], false)?;
+            (glyph.width, glyph.height)
         };
+        let raster = self.raster.get_or_insert_with(|| Rasterized::new(
+            self.size.width * tile_width,
+            self.size.height * tile_height));
+        self.drawn.resize(self.screen.len(), None);
 
-        let mut result = Rasterized::new(self.size.width * tile_width,
-                                         self.size.height * tile_height);
+        let mut regions = Vec::new();
         for tile_y in 0 .. self.size.height {
+            let mut changed: Option<(usize, usize)> = None;
             for tile_x in 0 .. self.size.width {
-                let upper_left_x = tile_x * tile_width;
-                let upper_left_y = tile_y * tile_height;
-                let tile = &self.screen[tile_y * self.size.width + tile_x];
+                let index = tile_y * self.size.width + tile_x;
+                let tile = &self.screen[index];
                 let Position { x: cx, y: cy } = self.cursor_position;
-                let rasterized = if (tile_x == cx) && (tile_y == cy) {
-                    tile.rasterize(true)?
-                } else {
-                    cache[tile].clone()
-                };
-                result.blit(&rasterized, (upper_left_x, upper_left_y));
+                let inverted = (tile_x == cx) && (tile_y == cy);
+                if matches!(self.drawn[index], Some((ref drawn, was_inverted))
+                            if drawn == tile && was_inverted == inverted) {
+                    continue;
+                }
+                let glyph = self.glyphs.get(tile, inverted)?;
+                raster.blit(glyph, (tile_x * tile_width, tile_y * tile_height));
+                self.drawn[index] = Some((tile.clone(), inverted));
+                changed = Some(changed.map_or((tile_x, tile_x), |(first, _)| (first, tile_x)));
+            }
+            if let Some((first, last)) = changed {
+                regions.push(Region {
+                    x: first * tile_width,
+                    y: tile_y * tile_height,
+                    width: (last + 1 - first) * tile_width,
+                    height: tile_height,
+                });
             }
         }
 
-        Some(result)
+        Some(regions)
+    }
+
+    /// Everything rasterized so far.
+    pub fn raster(&self) -> Option<&Rasterized> {
+        self.raster.as_ref()
     }
 
     pub fn clear_screen(&mut self) {
@@ -325,4 +404,49 @@ impl Terminal {
     pub fn reset_fg_color(&mut self) {
         self.formatting.set_foreground_color(Color::WHITE);
     }
+
+    pub fn set_underline_color(&mut self, color: Color) {
+        self.formatting.set_underline_color(Some(color));
+    }
+
+    pub fn reset_underline(&mut self) {
+        self.formatting.set_underline_color(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_tiles_are_redrawn() {
+        let draw_spell = |terminal: &mut Terminal| terminal.write("return x;");
+        let edit_spell = |terminal: &mut Terminal| {
+            terminal.set_cursor_position(&Position { x: 7, y: 0 });
+            terminal.set_fg_color(Color::RED);
+            terminal.write("y");
+        };
+
+        let mut terminal = Terminal::default();
+        draw_spell(&mut terminal);
+        assert_eq!(terminal.rasterize().unwrap().len(), terminal.size.height);
+        assert!(terminal.rasterize().unwrap().is_empty());
+        edit_spell(&mut terminal);
+        let raster = terminal.raster().unwrap();
+        let tile_width = raster.width / terminal.size.width;
+        let tile_height = raster.height / terminal.size.height;
+        // The new character, and where the cursor is and was.
+        assert_eq!(terminal.rasterize().unwrap(), vec![Region {
+            x: 7 * tile_width,
+            y: 0,
+            width: 3 * tile_width,
+            height: tile_height,
+        }]);
+
+        let mut fresh = Terminal::default();
+        draw_spell(&mut fresh);
+        edit_spell(&mut fresh);
+        fresh.rasterize().unwrap();
+        assert_eq!(fresh.raster().unwrap().data, terminal.raster().unwrap().data);
+    }
 }
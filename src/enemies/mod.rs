@@ -1,7 +1,9 @@
 use bevy::prelude::*;
 use crate::assets::GameState;
+use serde::{Deserialize, Serialize};
 
 pub mod halo;
+pub mod spellcasting;
 
 pub struct EnemiesPlugin;
 
@@ -9,10 +11,21 @@ impl Plugin for EnemiesPlugin {
     fn build(&self, app: &mut App) {
         app
             .add_plugin(crate::enemies::halo::HaloPlugin)
+            .add_plugin(crate::enemies::spellcasting::SpellcastingPlugin)
             .add_system(spawn_guys.in_schedule(OnEnter(GameState::Ready)));
     }
 }
 
+/// Tells enemies apart the same way on every peer. Every peer spawns the
+/// same enemies in the same order, so numbering them as they're spawned is
+/// enough.
+#[derive(
+    Clone, Copy, Debug,
+    PartialEq, Eq, Hash,
+    Component, Serialize, Deserialize
+)]
+pub struct EnemyId(pub u32);
+
 fn spawn_guys(
     mut commands: Commands,
 ) {
@@ -20,5 +33,6 @@ fn spawn_guys(
         crate::enemies::halo::SpawnHalo {
             transform: Transform::from_translation(Vec3::new(5.0, 5.0, 5.0)),
         },
+        EnemyId(0),
     ));
 }
@@ -0,0 +1,187 @@
+use bevy::prelude::*;
+use crate::assets::GameState;
+use crate::casting::WorldLookup;
+use crate::enemies::EnemyId;
+use crate::enemies::halo::Halo;
+use crate::fps_controller::LogicalPlayer;
+use crate::level::LevelSeed;
+use crate::magic::Spec;
+use crate::magic::bytecode::{self, Program};
+use crate::magic::intrinsics::IntrinsicSet;
+use crate::magic::library::{SpellHash, SpellLibrary};
+use crate::magic::mana::{Mana, ManaCosts};
+use crate::puzzle::{self, Effect, Family};
+use rand::{Rng, SeedableRng};
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
+
+pub const ARCHIVE_PATH: &str = "saves/archive.bin";
+/// The chance that a newly spawned enemy gets a spell, if there are any.
+pub const SPELLCASTER_CHANCE: f64 = 0.5;
+/// How much mana an enemy has for each cast.
+pub const ENEMY_MANA: Mana = 2000;
+pub const CAST_RANGE: f32 = 15.0;
+pub const CAST_COOLDOWN_SECONDS: f32 = 4.0;
+pub const BASE_SPELL_DAMAGE: f32 = 10.0;
+
+pub struct SpellcastingPlugin;
+
+impl Plugin for SpellcastingPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(SpellArchive::load(ARCHIVE_PATH))
+            .add_event::<ArchiveSpell>()
+            .add_event::<EnemySpellCast>()
+            .add_system(archive_spells)
+            .add_system(assign_spells.run_if(in_state(GameState::Ready)))
+            .add_system(cast_spells.run_if(in_state(GameState::Ready)));
+    }
+}
+
+/// Every spell the player has ever cast, kept across runs.
+#[derive(Resource)]
+pub struct SpellArchive {
+    pub library: SpellLibrary,
+    // What was in the archive when the game started. Only these are handed
+    // out, so enemies never use spells from the current run.
+    earlier_runs: Vec<SpellHash>,
+}
+
+impl SpellArchive {
+    /// If the archive can't be read, this starts a new one, which will
+    /// replace the old file the next time a spell is archived.
+    pub fn load(path: &str) -> Self {
+        let library = SpellLibrary::load(path).unwrap_or_else(|error| {
+            warn!("Starting a new spell archive: {}", error);
+            SpellLibrary::new(path)
+        });
+        let earlier_runs = library.spells().map(|(hash, _)| hash).collect();
+        SpellArchive { library, earlier_runs }
+    }
+
+    pub fn earlier_runs(&self) -> &[SpellHash] {
+        &self.earlier_runs
+    }
+}
+
+/// Send this to save a spell the player wrote for enemies in later runs.
+#[derive(Clone, Debug)]
+pub struct ArchiveSpell(pub Spec);
+
+#[derive(Clone, Debug)]
+pub struct EnemySpellCast {
+    pub caster: Entity,
+    pub target: Entity,
+    pub effect: Effect,
+    pub damage: f32,
+}
+
+/// An enemy wielding a spell from an earlier run. The spell is cast by
+/// having it solve a fresh puzzle of `family`; if it gets the answer wrong
+/// or runs out of mana, the cast fizzles.
+#[derive(Component)]
+pub struct SpellCaster {
+    pub hash: SpellHash,
+    pub spell: Spec,
+    pub family: Family,
+    program: Program,
+    cooldown: Timer,
+    rng: ChaCha8Rng,
+}
+
+fn archive_spells(
+    mut archive_events: EventReader<ArchiveSpell>,
+    mut archive: ResMut<SpellArchive>,
+) {
+    let mut changed = false;
+    for ArchiveSpell(spec) in archive_events.iter() {
+        if archive.library.get(spec.content_hash()) == Some(spec) {
+            continue;
+        }
+        match archive.library.add(spec.clone()) {
+            Ok(_) => changed = true,
+            Err(error) => warn!("Couldn't archive a spell: {}", error),
+        }
+    }
+    if changed {
+        if let Err(error) = archive.library.save() {
+            warn!("Couldn't save the spell archive: {}", error);
+        }
+    }
+}
+
+// Each peer hands out and casts enemy spells on its own, from its own level
+// seed and archive, so this is only consistent in single-player games. In a
+// session, peers can disagree about which enemies cast and what they cast.
+fn assign_spells(
+    mut commands: Commands,
+    archive: Res<SpellArchive>,
+    level_seed: Option<Res<LevelSeed>>,
+    halos: Query<(Entity, &EnemyId), Added<Halo>>,
+) {
+    let seed = level_seed.map_or(0, |seed| seed.0);
+    for (entity, id) in halos.iter() {
+        // Each enemy gets its own stream, however many were spawned before it.
+        let mut rng = ChaCha8Rng::seed_from_u64(seed ^ id.0 as u64);
+        if !rng.gen_bool(SPELLCASTER_CHANCE) {
+            continue;
+        }
+        let Some(hash) = archive.earlier_runs().choose(&mut rng).cloned() else {
+            continue;
+        };
+        let spell = archive.library.get(hash).unwrap().clone();
+        // Spells that don't reliably solve anything aren't worth wielding.
+        let Some((family, _)) = puzzle::classify(
+            &spell, rng.gen(), &ManaCosts::default(), &IntrinsicSet::all(), ENEMY_MANA)
+        else {
+            continue;
+        };
+        let optimized = family.optimize(&spell, &IntrinsicSet::all());
+        let program = bytecode::compile(&optimized, &ManaCosts::default(), &IntrinsicSet::all());
+        commands.entity(entity).insert(SpellCaster {
+            hash,
+            spell,
+            family,
+            program,
+            cooldown: Timer::from_seconds(CAST_COOLDOWN_SECONDS, TimerMode::Repeating),
+            rng,
+        });
+    }
+}
+
+fn cast_spells(
+    time: Res<Time>,
+    mut casters: Query<(Entity, &GlobalTransform, &mut SpellCaster)>,
+    players: Query<(Entity, &GlobalTransform), With<LogicalPlayer>>,
+    mut casts: EventWriter<EnemySpellCast>,
+    world: WorldLookup,
+) {
+    for (caster_entity, caster_transform, mut caster) in casters.iter_mut() {
+        if !caster.cooldown.tick(time.delta()).just_finished() {
+            continue;
+        }
+        let position = caster_transform.translation();
+        let nearest = players.iter()
+            .map(|(entity, transform)| (entity, transform.translation().distance(position)))
+            .filter(|(_, distance)| *distance <= CAST_RANGE)
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
+        let Some((target, _)) = nearest else { continue; };
+
+        let caster = &mut *caster;
+        let puzzle = caster.family.generate(&mut caster.rng);
+        let mut context = puzzle.inputs.clone();
+        let world = world.view(caster_entity, Some(target));
+        let execution = caster.program.run_in_world(&mut context, ENEMY_MANA, &world);
+        if execution.result != Ok(Some(puzzle.answer)) {
+            continue;
+        }
+        // Cheaper spells hit harder, up to twice the base damage.
+        let efficiency = 1.0 - execution.mana_spent as f32 / ENEMY_MANA as f32;
+        casts.send(EnemySpellCast {
+            caster: caster_entity,
+            target,
+            effect: caster.family.effect(),
+            damage: BASE_SPELL_DAMAGE * (1.0 + efficiency),
+        });
+    }
+}
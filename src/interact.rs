@@ -44,7 +44,7 @@ pub fn interact(
             if let Ok(item) = items.get(entity) {
                 let item_type = item.item_type.clone();
                 commands.entity(entity).despawn();
-                inventory.insert(&InventoryItem { item_type, equipped: false });
+                inventory.insert(&InventoryItem { item_type, equipped: false, spell: None });
             }
             if screens.get(entity).is_ok() {
                 screen_activated.entity = Some(entity);
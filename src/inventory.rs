@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use bevy::prelude::*;
 use crate::assets::{ImageAssets, GameState};
+use crate::casting::BoundSpell;
 use crate::fps_controller::LogicalPlayer;
 use crate::level::voxel::VoxelShape;
 use crate::ui::{ActiveHotbarSlot, HotbarSlot, InventorySlot, InventoryPosition};
@@ -18,6 +19,8 @@ impl Plugin for InventoryPlugin {
 pub struct InventoryItem {
     pub item_type: ItemType,
     pub equipped: bool,
+    /// Only staffs and books have spells.
+    pub spell: Option<BoundSpell>,
 }
 
 #[derive(Component, Debug)]
@@ -36,14 +39,17 @@ impl Inventory {
         hotbar[0] = Some(InventoryItem {
             item_type: ItemType::Voxel(VoxelShape::Solid),
             equipped: false,
+            spell: None,
         });
         hotbar[1] = Some(InventoryItem {
             item_type: ItemType::Voxel(VoxelShape::Staircase),
             equipped: false,
+            spell: None,
         });
         hotbar[2] = Some(InventoryItem {
             item_type: ItemType::Voxel(VoxelShape::Roof),
             equipped: false,
+            spell: None,
         });
         Inventory {
             width: 16,
@@ -54,7 +60,13 @@ impl Inventory {
         }
     }
 
+    /// Puts `item` in the first free hotbar slot, so that it's ready to
+    /// use, or in the backpack if the hotbar is full.
     pub fn insert(&mut self, item: &InventoryItem) {
+        if let Some(slot) = self.hotbar.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(item.clone());
+            return;
+        }
         for x in 0 .. self.width {
             for y in 0 .. self.height {
                 if !self.map.contains_key(&(x, y)) {
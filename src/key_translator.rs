@@ -74,6 +74,18 @@ fn keycode_to_key(
         return Some(Key::Char(character));
     }
 
+    if shift {
+        match keycode {
+            KeyCode::Left  => return Some(Key::ShiftLeft),
+            KeyCode::Right => return Some(Key::ShiftRight),
+            KeyCode::Up    => return Some(Key::ShiftUp),
+            KeyCode::Down  => return Some(Key::ShiftDown),
+            KeyCode::Home  => return Some(Key::ShiftHome),
+            KeyCode::End   => return Some(Key::ShiftEnd),
+            _              => {},
+        }
+    }
+
     match keycode {
         KeyCode::Back         => Some(Key::Backspace),
         KeyCode::Left         => Some(Key::Left),
@@ -88,6 +100,18 @@ fn keycode_to_key(
         KeyCode::Delete       => Some(Key::Delete),
         KeyCode::Insert       => Some(Key::Insert),
         KeyCode::Escape       => Some(Key::Esc),
+        KeyCode::F1           => Some(Key::F(1)),
+        KeyCode::F2           => Some(Key::F(2)),
+        KeyCode::F3           => Some(Key::F(3)),
+        KeyCode::F4           => Some(Key::F(4)),
+        KeyCode::F5           => Some(Key::F(5)),
+        KeyCode::F6           => Some(Key::F(6)),
+        KeyCode::F7           => Some(Key::F(7)),
+        KeyCode::F8           => Some(Key::F(8)),
+        KeyCode::F9           => Some(Key::F(9)),
+        KeyCode::F10          => Some(Key::F(10)),
+        KeyCode::F11          => Some(Key::F(11)),
+        KeyCode::F12          => Some(Key::F(12)),
         _                     => None,
     }
 }
@@ -27,6 +27,11 @@ impl Plugin for LevelPlugin {
     }
 }
 
+/// The seed the current level was generated from. Anything else that should
+/// be the same for everyone playing the level is derived from it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Resource)]
+pub struct LevelSeed(pub u64);
+
 #[derive(Clone, Default, Resource)]
 pub struct ActiveLevel {
     pub map: Option<Map>,
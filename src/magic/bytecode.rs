@@ -0,0 +1,457 @@
+// A compact bytecode for spells, for when they're cast often enough that
+// walking the tree in `mana::interpret_metered` is too slow.
+//
+// Variables are resolved to slots at compile time, and consecutive charges
+// are merged into one `Charge`, but otherwise a program does exactly what the
+// metered interpreter does in the same order: same results, same errors and
+// the same mana spent, down to which operation runs out of mana.
+
+use crate::magic::{BinaryOp, Context, EvalError, Expr, Spec, Value, Variable};
+use crate::magic::intrinsics::{self, Intrinsic, IntrinsicSet};
+use crate::magic::mana::{self, Execution, Mana, ManaCosts, SpellError};
+use crate::magic::world::{self, NOWHERE, WorldQuery, WorldView};
+use std::collections::BTreeMap;
+
+pub type Slot = usize;
+
+#[derive(Clone, Debug)]
+pub enum Instruction {
+    /// Fails with `SpellError::OutOfMana` if the budget can't cover it.
+    Charge(Mana),
+    Const(Value),
+    Load(Slot),
+    Store(Slot),
+    Not,
+    Binary(BinaryOp),
+    Call(&'static Intrinsic),
+    Query(WorldQuery),
+    /// Raised where the interpreter would find a bad call.
+    Fail(SpellError),
+    Jump(usize),
+    /// Pops a condition and jumps if it's zero.
+    JumpIfZero(usize),
+    /// Pops the upper and lower bounds of loop number `index`.
+    LoopStart { index: usize, variable: Slot },
+    /// Either pays for another iteration and sets the loop variable, or
+    /// restores the variable's old value and jumps to `exit`.
+    LoopNext { index: usize, variable: Slot, cost: Mana, exit: usize },
+    Return(Slot),
+    Halt,
+}
+
+#[derive(Clone, Copy, Default)]
+struct LoopState {
+    next: u64,
+    upper: u64,
+    shadowed: Option<Value>,
+}
+
+/// A spell compiled for a particular set of costs and granted intrinsics.
+#[derive(Clone, Debug)]
+pub struct Program {
+    code: Vec<Instruction>,
+    variables: Vec<Variable>,
+    loops: usize,
+    stack_size: usize,
+}
+
+struct Emitter<'a> {
+    costs: &'a ManaCosts,
+    intrinsics: &'a IntrinsicSet,
+    code: Vec<Instruction>,
+    slots: BTreeMap<Variable, Slot>,
+    loops: usize,
+    depth: usize,
+    stack_size: usize,
+    // Charges can't be merged across this point, because something jumps
+    // here.
+    label: usize,
+}
+
+impl<'a> Emitter<'a> {
+    fn slot(&mut self, variable: &Variable) -> Slot {
+        let next = self.slots.len();
+        *self.slots.entry(variable.clone()).or_insert(next)
+    }
+
+    fn emit(&mut self, instruction: Instruction) {
+        self.code.push(instruction);
+    }
+
+    fn charge(&mut self, amount: Mana) {
+        if amount == 0 {
+            return;
+        }
+        if self.code.len() > self.label {
+            if let Some(Instruction::Charge(ref mut total)) = self.code.last_mut() {
+                *total += amount;
+                return;
+            }
+        }
+        self.emit(Instruction::Charge(amount));
+    }
+
+    fn label(&mut self) -> usize {
+        self.label = self.code.len();
+        self.label
+    }
+
+    fn patch(&mut self, at: usize, target: usize) {
+        match self.code[at] {
+            Instruction::Jump(ref mut t) | Instruction::JumpIfZero(ref mut t) => *t = target,
+            Instruction::LoopNext { ref mut exit, .. } => *exit = target,
+            _ => unreachable!(),
+        }
+    }
+
+    fn push(&mut self) {
+        self.depth += 1;
+        self.stack_size = self.stack_size.max(self.depth);
+    }
+
+    fn pop(&mut self, count: usize) {
+        self.depth -= count;
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        self.charge(self.costs.expr_node);
+        match expr {
+            Expr::Var(ref var) => {
+                let slot = self.slot(var);
+                self.emit(Instruction::Load(slot));
+                self.push();
+            },
+            Expr::Const(value) => {
+                self.emit(Instruction::Const(*value));
+                self.push();
+            },
+            Expr::Not(ref x) => {
+                self.expr(x);
+                self.emit(Instruction::Not);
+            },
+            Expr::Call(ref name, ref args) => {
+                if let Some(query) = world::lookup(name) {
+                    self.query(query, args);
+                    return;
+                }
+                let Some(intrinsic) = self.intrinsics.get(name) else {
+                    self.emit(Instruction::Fail(if intrinsics::lookup(name).is_some() {
+                        SpellError::IntrinsicNotGranted(name.clone())
+                    } else {
+                        EvalError::UnknownIntrinsic(name.clone()).into()
+                    }));
+                    // Keeps the stack balanced for whatever follows, which
+                    // never runs.
+                    self.push();
+                    return;
+                };
+                if let Err(error) = crate::magic::check_arity(intrinsic, args) {
+                    self.emit(Instruction::Fail(error.into()));
+                    self.push();
+                    return;
+                }
+                self.charge(intrinsic.cost);
+                for arg in args {
+                    self.expr(arg);
+                }
+                self.emit(Instruction::Call(intrinsic));
+                self.pop(args.len());
+                self.push();
+            },
+            _ => {
+                let (op, x, y) = expr.as_binary().unwrap();
+                self.expr(x);
+                self.expr(y);
+                self.emit(Instruction::Binary(op));
+                self.pop(1);
+            },
+        }
+    }
+
+    fn query(&mut self, query: WorldQuery, args: &[Expr]) {
+        if let Err(error) = mana::check_query_arity(query, args) {
+            self.emit(Instruction::Fail(error.into()));
+            self.push();
+            return;
+        }
+        self.charge(self.costs.queries.get(query));
+        for arg in args {
+            self.expr(arg);
+        }
+        self.emit(Instruction::Query(query));
+        self.pop(args.len());
+        self.push();
+    }
+
+    fn spec(&mut self, spec: &Spec) {
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                self.charge(self.costs.assign);
+                self.expr(expr);
+                let slot = self.slot(var);
+                self.emit(Instruction::Store(slot));
+                self.pop(1);
+            },
+            Spec::Block(ref specs) => {
+                for s in specs {
+                    self.spec(s);
+                }
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                self.expr(lower);
+                self.expr(upper);
+                let (index, variable) = (self.loops, self.slot(var));
+                self.loops += 1;
+                self.emit(Instruction::LoopStart { index, variable });
+                self.pop(2);
+                let head = self.label();
+                self.emit(Instruction::LoopNext {
+                    index,
+                    variable,
+                    cost: self.costs.loop_iteration,
+                    exit: 0,
+                });
+                self.spec(body);
+                self.emit(Instruction::Jump(head));
+                let exit = self.label();
+                self.patch(head, exit);
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                self.charge(self.costs.branch);
+                self.expr(cond);
+                let branch = self.code.len();
+                self.emit(Instruction::JumpIfZero(0));
+                self.pop(1);
+                self.spec(if_true);
+                let jump = self.code.len();
+                self.emit(Instruction::Jump(0));
+                let else_label = self.label();
+                self.patch(branch, else_label);
+                self.spec(if_false);
+                let end = self.label();
+                self.patch(jump, end);
+            },
+            Spec::Return(ref var) => {
+                let slot = self.slot(var);
+                self.emit(Instruction::Return(slot));
+            },
+        }
+    }
+}
+
+/// Compiles `spec`. Calls to intrinsics that don't exist, aren't granted or
+/// have the wrong number of arguments still compile, and fail when reached.
+pub fn compile(spec: &Spec, costs: &ManaCosts, intrinsics: &IntrinsicSet) -> Program {
+    let mut emitter = Emitter {
+        costs,
+        intrinsics,
+        code: Vec::new(),
+        slots: BTreeMap::new(),
+        loops: 0,
+        depth: 0,
+        stack_size: 0,
+        label: 0,
+    };
+    emitter.spec(spec);
+    emitter.emit(Instruction::Halt);
+    let mut variables = vec![Variable::new(""); emitter.slots.len()];
+    for (variable, slot) in emitter.slots {
+        variables[slot] = variable;
+    }
+    Program {
+        code: emitter.code,
+        variables,
+        loops: emitter.loops,
+        stack_size: emitter.stack_size,
+    }
+}
+
+impl Program {
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    /// The variable in each slot.
+    pub fn variables(&self) -> &[Variable] {
+        &self.variables
+    }
+
+    /// Runs the program like `interpret_metered` would run its spell. Only
+    /// the variables the spell mentions are read from or written back to
+    /// `context`.
+    pub fn run(&self, context: &mut Context, budget: Mana) -> Execution {
+        self.run_in_world(context, budget, &NOWHERE)
+    }
+
+    /// Like `run`, but like `interpret_in_world`.
+    pub fn run_in_world(&self, context: &mut Context, budget: Mana, world: &WorldView) -> Execution {
+        let mut slots: Vec<Option<Value>> = self.variables.iter()
+            .map(|variable| context.get(variable).cloned())
+            .collect();
+        let mut spent: Mana = 0;
+        let result = self.execute(&mut slots, &mut spent, budget, world);
+        for (variable, value) in self.variables.iter().zip(slots) {
+            match value {
+                Some(value) => context.insert(variable.clone(), value),
+                None => context.remove(variable),
+            };
+        }
+        Execution { result, mana_spent: spent }
+    }
+
+    fn execute(
+        &self,
+        slots: &mut [Option<Value>],
+        spent: &mut Mana,
+        budget: Mana,
+        world: &WorldView,
+    ) -> Result<Option<Value>, SpellError> {
+        let mut stack: Vec<Value> = Vec::with_capacity(self.stack_size);
+        let mut loops = vec![LoopState::default(); self.loops];
+        let load = |slots: &[Option<Value>], slot: Slot| {
+            slots[slot].ok_or_else(|| EvalError::UndefinedVariable(self.variables[slot].clone()))
+        };
+        let mut charge = |amount: Mana| {
+            if amount > budget - *spent {
+                *spent = budget;
+                return Err(SpellError::OutOfMana);
+            }
+            *spent += amount;
+            Ok(())
+        };
+        let mut pc = 0;
+        loop {
+            let instruction = &self.code[pc];
+            pc += 1;
+            match *instruction {
+                Instruction::Charge(amount) => charge(amount)?,
+                Instruction::Const(value) => stack.push(value),
+                Instruction::Load(slot) => stack.push(load(slots, slot)?),
+                Instruction::Store(slot) => slots[slot] = stack.pop(),
+                Instruction::Not => {
+                    let x = stack.pop().unwrap();
+                    stack.push(!x);
+                },
+                Instruction::Binary(op) => {
+                    let y = stack.pop().unwrap();
+                    let x = stack.pop().unwrap();
+                    stack.push(op.apply(x, y)?);
+                },
+                Instruction::Call(intrinsic) => {
+                    let args = stack.len() - intrinsic.arity;
+                    let value = (intrinsic.function)(&stack[args ..]);
+                    stack.truncate(args);
+                    stack.push(value);
+                },
+                Instruction::Query(query) => {
+                    let args = stack.len() - query.arity();
+                    let value = world.answer(query, &stack[args ..]);
+                    stack.truncate(args);
+                    stack.push(value);
+                },
+                Instruction::Fail(ref error) => return Err(error.clone()),
+                Instruction::Jump(target) => pc = target,
+                Instruction::JumpIfZero(target) => {
+                    if stack.pop().unwrap() == 0 {
+                        pc = target;
+                    }
+                },
+                Instruction::LoopStart { index, variable } => {
+                    let upper = stack.pop().unwrap();
+                    let lower = stack.pop().unwrap();
+                    loops[index] = LoopState {
+                        next: u64::from(lower),
+                        upper: u64::from(upper),
+                        shadowed: slots[variable],
+                    };
+                },
+                Instruction::LoopNext { index, variable, cost, exit } => {
+                    let state = &mut loops[index];
+                    if state.next > state.upper {
+                        slots[variable] = state.shadowed;
+                        pc = exit;
+                    } else {
+                        let value = state.next as Value;
+                        state.next += 1;
+                        charge(cost)?;
+                        slots[variable] = Some(value);
+                    }
+                },
+                Instruction::Return(slot) => return Ok(Some(load(slots, slot)?)),
+                Instruction::Halt => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::mana::interpret_metered;
+    use crate::magic::parser::parse;
+    use crate::magic::testing::Generator;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const SPELLS: Generator = Generator {
+        names: &["a", "b", "i", "x", "y"],
+        constants: &[0, 1, 2, 3, 7, 31, 32, Value::MAX],
+        calls: &[
+            ("popcount", 1), ("parity", 1), ("rotate_left", 2), ("min", 2),
+            ("max", 1), ("no_such_intrinsic", 1),
+        ],
+        dynamic_bounds: true,
+    };
+
+    fn assert_same(spec: &Spec, context: &Context, intrinsics: &IntrinsicSet, budget: Mana) {
+        let costs = ManaCosts::default();
+        let mut expected_context = context.clone();
+        let expected = interpret_metered(spec, &mut expected_context, &costs, intrinsics, budget);
+        let mut actual_context = context.clone();
+        let actual = compile(spec, &costs, intrinsics).run(&mut actual_context, budget);
+        assert_eq!(actual, expected, "{:?}", spec);
+        assert_eq!(actual_context, expected_context, "{:?}", spec);
+    }
+
+    #[test]
+    fn matches_interpreter_on_examples() {
+        let spells = [
+            "c = 0; for i in 0 ..= 31 { c = c + ((x >> i) & 1); } return c;",
+            "if x < 10 { y = x * 2; } else { y = x / (x - x); } return y;",
+            "i = 5; for i in 1 ..= 3 { y = i; } return i;",
+            "for i in 0 ..= 4294967295 { } return x;",
+            "y = popcount(x) + min(x, 3); return y;",
+            "y = max(x); return y;",
+            "y = frobnicate(x); return y;",
+            "return undefined;",
+            "for i in 0 ..= 3 { for j in 0 ..= i { if j == 2 { return j; } } }",
+            "",
+        ];
+        let context: Context = [(Variable::new("x"), 37)].into_iter().collect();
+        for source in spells {
+            let spec = parse(source).unwrap();
+            for budget in [0, 1, 5, 17, 100, 100_000] {
+                assert_same(&spec, &context, &IntrinsicSet::all(), budget);
+                assert_same(&spec, &context, &IntrinsicSet::for_run(3), budget);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_on_random_spells() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0 .. 2000 {
+            let spec = SPELLS.spec(&mut rng, 4);
+            let mut context = Context::new();
+            for name in SPELLS.names {
+                if rng.gen_bool(0.5) {
+                    context.insert(Variable::new(name), rng.gen_range(0 .. 40));
+                }
+            }
+            let intrinsics = IntrinsicSet::for_run(rng.gen());
+            let budget = rng.gen_range(0 .. 400);
+            assert_same(&spec, &context, &intrinsics, budget);
+            assert_same(&spec, &context, &IntrinsicSet::all(), 1_000_000);
+        }
+    }
+}
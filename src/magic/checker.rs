@@ -0,0 +1,573 @@
+// Static checks run on a spell before it's cast. Alongside the diagnostics,
+// this works out an upper bound on how much mana the spell can spend, by
+// tracking a range of possible values for every variable so that loops with
+// computed bounds can still be bounded.
+
+use crate::magic::{BinaryOp, Expr, Spec, Value, Variable};
+use crate::magic::compiler::MAX_UNROLLED_ITERATIONS;
+use crate::magic::intrinsics::{self, IntrinsicSet};
+use crate::magic::mana::{Mana, ManaCosts};
+use crate::magic::parser::{Span, SpanTable};
+use crate::magic::world::{self, WorldQuery};
+use std::collections::{BTreeMap, BTreeSet};
+use thiserror::Error;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum DiagnosticKind {
+    #[error("`{0}` is used before it's assigned")]
+    UndefinedVariable(Variable),
+    #[error("`{0}` might not be assigned here")]
+    PossiblyUndefinedVariable(Variable),
+    #[error("unreachable code")]
+    Unreachable,
+    #[error("the loop over `{0}` isn't bounded; it could run {1} times")]
+    UnboundedLoop(Variable, u64),
+    #[error("there is no intrinsic called `{0}`")]
+    UnknownIntrinsic(String),
+    #[error("`{0}` was not granted this run")]
+    IntrinsicNotGranted(String),
+    #[error("`{name}` takes {expected} arguments but was given {found}")]
+    WrongNumberOfArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    #[error("this always divides by zero")]
+    DivisionByZero,
+    #[error("this always shifts by {0} or more, which fails")]
+    OverShift(Value),
+}
+
+impl DiagnosticKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            DiagnosticKind::PossiblyUndefinedVariable(_) |
+            DiagnosticKind::Unreachable => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub kind: DiagnosticKind,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        self.kind.severity()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Analysis {
+    /// In the order they appear in the source.
+    pub diagnostics: Vec<Diagnostic>,
+    /// The most mana the spell could spend under the metered interpreter, or
+    /// `None` if it has an unbounded loop.
+    pub worst_case_mana: Option<Mana>,
+}
+
+impl Analysis {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics.iter().any(|d| d.severity() == Severity::Error)
+    }
+}
+
+// The smallest and largest value an expression might take.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Range {
+    low: Value,
+    high: Value,
+}
+
+impl Range {
+    const ANY: Range = Range { low: 0, high: Value::MAX };
+    const BOOLEAN: Range = Range { low: 0, high: 1 };
+    const BIT_COUNT: Range = Range { low: 0, high: Value::BITS };
+
+    fn exactly(value: Value) -> Range {
+        Range { low: value, high: value }
+    }
+
+    fn join(self, other: Range) -> Range {
+        Range {
+            low: self.low.min(other.low),
+            high: self.high.max(other.high),
+        }
+    }
+
+    // Everything up to the highest bit either side could have set.
+    fn bits(self, other: Range) -> Range {
+        let combined = self.high | other.high;
+        Range { low: 0, high: Value::MAX.checked_shr(combined.leading_zeros()).unwrap_or(0) }
+    }
+
+    fn binary(op: BinaryOp, x: Range, y: Range) -> Range {
+        let checked = |low: Option<Value>, high: Option<Value>| match (low, high) {
+            (Some(low), Some(high)) => Range { low, high },
+            _ => Range::ANY,
+        };
+        match op {
+            BinaryOp::Add => checked(x.low.checked_add(y.low), x.high.checked_add(y.high)),
+            BinaryOp::Sub if x.low >= y.high => Range {
+                low: x.low - y.high,
+                high: x.high - y.low,
+            },
+            BinaryOp::Mul => checked(x.low.checked_mul(y.low), x.high.checked_mul(y.high)),
+            BinaryOp::Div => Range {
+                low: x.low / y.high.max(1),
+                high: x.high / y.low.max(1),
+            },
+            BinaryOp::Mod => Range {
+                low: 0,
+                high: x.high.min(y.high.saturating_sub(1)),
+            },
+            BinaryOp::And => Range { low: 0, high: x.high.min(y.high) },
+            BinaryOp::Or | BinaryOp::Xor => x.bits(y),
+            BinaryOp::ShiftLeft if y.high < Value::BITS && x.high.leading_zeros() >= y.high =>
+                Range { low: x.low << y.low, high: x.high << y.high },
+            BinaryOp::ShiftRight => Range {
+                low: x.low.checked_shr(y.high).unwrap_or(0),
+                high: x.high.checked_shr(y.low).unwrap_or(0),
+            },
+            _ if op.is_comparison() => Range::BOOLEAN,
+            _ => Range::ANY,
+        }
+    }
+
+    // How many times `for _ in self ..= upper` could run at most.
+    fn iterations(self, upper: Range) -> u64 {
+        if upper.high < self.low {
+            0
+        } else {
+            (upper.high - self.low) as u64 + 1
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Assigned {
+    Always,
+    Sometimes,
+}
+
+#[derive(Clone, Debug, Default)]
+struct State {
+    variables: BTreeMap<Variable, (Assigned, Range)>,
+}
+
+impl State {
+    // The state after control flow from `self` and `other` meets.
+    fn join(&self, other: &State) -> State {
+        let names: BTreeSet<&Variable> = self.variables.keys()
+            .chain(other.variables.keys())
+            .collect();
+        let variables = names.into_iter()
+            .map(|name| {
+                let joined = match (self.variables.get(name), other.variables.get(name)) {
+                    (Some(&(Assigned::Always, x)), Some(&(Assigned::Always, y))) =>
+                        (Assigned::Always, x.join(y)),
+                    (Some(&(_, x)), Some(&(_, y))) => (Assigned::Sometimes, x.join(y)),
+                    (Some(&(_, x)), None) | (None, Some(&(_, x))) => (Assigned::Sometimes, x),
+                    (None, None) => unreachable!(),
+                };
+                (name.clone(), joined)
+            })
+            .collect();
+        State { variables }
+    }
+}
+
+fn add(x: Option<Mana>, y: Option<Mana>) -> Option<Mana> {
+    Some(x?.saturating_add(y?))
+}
+
+/// Adds every variable `spec` might assign to `variables`. Loop variables
+/// aren't included, since a loop puts its variable back when it ends.
+pub fn assigned_in(spec: &Spec, variables: &mut BTreeSet<Variable>) {
+    match spec {
+        Spec::Assign(ref var, _) => {
+            variables.insert(var.clone());
+        },
+        Spec::Block(ref specs) => {
+            for s in specs {
+                assigned_in(s, variables);
+            }
+        },
+        Spec::For(ref var, _, _, ref body) => {
+            assigned_in(body, variables);
+            variables.remove(var);
+        },
+        Spec::If(_, ref if_true, ref if_false) => {
+            assigned_in(if_true, variables);
+            assigned_in(if_false, variables);
+        },
+        Spec::Return(_) => {},
+    }
+}
+
+struct Checker<'a> {
+    spans: &'a SpanTable,
+    // Index of the next span to hand out; nodes are visited in post-order.
+    next_span: usize,
+    costs: &'a ManaCosts,
+    intrinsics: &'a IntrinsicSet,
+    state: State,
+    // Nothing is reported in code that can't run.
+    reachable: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+// What a statement does to control flow and how much it can cost.
+struct Summary {
+    always_returns: bool,
+    mana: Option<Mana>,
+}
+
+impl<'a> Checker<'a> {
+    fn span(&mut self) -> Span {
+        let span = self.spans.spans.get(self.next_span).cloned().unwrap_or_default();
+        self.next_span += 1;
+        span
+    }
+
+    fn report(&mut self, span: Span, kind: DiagnosticKind) {
+        if self.reachable {
+            self.diagnostics.push(Diagnostic { span, kind });
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> (Range, Mana) {
+        let (range, mana) = match expr {
+            Expr::Var(ref var) => {
+                let span = self.span();
+                return match self.state.variables.get(var).cloned() {
+                    Some((Assigned::Always, range)) => (range, self.costs.expr_node),
+                    Some((Assigned::Sometimes, range)) => {
+                        self.report(span, DiagnosticKind::PossiblyUndefinedVariable(var.clone()));
+                        (range, self.costs.expr_node)
+                    },
+                    None => {
+                        self.report(span, DiagnosticKind::UndefinedVariable(var.clone()));
+                        (Range::ANY, self.costs.expr_node)
+                    },
+                };
+            },
+            Expr::Const(value) => (Range::exactly(*value), 0),
+            Expr::Not(ref x) => {
+                let (x, mana) = self.expr(x);
+                (Range { low: !x.high, high: !x.low }, mana)
+            },
+            Expr::Call(ref name, ref args) => {
+                let mut ranges = Vec::new();
+                let mut mana: Mana = 0;
+                for arg in args {
+                    let (range, cost) = self.expr(arg);
+                    ranges.push(range);
+                    mana = mana.saturating_add(cost);
+                }
+                let span = self.span();
+                let range = self.call(name, &ranges, span);
+                let cost = match world::lookup(name) {
+                    Some(query) => self.costs.queries.get(query),
+                    None => intrinsics::lookup(name).map_or(0, |intrinsic| intrinsic.cost),
+                };
+                return (range, mana.saturating_add(cost).saturating_add(self.costs.expr_node));
+            },
+            _ => {
+                let (op, x, y) = expr.as_binary().unwrap();
+                let (x, x_mana) = self.expr(x);
+                let (y, y_mana) = self.expr(y);
+                let span = self.span();
+                // Only operations that fail whatever the inputs are reported.
+                match op {
+                    BinaryOp::Div | BinaryOp::Mod if y.high == 0 =>
+                        self.report(span, DiagnosticKind::DivisionByZero),
+                    BinaryOp::ShiftLeft | BinaryOp::ShiftRight if y.low >= Value::BITS =>
+                        self.report(span, DiagnosticKind::OverShift(y.low)),
+                    _ => {},
+                }
+                let mana = x_mana.saturating_add(y_mana).saturating_add(self.costs.expr_node);
+                return (Range::binary(op, x, y), mana);
+            },
+        };
+        self.span();
+        (range, mana.saturating_add(self.costs.expr_node))
+    }
+
+    fn call(&mut self, name: &str, args: &[Range], span: Span) -> Range {
+        if let Some(query) = world::lookup(name) {
+            return self.query(query, args, span);
+        }
+        let Some(intrinsic) = intrinsics::lookup(name) else {
+            self.report(span, DiagnosticKind::UnknownIntrinsic(name.to_string()));
+            return Range::ANY;
+        };
+        if self.intrinsics.get(name).is_none() {
+            self.report(span, DiagnosticKind::IntrinsicNotGranted(name.to_string()));
+        }
+        if intrinsic.arity != args.len() {
+            self.report(span, DiagnosticKind::WrongNumberOfArguments {
+                name: name.to_string(),
+                expected: intrinsic.arity,
+                found: args.len(),
+            });
+            return Range::ANY;
+        }
+        match name {
+            "popcount" | "leading_zeros" | "trailing_zeros" => Range::BIT_COUNT,
+            "parity" => Range::BOOLEAN,
+            "min" => Range {
+                low: args[0].low.min(args[1].low),
+                high: args[0].high.min(args[1].high),
+            },
+            "max" => Range {
+                low: args[0].low.max(args[1].low),
+                high: args[0].high.max(args[1].high),
+            },
+            _ => Range::ANY,
+        }
+    }
+
+    fn query(&mut self, query: WorldQuery, args: &[Range], span: Span) -> Range {
+        if query.arity() != args.len() {
+            self.report(span, DiagnosticKind::WrongNumberOfArguments {
+                name: query.name().to_string(),
+                expected: query.arity(),
+                found: args.len(),
+            });
+            return Range::ANY;
+        }
+        match query {
+            WorldQuery::TargetHasStatus => Range::BOOLEAN,
+            _ => Range::ANY,
+        }
+    }
+
+    fn spec(&mut self, spec: &Spec) -> Summary {
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                let (range, mana) = self.expr(expr);
+                self.span();
+                self.state.variables.insert(var.clone(), (Assigned::Always, range));
+                Summary {
+                    always_returns: false,
+                    mana: Some(mana.saturating_add(self.costs.assign)),
+                }
+            },
+            Spec::Block(ref specs) => {
+                let mut summary = Summary { always_returns: false, mana: Some(0) };
+                let mut unreachable: Option<(Span, Span)> = None;
+                let reachable = self.reachable;
+                for s in specs {
+                    if summary.always_returns {
+                        self.reachable = false;
+                        self.spec(s);
+                        let span = self.spans.spans.get(self.next_span - 1)
+                            .cloned()
+                            .unwrap_or_default();
+                        let first = unreachable.map_or(span, |(first, _)| first);
+                        unreachable = Some((first, span));
+                        continue;
+                    }
+                    let inner = self.spec(s);
+                    summary.always_returns = inner.always_returns;
+                    summary.mana = add(summary.mana, inner.mana);
+                }
+                self.reachable = reachable;
+                if let Some((first, last)) = unreachable {
+                    let span = Span { start: first.start, end: last.end };
+                    self.report(span, DiagnosticKind::Unreachable);
+                }
+                self.span();
+                summary
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                let (lower, lower_mana) = self.expr(lower);
+                let (upper, upper_mana) = self.expr(upper);
+                let before = self.state.clone();
+
+                // Rather than finding a fixed point, forget everything about
+                // the variables the body assigns.
+                let mut assigned = BTreeSet::new();
+                assigned_in(body, &mut assigned);
+                for name in assigned {
+                    if let Some(entry) = self.state.variables.get_mut(&name) {
+                        entry.1 = Range::ANY;
+                    }
+                }
+                let range = Range { low: lower.low, high: upper.high };
+                self.state.variables.insert(var.clone(), (Assigned::Always, range));
+                let inner = self.spec(body);
+                let span = self.span();
+
+                let runs_at_least_once = lower.high <= upper.low;
+                let mut after = if runs_at_least_once {
+                    self.state.clone()
+                } else {
+                    self.state.join(&before)
+                };
+                match before.variables.get(var) {
+                    Some(entry) => after.variables.insert(var.clone(), *entry),
+                    None => after.variables.remove(var),
+                };
+                self.state = after;
+
+                let iterations = lower.iterations(upper);
+                let per_iteration = add(inner.mana, Some(self.costs.loop_iteration));
+                let loop_mana = if iterations > MAX_UNROLLED_ITERATIONS as u64 {
+                    self.report(span, DiagnosticKind::UnboundedLoop(var.clone(), iterations));
+                    None
+                } else {
+                    per_iteration.map(|mana| mana.saturating_mul(iterations))
+                };
+                Summary {
+                    always_returns: inner.always_returns && runs_at_least_once,
+                    mana: add(Some(lower_mana.saturating_add(upper_mana)), loop_mana),
+                }
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                let (_, cond_mana) = self.expr(cond);
+                let before = self.state.clone();
+                let true_summary = self.spec(if_true);
+                let true_state = std::mem::replace(&mut self.state, before);
+                let false_summary = self.spec(if_false);
+                self.span();
+                // Paths that return don't flow on to what comes next.
+                self.state = match (true_summary.always_returns, false_summary.always_returns) {
+                    (true, false) => self.state.clone(),
+                    (false, true) => true_state,
+                    _ => self.state.join(&true_state),
+                };
+                let branches = match (true_summary.mana, false_summary.mana) {
+                    (Some(x), Some(y)) => Some(x.max(y)),
+                    _ => None,
+                };
+                Summary {
+                    always_returns: true_summary.always_returns && false_summary.always_returns,
+                    mana: add(Some(cond_mana.saturating_add(self.costs.branch)), branches),
+                }
+            },
+            Spec::Return(ref var) => {
+                let span = self.span();
+                match self.state.variables.get(var) {
+                    Some((Assigned::Always, _)) => {},
+                    Some((Assigned::Sometimes, _)) =>
+                        self.report(span, DiagnosticKind::PossiblyUndefinedVariable(var.clone())),
+                    None => self.report(span, DiagnosticKind::UndefinedVariable(var.clone())),
+                }
+                Summary { always_returns: true, mana: Some(0) }
+            },
+        }
+    }
+}
+
+/// Checks a spell that will be given `inputs`. `spans` should come from
+/// `parser::parse_with_spans`; spells without a source can pass an empty
+/// table, in which case every diagnostic has an empty span.
+pub fn check(
+    spec: &Spec,
+    spans: &SpanTable,
+    inputs: &[Variable],
+    costs: &ManaCosts,
+    intrinsics: &IntrinsicSet,
+) -> Analysis {
+    let mut checker = Checker {
+        spans,
+        next_span: 0,
+        costs,
+        intrinsics,
+        state: State {
+            variables: inputs.iter()
+                .map(|var| (var.clone(), (Assigned::Always, Range::ANY)))
+                .collect(),
+        },
+        reachable: true,
+        diagnostics: Vec::new(),
+    };
+    let summary = checker.spec(spec);
+    let mut diagnostics = checker.diagnostics;
+    diagnostics.sort_by_key(|d| d.span.start);
+    Analysis { diagnostics, worst_case_mana: summary.mana }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::Context;
+    use crate::magic::mana::interpret_metered;
+    use crate::magic::parser::{parse_with_spans, Position};
+
+    fn analyze(source: &str) -> Analysis {
+        let (spec, spans) = parse_with_spans(source).unwrap();
+        check(&spec, &spans, &[Variable::new("x")], &ManaCosts::default(), &IntrinsicSet::all())
+    }
+
+    // What the interpreter spends with `x` set to `value`.
+    fn spent(source: &str, value: Value) -> Mana {
+        let (spec, _) = parse_with_spans(source).unwrap();
+        let mut context: Context = [(Variable::new("x"), value)].into_iter().collect();
+        interpret_metered(&spec, &mut context, &ManaCosts::default(), &IntrinsicSet::all(), Mana::MAX)
+            .mana_spent
+    }
+
+    fn span(start: (usize, usize), end: (usize, usize)) -> Span {
+        Span {
+            start: Position { line: start.0, column: start.1 },
+            end: Position { line: end.0, column: end.1 },
+        }
+    }
+
+    #[test]
+    fn constant_loops_are_bounded_exactly() {
+        let source = "c = 0; for i in 0 ..= 3 { c = c + i; } return c;";
+        let analysis = analyze(source);
+        assert_eq!(analysis.diagnostics, vec![]);
+        assert_eq!(analysis.worst_case_mana, Some(spent(source, 0)));
+    }
+
+    #[test]
+    fn computed_bounds_use_the_range_of_the_bound() {
+        // `n` is at most 7, whatever `x` is.
+        let source = "n = x & 7; for i in 0 ..= n { y = i * 2; } return n;";
+        let analysis = analyze(source);
+        assert_eq!(analysis.diagnostics, vec![]);
+        assert_eq!(analysis.worst_case_mana, Some(spent(source, 7)));
+        assert!(spent(source, 3) < spent(source, 7));
+
+        // Branches are bounded by the dearer side.
+        let source = "n = x & 7; for i in 0 ..= n { if i < 2 { y = i * 2; } } return n;";
+        assert!(analyze(source).worst_case_mana > Some(spent(source, 7)));
+    }
+
+    #[test]
+    fn dynamic_bounds_are_unbounded() {
+        let analysis = analyze("for i in 0 ..= x { }\nreturn x;");
+        assert_eq!(analysis.worst_case_mana, None);
+        assert_eq!(analysis.diagnostics, vec![Diagnostic {
+            span: span((1, 1), (1, 21)),
+            kind: DiagnosticKind::UnboundedLoop(Variable::new("i"), 1 << 32),
+        }]);
+    }
+
+    #[test]
+    fn certain_faults_are_flagged() {
+        let analysis = analyze("d = x & 0;\ny = 5 / d;\nz = x << (x & 7) + 32;\nw = x / (x | 1);");
+        assert_eq!(analysis.diagnostics, vec![
+            Diagnostic { span: span((2, 5), (2, 10)), kind: DiagnosticKind::DivisionByZero },
+            Diagnostic { span: span((3, 5), (3, 22)), kind: DiagnosticKind::OverShift(32) },
+        ]);
+        assert!(analysis.has_errors());
+
+        // Shifts and divisions that only might fail aren't.
+        assert_eq!(analyze("y = 1 << x; z = 5 % x;").diagnostics, vec![]);
+    }
+}
@@ -0,0 +1,705 @@
+// Lowers `Spec` programs to straight-line `Bundle`s for a `VLIW`.
+//
+// Each lane runs its own copy of the spell on its own inputs, so a machine
+// with N lanes casts N spells at once. There are no jumps: loops are
+// unrolled, so their bounds have to be known at compile time, and both sides
+// of an `if` are evaluated and merged with a mask. Lanes that have returned
+// keep running, but their result is frozen. Lanes that fail, by dividing by
+// zero or shifting too far, are frozen the same way and return nothing.
+// Constants are only folded where that can't fail, so a fault in a branch no
+// lane takes isn't an error.
+//
+// Compilation happens in three steps. The spell is first flattened into
+// single-assignment lane instructions over an unbounded set of virtual
+// registers, with constants folded and common subexpressions shared. Those
+// are then packed into as few bundles as dependencies and the issue width
+// allow, and finally assigned to physical registers by linear scan.
+
+use crate::magic::{BinaryOp, Bundle, EvalError, Expr, LaneInstruction};
+use crate::magic::{LaneOp, Operand, Spec, Value, Variable, VLIW};
+use crate::magic::check_arity;
+use crate::magic::intrinsics::{self, IntrinsicSet};
+use crate::magic::mana::{Mana, ManaCosts};
+use crate::magic::vliw::{Cycles, Machine, VliwError};
+use crate::magic::Context;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use thiserror::Error;
+
+/// The most loop iterations a spell may unroll to.
+pub const MAX_UNROLLED_ITERATIONS: usize = 1 << 16;
+/// The most lane instructions a spell may compile to.
+pub const MAX_INSTRUCTIONS: usize = 1 << 16;
+
+const ALL_LANES: Operand = Operand::Immediate(Value::MAX);
+const NO_LANES: Operand = Operand::Immediate(0);
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum CompileError {
+    #[error("this machine has no {0:?} op")]
+    MissingLaneOp(LaneOp),
+    #[error("this machine can't issue any lane ops")]
+    NoIssueSlots,
+    #[error("the bounds of the loop over `{0}` aren't constant")]
+    DynamicLoopBounds(Variable),
+    #[error("loops unroll to more than {} iterations", MAX_UNROLLED_ITERATIONS)]
+    LoopTooLong,
+    #[error("the spell compiles to more than {} instructions", MAX_INSTRUCTIONS)]
+    ProgramTooLarge,
+    #[error("the spell needs more than {available} registers")]
+    OutOfRegisters { available: usize },
+    #[error("`{0}` can't be compiled")]
+    UnsupportedIntrinsic(String),
+    #[error("`{0}` was not granted this run")]
+    IntrinsicNotGranted(String),
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+/// A spell compiled for a particular `VLIW`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compiled {
+    pub bundles: Vec<Bundle>,
+    /// The register each input variable has to be loaded into before
+    /// running. Variables the spell never reads are left out.
+    pub inputs: BTreeMap<Variable, usize>,
+    /// The value returned in each lane, if `returned` is nonzero there.
+    pub result: Operand,
+    /// All ones in the lanes that hit a `return`, zero elsewhere.
+    pub returned: Operand,
+    /// All ones in the lanes that failed, zero elsewhere.
+    pub failed: Operand,
+}
+
+impl Compiled {
+    pub fn mana_cost(&self, costs: &ManaCosts) -> Mana {
+        self.bundles.len() as Mana * costs.bundle
+    }
+
+    /// Runs one copy of the spell per context, which must be at most one per
+    /// lane. Inputs missing from a context are read as 0, and spare lanes
+    /// repeat the last context so they can't fault where it doesn't.
+    /// Returns what each copy returned, or `None` if it failed, along with
+    /// the cycles taken.
+    pub fn run(
+        &self,
+        vliw: &VLIW,
+        contexts: &[Context],
+    ) -> Result<(Vec<Option<Value>>, Cycles), VliwError> {
+        vliw.check()?;
+        if contexts.len() > vliw.number_of_lanes {
+            return Err(VliwError::TooManyContexts {
+                found: contexts.len(),
+                lanes: vliw.number_of_lanes,
+            });
+        }
+        let mut machine = Machine::new(vliw);
+        for (var, register) in &self.inputs {
+            let values: Vec<Value> = (0 .. vliw.number_of_lanes)
+                .map(|lane| {
+                    contexts.get(lane)
+                        .or(contexts.last())
+                        .and_then(|context| context.get(var).cloned())
+                        .unwrap_or(0)
+                })
+                .collect();
+            machine.set_register(*register, &values);
+        }
+        let cycles = machine.run(&self.bundles)?;
+        let read = |operand: &Operand, lane: usize| match operand {
+            Operand::Register(register) => machine.register(*register)[lane],
+            Operand::Immediate(value) => *value,
+        };
+        let results = (0 .. contexts.len())
+            .map(|lane| {
+                if read(&self.returned, lane) != 0 && read(&self.failed, lane) == 0 {
+                    Some(read(&self.result, lane))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        Ok((results, cycles))
+    }
+}
+
+// A virtual register is defined either by an input or by a lane instruction
+// whose operands refer to other virtual registers.
+#[derive(Clone, Debug)]
+enum Definition {
+    Input,
+    Op(LaneOp, Operand, Operand),
+}
+
+impl Definition {
+    fn sources(&self) -> Vec<usize> {
+        match self {
+            Definition::Input => Vec::new(),
+            Definition::Op(_, lhs, rhs) => [lhs, rhs].iter()
+                .filter_map(|operand| match operand {
+                    Operand::Register(register) => Some(*register),
+                    Operand::Immediate(_) => None,
+                })
+                .collect(),
+        }
+    }
+}
+
+struct Lowering<'a> {
+    vliw: &'a VLIW,
+    intrinsics: &'a IntrinsicSet,
+    definitions: Vec<Definition>,
+    shared: HashMap<(LaneOp, Operand, Operand), Operand>,
+    inputs: BTreeMap<Variable, usize>,
+    env: BTreeMap<Variable, Operand>,
+    // Lanes taking the branch currently being compiled, as a mask.
+    branch: Operand,
+    returned: Operand,
+    result: Operand,
+    failed: Operand,
+    // Set once the rest of the current branch can't be reached.
+    dead: bool,
+    unrolled: usize,
+}
+
+impl<'a> Lowering<'a> {
+    fn emit(&mut self, op: LaneOp, lhs: Operand, rhs: Operand) -> Result<Operand, CompileError> {
+        let rhs = if op.is_unary() { NO_LANES } else { rhs };
+        if let (Operand::Immediate(x), Operand::Immediate(y)) = (lhs, rhs) {
+            if let Some(value) = op.apply(x, y) {
+                return Ok(Operand::Immediate(value));
+            }
+        }
+        match (op, lhs, rhs) {
+            (LaneOp::And, ALL_LANES, other) | (LaneOp::And, other, ALL_LANES) |
+            (LaneOp::Or, NO_LANES, other) | (LaneOp::Or, other, NO_LANES) |
+            (LaneOp::Xor, NO_LANES, other) | (LaneOp::Xor, other, NO_LANES) |
+            (LaneOp::Add, NO_LANES, other) | (LaneOp::Add, other, NO_LANES) |
+            (LaneOp::Subtract, other, NO_LANES) | (LaneOp::Shift, other, NO_LANES) =>
+                return Ok(other),
+            (LaneOp::And, NO_LANES, _) | (LaneOp::And, _, NO_LANES) =>
+                return Ok(NO_LANES),
+            _ => {},
+        }
+        if let Some(operand) = self.shared.get(&(op, lhs, rhs)) {
+            return Ok(*operand);
+        }
+        if !self.vliw.lane_ops.contains(&op) {
+            return Err(CompileError::MissingLaneOp(op));
+        }
+        if self.definitions.len() >= MAX_INSTRUCTIONS {
+            return Err(CompileError::ProgramTooLarge);
+        }
+        let operand = Operand::Register(self.definitions.len());
+        self.definitions.push(Definition::Op(op, lhs, rhs));
+        self.shared.insert((op, lhs, rhs), operand);
+        Ok(operand)
+    }
+
+    // `mask` must be all ones or all zeros in every lane.
+    fn select(&mut self, mask: Operand, if_true: Operand, if_false: Operand) -> Result<Operand, CompileError> {
+        if if_true == if_false {
+            return Ok(if_true);
+        }
+        let difference = self.emit(LaneOp::Xor, if_false, if_true)?;
+        let masked = self.emit(LaneOp::And, difference, mask)?;
+        self.emit(LaneOp::Xor, if_false, masked)
+    }
+
+    // Turns a 1 or 0 into all ones or all zeros.
+    fn mask(&mut self, boolean: Operand) -> Result<Operand, CompileError> {
+        self.emit(LaneOp::Negate, boolean, NO_LANES)
+    }
+
+    // Fails the active lanes where `boolean` is 1.
+    fn fail_lanes(&mut self, boolean: Operand) -> Result<(), CompileError> {
+        let mask = self.mask(boolean)?;
+        let active = self.active()?;
+        let failing = self.emit(LaneOp::And, mask, active)?;
+        self.failed = self.emit(LaneOp::Or, self.failed, failing)?;
+        self.returned = self.emit(LaneOp::Or, self.returned, failing)?;
+        Ok(())
+    }
+
+    // The lanes that are executing the current statement.
+    fn active(&mut self) -> Result<Operand, CompileError> {
+        let running = self.emit(LaneOp::Not, self.returned, NO_LANES)?;
+        self.emit(LaneOp::And, self.branch, running)
+    }
+
+    fn variable(&mut self, var: &Variable) -> Operand {
+        if let Some(operand) = self.env.get(var) {
+            return *operand;
+        }
+        let definitions = &mut self.definitions;
+        let register = *self.inputs.entry(var.clone()).or_insert_with(|| {
+            definitions.push(Definition::Input);
+            definitions.len() - 1
+        });
+        Operand::Register(register)
+    }
+
+    fn binary(&mut self, op: BinaryOp, x: Operand, y: Operand) -> Result<Operand, CompileError> {
+        if let (Operand::Immediate(x), Operand::Immediate(y)) = (x, y) {
+            if let Ok(value) = op.apply(x, y) {
+                return Ok(Operand::Immediate(value));
+            }
+        }
+        match op {
+            BinaryOp::Or => self.emit(LaneOp::Or, x, y),
+            BinaryOp::And => self.emit(LaneOp::And, x, y),
+            BinaryOp::Xor => self.emit(LaneOp::Xor, x, y),
+            BinaryOp::Add => self.emit(LaneOp::Add, x, y),
+            BinaryOp::Sub => self.emit(LaneOp::Subtract, x, y),
+            BinaryOp::Mul => self.emit(LaneOp::Multiply, x, y),
+            BinaryOp::Div | BinaryOp::Mod => {
+                let zero = self.emit(LaneOp::Equal, y, NO_LANES)?;
+                self.fail_lanes(zero)?;
+                // Inactive lanes, including the ones that just failed, may
+                // hold anything, so keep them from faulting by dividing by 1
+                // there instead.
+                let active = self.active()?;
+                let inactive = self.emit(LaneOp::Add, active, Operand::Immediate(1))?;
+                let divisor = self.emit(LaneOp::Or, y, inactive)?;
+                let lane_op = if op == BinaryOp::Div { LaneOp::Divide } else { LaneOp::Remainder };
+                self.emit(lane_op, x, divisor)
+            },
+            BinaryOp::ShiftLeft | BinaryOp::ShiftRight => {
+                // The interpreter fails on shifts by 32 or more, where
+                // `LaneOp::Shift` would give 0, so fail those lanes instead.
+                let too_far = self.emit(LaneOp::LessThan, Operand::Immediate(Value::BITS - 1), y)?;
+                self.fail_lanes(too_far)?;
+                let amount = if op == BinaryOp::ShiftLeft {
+                    y
+                } else {
+                    self.emit(LaneOp::Negate, y, NO_LANES)?
+                };
+                self.emit(LaneOp::Shift, x, amount)
+            },
+            BinaryOp::Eq => self.emit(LaneOp::Equal, x, y),
+            BinaryOp::Ne => {
+                let equal = self.emit(LaneOp::Equal, x, y)?;
+                self.emit(LaneOp::Xor, equal, Operand::Immediate(1))
+            },
+            BinaryOp::Lt => self.emit(LaneOp::LessThan, x, y),
+            BinaryOp::Gt => self.emit(LaneOp::LessThan, y, x),
+            BinaryOp::Le => {
+                let greater = self.emit(LaneOp::LessThan, y, x)?;
+                self.emit(LaneOp::Xor, greater, Operand::Immediate(1))
+            },
+            BinaryOp::Ge => {
+                let less = self.emit(LaneOp::LessThan, x, y)?;
+                self.emit(LaneOp::Xor, less, Operand::Immediate(1))
+            },
+        }
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<Operand, CompileError> {
+        let Some(intrinsic) = self.intrinsics.get(name) else {
+            return Err(if intrinsics::lookup(name).is_some() {
+                CompileError::IntrinsicNotGranted(name.to_string())
+            } else {
+                EvalError::UnknownIntrinsic(name.to_string()).into()
+            });
+        };
+        check_arity(intrinsic, args)?;
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.expr(arg)?);
+        }
+        if values.iter().all(|value| matches!(value, Operand::Immediate(_))) {
+            let values: Vec<Value> = values.iter()
+                .map(|value| match value {
+                    Operand::Immediate(value) => *value,
+                    Operand::Register(_) => unreachable!(),
+                })
+                .collect();
+            return Ok(Operand::Immediate((intrinsic.function)(&values)));
+        }
+        match name {
+            "min" | "max" => {
+                let (x, y) = (values[0], values[1]);
+                let less = self.emit(LaneOp::LessThan, x, y)?;
+                let mask = self.mask(less)?;
+                if name == "min" {
+                    self.select(mask, x, y)
+                } else {
+                    self.select(mask, y, x)
+                }
+            },
+            _ => Err(CompileError::UnsupportedIntrinsic(name.to_string())),
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) -> Result<Operand, CompileError> {
+        match expr {
+            Expr::Var(ref var) => Ok(self.variable(var)),
+            Expr::Const(value) => Ok(Operand::Immediate(*value)),
+            Expr::Not(ref x) => {
+                let x = self.expr(x)?;
+                self.emit(LaneOp::Not, x, NO_LANES)
+            },
+            Expr::Call(ref name, ref args) => self.call(name, args),
+            _ => {
+                let (op, x, y) = expr.as_binary().unwrap();
+                let x = self.expr(x)?;
+                let y = self.expr(y)?;
+                self.binary(op, x, y)
+            },
+        }
+    }
+
+    fn spec(&mut self, spec: &Spec) -> Result<(), CompileError> {
+        if self.dead {
+            return Ok(());
+        }
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                let value = self.expr(expr)?;
+                self.env.insert(var.clone(), value);
+            },
+            Spec::Block(ref specs) => {
+                for s in specs {
+                    self.spec(s)?;
+                }
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                let (lower, upper) = match (self.expr(lower)?, self.expr(upper)?) {
+                    (Operand::Immediate(lower), Operand::Immediate(upper)) => (lower, upper),
+                    _ => return Err(CompileError::DynamicLoopBounds(var.clone())),
+                };
+                let shadowed = self.env.get(var).cloned();
+                for i in lower ..= upper {
+                    self.unrolled += 1;
+                    if self.unrolled > MAX_UNROLLED_ITERATIONS {
+                        return Err(CompileError::LoopTooLong);
+                    }
+                    self.env.insert(var.clone(), Operand::Immediate(i));
+                    self.spec(body)?;
+                    if self.dead {
+                        break;
+                    }
+                }
+                if let Some(x) = shadowed {
+                    self.env.insert(var.clone(), x);
+                } else {
+                    self.env.remove(var);
+                }
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                let is_zero = match self.expr(cond)? {
+                    Operand::Immediate(0) => return self.spec(if_false),
+                    Operand::Immediate(_) => return self.spec(if_true),
+                    cond => self.emit(LaneOp::Equal, cond, NO_LANES)?,
+                };
+                let taken = self.emit(LaneOp::Subtract, is_zero, Operand::Immediate(1))?;
+                let not_taken = self.mask(is_zero)?;
+                let outer = self.branch;
+                let before = self.env.clone();
+
+                self.branch = self.emit(LaneOp::And, outer, taken)?;
+                self.spec(if_true)?;
+                let true_env = std::mem::replace(&mut self.env, before.clone());
+                let true_dead = std::mem::replace(&mut self.dead, false);
+
+                self.branch = self.emit(LaneOp::And, outer, not_taken)?;
+                self.spec(if_false)?;
+                let false_env = std::mem::take(&mut self.env);
+                let false_dead = self.dead;
+
+                self.branch = outer;
+                self.dead = true_dead && false_dead;
+                self.env = before;
+                let vars: BTreeSet<&Variable> = true_env.keys().chain(false_env.keys()).collect();
+                for var in vars {
+                    let fallback = self.variable(var);
+                    let x = true_env.get(var).cloned().unwrap_or(fallback);
+                    let y = false_env.get(var).cloned().unwrap_or(fallback);
+                    let merged = self.select(taken, x, y)?;
+                    self.env.insert(var.clone(), merged);
+                }
+            },
+            Spec::Return(ref var) => {
+                let value = self.variable(var);
+                let active = self.active()?;
+                self.result = self.select(active, value, self.result)?;
+                self.returned = self.emit(LaneOp::Or, self.returned, active)?;
+                self.dead = true;
+            },
+        }
+        Ok(())
+    }
+}
+
+// Packs each live instruction into the earliest bundle after its sources
+// that still has a free slot. Returns the bundle of every instruction.
+fn schedule(
+    definitions: &[Definition],
+    live: &[bool],
+    issue_width: usize,
+) -> (BTreeMap<usize, usize>, usize) {
+    let mut bundle_of: BTreeMap<usize, usize> = BTreeMap::new();
+    let mut occupancy: Vec<usize> = Vec::new();
+    for (register, definition) in definitions.iter().enumerate() {
+        if !live[register] || matches!(definition, Definition::Input) {
+            continue;
+        }
+        let mut bundle = definition.sources().iter()
+            .filter_map(|source| bundle_of.get(source).map(|b| b + 1))
+            .max()
+            .unwrap_or(0);
+        while matches!(occupancy.get(bundle), Some(used) if *used >= issue_width) {
+            bundle += 1;
+        }
+        if bundle >= occupancy.len() {
+            occupancy.resize(bundle + 1, 0);
+        }
+        occupancy[bundle] += 1;
+        bundle_of.insert(register, bundle);
+    }
+    (bundle_of, occupancy.len())
+}
+
+/// Compiles `spec` for `vliw`, calling only the intrinsics in `intrinsics`.
+pub fn compile(
+    spec: &Spec,
+    vliw: &VLIW,
+    intrinsics: &IntrinsicSet,
+) -> Result<Compiled, CompileError> {
+    let mut lowering = Lowering {
+        vliw,
+        intrinsics,
+        definitions: Vec::new(),
+        shared: HashMap::new(),
+        inputs: BTreeMap::new(),
+        env: BTreeMap::new(),
+        branch: ALL_LANES,
+        returned: NO_LANES,
+        result: NO_LANES,
+        failed: NO_LANES,
+        dead: false,
+        unrolled: 0,
+    };
+    lowering.spec(spec)?;
+    let Lowering { definitions, inputs, result, returned, failed, .. } = lowering;
+    if vliw.issue_width == 0 && definitions.iter().any(|d| matches!(d, Definition::Op(..))) {
+        return Err(CompileError::NoIssueSlots);
+    }
+
+    // Dead code elimination. Every source is defined before its uses.
+    // Divisions are kept even when nothing reads them, since they can fault.
+    let outputs: Vec<usize> = [result, returned, failed].iter()
+        .filter_map(|operand| match operand {
+            Operand::Register(register) => Some(*register),
+            Operand::Immediate(_) => None,
+        })
+        .collect();
+    let mut live: Vec<bool> = definitions.iter()
+        .map(|definition| matches!(definition, Definition::Op(LaneOp::Divide | LaneOp::Remainder, ..)))
+        .collect();
+    for register in &outputs {
+        live[*register] = true;
+    }
+    for register in (0 .. definitions.len()).rev() {
+        if live[register] {
+            for source in definitions[register].sources() {
+                live[source] = true;
+            }
+        }
+    }
+
+    let (bundle_of, number_of_bundles) = schedule(&definitions, &live, vliw.issue_width);
+
+    // Linear scan register allocation. Inputs are written at position 0 and
+    // an instruction in bundle `b` reads and writes at position `b + 1`. A
+    // register can be reused by an instruction in the same bundle as the
+    // last read of its previous value, since reads happen first.
+    let start = |register: usize| bundle_of.get(&register).map_or(0, |b| b + 1);
+    let mut end: BTreeMap<usize, usize> = BTreeMap::new();
+    for (register, b) in &bundle_of {
+        for source in definitions[*register].sources() {
+            let last = end.entry(source).or_insert(0);
+            *last = (*last).max(b + 1);
+        }
+    }
+    for register in &outputs {
+        end.insert(*register, usize::MAX);
+    }
+    let mut order: Vec<usize> = (0 .. definitions.len()).filter(|r| live[*r]).collect();
+    for register in &order {
+        end.entry(*register).or_insert(start(*register) + 1);
+    }
+    order.sort_by_key(|register| start(*register));
+    let mut free: BTreeSet<usize> = (0 .. vliw.number_of_registers).collect();
+    let mut allocated: Vec<(usize, usize)> = Vec::new();
+    let mut physical: BTreeMap<usize, usize> = BTreeMap::new();
+    for register in order {
+        let position = start(register);
+        allocated.retain(|(virtual_register, physical_register)| {
+            if end[virtual_register] <= position {
+                free.insert(*physical_register);
+                false
+            } else {
+                true
+            }
+        });
+        let Some(physical_register) = free.pop_first() else {
+            return Err(CompileError::OutOfRegisters { available: vliw.number_of_registers });
+        };
+        allocated.push((register, physical_register));
+        physical.insert(register, physical_register);
+    }
+
+    let map = |operand: Operand| match operand {
+        Operand::Register(register) => Operand::Register(physical[&register]),
+        immediate => immediate,
+    };
+    let mut bundles = vec![Bundle::default(); number_of_bundles];
+    for (register, b) in &bundle_of {
+        let Definition::Op(op, lhs, rhs) = definitions[*register] else {
+            unreachable!()
+        };
+        bundles[*b].lane_ops.push(LaneInstruction {
+            op,
+            destination: physical[register],
+            lhs: map(lhs),
+            rhs: map(rhs),
+        });
+    }
+    Ok(Compiled {
+        bundles,
+        inputs: inputs.into_iter()
+            .filter(|(_, register)| live[*register])
+            .map(|(var, register)| (var, physical[&register]))
+            .collect(),
+        result: map(result),
+        returned: map(returned),
+        failed: map(failed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::mana::interpret_metered;
+    use crate::magic::parser::parse;
+    use crate::magic::testing::Generator;
+    use rand::{Rng, SeedableRng};
+    use rand::seq::SliceRandom;
+    use rand_chacha::ChaCha8Rng;
+
+    const SPELLS: Generator = Generator {
+        names: &["a", "b", "i", "x", "y"],
+        constants: &[0, 1, 2, 3, 7, 31, 32, Value::MAX],
+        calls: &[("min", 2), ("max", 2)],
+        // Loops are unrolled, so their bounds have to be constant.
+        dynamic_bounds: false,
+    };
+    const LANES: usize = 4;
+
+    // Every lane must return what the interpreter does, with failures
+    // returning nothing. Loops here have constant bounds, so every spell
+    // compiles.
+    fn assert_same(spec: &Spec, contexts: &[Context]) {
+        let vliw = VLIW::with_all_ops(256, LANES, 4).unwrap();
+        let compiled = compile(spec, &vliw, &IntrinsicSet::all())
+            .unwrap_or_else(|error| panic!("{}: {:?}", error, spec));
+        let expected: Vec<Option<Value>> = contexts.iter()
+            .map(|context| {
+                let mut context = context.clone();
+                interpret_metered(spec, &mut context, &ManaCosts::default(), &IntrinsicSet::all(), Mana::MAX)
+                    .result.ok().flatten()
+            })
+            .collect();
+        let (actual, _) = compiled.run(&vliw, contexts)
+            .unwrap_or_else(|error| panic!("{}: {:?}", error, spec));
+        assert_eq!(actual, expected, "{:?} {:?}", spec, contexts);
+    }
+
+    fn contexts(rng: &mut ChaCha8Rng) -> Vec<Context> {
+        (0 .. LANES)
+            .map(|_| SPELLS.names.iter()
+                .map(|name| {
+                    let value = *[0, 1, 5, 31, 32, 40, Value::MAX].choose(rng).unwrap();
+                    (Variable::new(name), value)
+                })
+                .collect())
+            .collect()
+    }
+
+    #[test]
+    fn matches_interpreter_on_examples() {
+        let spells = [
+            "c = 0; for i in 0 ..= 31 { c = c + ((x >> i) & 1); } return c;",
+            "if x < 10 { y = x * 2; } else { y = x / (x - x); } return y;",
+            "i = 5; for i in 1 ..= 3 { y = i; } return i;",
+            "y = 1 << x; return y;",
+            "if x < 32 { y = 1 << x; } else { y = 0; } return y;",
+            "y = x >> a; if y == 0 { return a; } return y;",
+            "y = min(x, 3); return y;",
+            "for i in 0 ..= 3 { for j in 0 ..= i { if j == 2 { return j; } } }",
+            "",
+        ];
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for source in spells {
+            let spec = parse(source).unwrap();
+            for _ in 0 .. 20 {
+                assert_same(&spec, &contexts(&mut rng));
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_on_random_spells() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0 .. 2000 {
+            let spec = SPELLS.spec(&mut rng, 4);
+            let lanes = rng.gen_range(1 ..= LANES);
+            assert_same(&spec, &contexts(&mut rng)[.. lanes]);
+        }
+    }
+
+    #[test]
+    fn shifting_too_far_fails_only_that_lane() {
+        let vliw = VLIW::with_all_ops(16, 2, 2).unwrap();
+        let spec = parse("y = 1 << x; return y;").unwrap();
+        let compiled = compile(&spec, &vliw, &IntrinsicSet::all()).unwrap();
+        let contexts: Vec<Context> = [3, 32].iter()
+            .map(|x| [(Variable::new("x"), *x)].into_iter().collect())
+            .collect();
+        let (results, _) = compiled.run(&vliw, &contexts).unwrap();
+        assert_eq!(results, vec![Some(8), None]);
+    }
+
+    #[test]
+    fn dividing_by_zero_fails_only_that_lane() {
+        let vliw = VLIW::with_all_ops(16, 2, 2).unwrap();
+        let spec = parse("y = 12 / x; return y;").unwrap();
+        let compiled = compile(&spec, &vliw, &IntrinsicSet::all()).unwrap();
+        let contexts: Vec<Context> = [3, 0].iter()
+            .map(|x| [(Variable::new("x"), *x)].into_iter().collect())
+            .collect();
+        let (results, _) = compiled.run(&vliw, &contexts).unwrap();
+        assert_eq!(results, vec![Some(4), None]);
+    }
+
+    #[test]
+    fn faults_in_branches_no_lane_takes_compile() {
+        let vliw = VLIW::with_all_ops(16, 2, 2).unwrap();
+        let spec = parse("y = 1; if x { y = 1 / 0; z = 1 << 40; } return y;").unwrap();
+        let compiled = compile(&spec, &vliw, &IntrinsicSet::all()).unwrap();
+        let contexts: Vec<Context> = [0, 1].iter()
+            .map(|x| [(Variable::new("x"), *x)].into_iter().collect())
+            .collect();
+        let (results, _) = compiled.run(&vliw, &contexts).unwrap();
+        assert_eq!(results, vec![Some(1), None]);
+    }
+
+    #[test]
+    fn rejects_more_contexts_than_lanes() {
+        let vliw = VLIW::with_all_ops(16, 2, 2).unwrap();
+        let compiled = compile(&parse("return x;").unwrap(), &vliw, &IntrinsicSet::all()).unwrap();
+        assert_eq!(
+            compiled.run(&vliw, &vec![Context::new(); 3]),
+            Err(VliwError::TooManyContexts { found: 3, lanes: 2 }));
+    }
+}
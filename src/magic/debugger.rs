@@ -0,0 +1,523 @@
+// Runs a spell one statement at a time. Execution and mana costs are exactly
+// those of `mana::interpret_metered`; the difference is that the interpreter
+// state lives in an explicit stack, so it can stop between any two
+// statements.
+//
+// Statements are identified by their path from the root of the spell: the
+// index of each child taken on the way down, where a `for` body is child 0
+// and the branches of an `if` are children 0 and 1.
+
+use crate::magic::{Context, Expr, Spec, Value, Variable};
+use crate::magic::lookup_variable;
+use crate::magic::intrinsics::IntrinsicSet;
+use crate::magic::mana::{Mana, ManaCosts, Meter, SpellError};
+use crate::magic::parser::{Span, SpanTable};
+use std::collections::{BTreeMap, BTreeSet};
+
+pub type NodePath = Vec<usize>;
+
+enum Frame {
+    Block {
+        path: NodePath,
+        next: usize,
+    },
+    Loop {
+        path: NodePath,
+        variable: Variable,
+        // Wider than `Value` so that a loop up to `Value::MAX` can end.
+        next: u64,
+        upper: Value,
+        shadowed: Option<Value>,
+    },
+}
+
+// The parts of a statement needed to execute it, without its children.
+enum Statement {
+    Assign(Variable, Expr),
+    Block,
+    For(Variable, Expr, Expr),
+    If(Expr),
+    Return(Variable),
+}
+
+fn node<'a>(spec: &'a Spec, path: &[usize]) -> &'a Spec {
+    path.iter().fold(spec, |spec, index| match spec {
+        Spec::Block(ref specs) => &specs[*index],
+        Spec::For(_, _, _, ref body) => body,
+        Spec::If(_, ref if_true, _) if *index == 0 => if_true,
+        Spec::If(_, _, ref if_false) => if_false,
+        _ => panic!("statement has no children"),
+    })
+}
+
+fn expr_size(expr: &Expr) -> usize {
+    1 + match expr {
+        Expr::Var(_) | Expr::Const(_) => 0,
+        Expr::Not(ref x) => expr_size(x),
+        Expr::Call(_, ref args) => args.iter().map(expr_size).sum(),
+        _ => {
+            let (_, x, y) = expr.as_binary().unwrap();
+            expr_size(x) + expr_size(y)
+        },
+    }
+}
+
+// Picks the statement spans out of a `SpanTable`, which also has spans for
+// expressions, all in post-order.
+fn statement_spans(
+    spec: &Spec,
+    table: &SpanTable,
+    path: &mut NodePath,
+    next: &mut usize,
+    spans: &mut BTreeMap<NodePath, Span>,
+) {
+    let mut child = |index: usize, spec: &Spec, next: &mut usize, spans: &mut BTreeMap<NodePath, Span>| {
+        path.push(index);
+        statement_spans(spec, table, path, next, spans);
+        path.pop();
+    };
+    match spec {
+        Spec::Assign(_, ref expr) => *next += expr_size(expr),
+        Spec::Block(ref specs) => {
+            for (index, s) in specs.iter().enumerate() {
+                child(index, s, next, spans);
+            }
+        },
+        Spec::For(_, ref lower, ref upper, ref body) => {
+            *next += expr_size(lower) + expr_size(upper);
+            child(0, body, next, spans);
+        },
+        Spec::If(ref cond, ref if_true, ref if_false) => {
+            *next += expr_size(cond);
+            child(0, if_true, next, spans);
+            child(1, if_false, next, spans);
+        },
+        Spec::Return(_) => {},
+    }
+    if let Some(span) = table.spans.get(*next) {
+        spans.insert(path.clone(), *span);
+    }
+    *next += 1;
+}
+
+pub struct Debugger {
+    spec: Spec,
+    spans: BTreeMap<NodePath, Span>,
+    costs: ManaCosts,
+    intrinsics: IntrinsicSet,
+    budget: Mana,
+    spent: Mana,
+    context: Context,
+    frames: Vec<Frame>,
+    // The statement that will run next.
+    current: Option<NodePath>,
+    result: Option<Result<Option<Value>, SpellError>>,
+    /// Lines, counting from 1, that `resume` and `step_over` stop at.
+    pub breakpoints: BTreeSet<usize>,
+}
+
+impl Debugger {
+    /// Stops before the first statement of `spec`. `spans` should come from
+    /// parsing it, and can be empty if there's no source to show.
+    pub fn new(
+        spec: Spec,
+        spans: &SpanTable,
+        context: Context,
+        costs: ManaCosts,
+        intrinsics: IntrinsicSet,
+        budget: Mana,
+    ) -> Self {
+        let mut statement_span_map = BTreeMap::new();
+        statement_spans(&spec, spans, &mut Vec::new(), &mut 0, &mut statement_span_map);
+        let mut debugger = Debugger {
+            spec,
+            spans: statement_span_map,
+            costs,
+            intrinsics,
+            budget,
+            spent: 0,
+            context,
+            frames: Vec::new(),
+            current: None,
+            result: None,
+            breakpoints: BTreeSet::new(),
+        };
+        if let Err(error) = debugger.enter(Vec::new()) {
+            debugger.finish(Err(error));
+        }
+        debugger
+    }
+
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    pub fn mana_spent(&self) -> Mana {
+        self.spent
+    }
+
+    pub fn budget(&self) -> Mana {
+        self.budget
+    }
+
+    /// `None` until the spell has returned, run off the end or failed.
+    pub fn result(&self) -> Option<&Result<Option<Value>, SpellError>> {
+        self.result.as_ref()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.result.is_some()
+    }
+
+    pub fn current(&self) -> Option<&Spec> {
+        self.current.as_ref().map(|path| node(&self.spec, path))
+    }
+
+    pub fn current_span(&self) -> Option<Span> {
+        self.spans.get(self.current.as_ref()?).cloned()
+    }
+
+    /// How many loops and blocks the current statement is nested in.
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn toggle_breakpoint(&mut self, line: usize) {
+        if !self.breakpoints.remove(&line) {
+            self.breakpoints.insert(line);
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        matches!(self.current_span(), Some(span) if self.breakpoints.contains(&span.start.line))
+    }
+
+    fn finish(&mut self, result: Result<Option<Value>, SpellError>) {
+        self.result = Some(result);
+        self.current = None;
+        self.frames.clear();
+    }
+
+    // Runs `f` against the remaining budget and books what it spent.
+    fn metered<T>(
+        &mut self,
+        f: impl FnOnce(&mut Meter, &mut Context) -> Result<T, SpellError>,
+    ) -> Result<T, SpellError> {
+        let mut meter = Meter::new(&self.costs, &self.intrinsics, self.budget - self.spent);
+        let result = f(&mut meter, &mut self.context);
+        self.spent += meter.spent();
+        result
+    }
+
+    // Makes `path` the current statement, or its first statement if it's a
+    // block.
+    fn enter(&mut self, path: NodePath) -> Result<(), SpellError> {
+        if self.descend(path) {
+            Ok(())
+        } else {
+            self.advance()
+        }
+    }
+
+    // Makes `path` the current statement and returns true, unless it's a
+    // block, which is pushed for `advance` to go into instead.
+    fn descend(&mut self, path: NodePath) -> bool {
+        if let Spec::Block(_) = node(&self.spec, &path) {
+            self.frames.push(Frame::Block { path, next: 0 });
+            false
+        } else {
+            self.current = Some(path);
+            true
+        }
+    }
+
+    // Moves on to whatever runs after the innermost frame's last statement.
+    // Blocks are gone into here rather than through `enter`, so an empty loop
+    // body doesn't recurse once per iteration.
+    fn advance(&mut self) -> Result<(), SpellError> {
+        loop {
+            match self.frames.last_mut() {
+                None => {
+                    self.finish(Ok(None));
+                    return Ok(());
+                },
+                Some(Frame::Block { ref path, ref mut next }) => {
+                    let Spec::Block(ref specs) = node(&self.spec, path) else {
+                        unreachable!()
+                    };
+                    if *next < specs.len() {
+                        let mut child = path.clone();
+                        child.push(*next);
+                        *next += 1;
+                        if self.descend(child) {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                },
+                Some(Frame::Loop { ref path, ref variable, ref mut next, upper, .. }) => {
+                    if *next <= *upper as u64 {
+                        let (path, variable, value) = (path.clone(), variable.clone(), *next as Value);
+                        *next += 1;
+                        let cost = self.costs.loop_iteration;
+                        self.metered(|meter, _| meter.charge(cost))?;
+                        self.context.insert(variable, value);
+                        let mut body = path;
+                        body.push(0);
+                        if self.descend(body) {
+                            return Ok(());
+                        }
+                        continue;
+                    }
+                },
+            }
+            if let Some(Frame::Loop { variable, shadowed, .. }) = self.frames.pop() {
+                match shadowed {
+                    Some(value) => self.context.insert(variable, value),
+                    None => self.context.remove(&variable),
+                };
+            }
+        }
+    }
+
+    fn execute(&mut self, path: NodePath) -> Result<Option<Value>, SpellError> {
+        let statement = match node(&self.spec, &path) {
+            Spec::Assign(ref var, ref expr) => Statement::Assign(var.clone(), expr.clone()),
+            Spec::Block(_) => Statement::Block,
+            Spec::For(ref var, ref lower, ref upper, _) =>
+                Statement::For(var.clone(), lower.clone(), upper.clone()),
+            Spec::If(ref cond, _, _) => Statement::If(cond.clone()),
+            Spec::Return(ref var) => Statement::Return(var.clone()),
+        };
+        match statement {
+            Statement::Assign(var, expr) => {
+                let cost = self.costs.assign;
+                let value = self.metered(|meter, context| {
+                    meter.charge(cost)?;
+                    meter.eval_expr(&expr, context)
+                })?;
+                self.context.insert(var, value);
+                self.advance()?;
+            },
+            Statement::Block => self.enter(path)?,
+            Statement::For(variable, lower, upper) => {
+                let (lower, upper) = self.metered(|meter, context| {
+                    Ok((meter.eval_expr(&lower, context)?, meter.eval_expr(&upper, context)?))
+                })?;
+                let shadowed = self.context.get(&variable).cloned();
+                self.frames.push(Frame::Loop {
+                    path,
+                    variable,
+                    next: lower as u64,
+                    upper,
+                    shadowed,
+                });
+                self.advance()?;
+            },
+            Statement::If(cond) => {
+                let cost = self.costs.branch;
+                let cond = self.metered(|meter, context| {
+                    meter.charge(cost)?;
+                    meter.eval_expr(&cond, context)
+                })?;
+                let mut branch = path;
+                branch.push(if cond == 0 { 1 } else { 0 });
+                self.enter(branch)?;
+            },
+            Statement::Return(var) => return Ok(Some(lookup_variable(&var, &self.context)?)),
+        }
+        Ok(None)
+    }
+
+    /// Runs the current statement. If it's a loop or an `if`, this stops at
+    /// the first statement inside it.
+    pub fn step_into(&mut self) {
+        let Some(path) = self.current.take() else { return; };
+        match self.execute(path) {
+            Ok(Some(value)) => self.finish(Ok(Some(value))),
+            Ok(None) => {},
+            Err(error) => self.finish(Err(error)),
+        }
+    }
+
+    /// Runs the current statement, including everything nested inside it,
+    /// unless a breakpoint is hit first.
+    pub fn step_over(&mut self) {
+        let Some(path) = self.current.clone() else { return; };
+        self.step_into();
+        while let Some(ref current) = self.current {
+            let inside = current.len() > path.len() && current.starts_with(&path);
+            if !inside || self.at_breakpoint() {
+                break;
+            }
+            self.step_into();
+        }
+    }
+
+    /// Runs until the next breakpoint or the end of the spell.
+    pub fn resume(&mut self) {
+        self.step_into();
+        while !self.is_finished() && !self.at_breakpoint() {
+            self.step_into();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::mana::interpret_metered;
+    use crate::magic::parser::{parse, parse_with_spans};
+    use crate::magic::testing::Generator;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    const SPELLS: Generator = Generator {
+        names: &["a", "b", "i", "x", "y"],
+        constants: &[0, 1, 2, 3, 7, 31, 32, Value::MAX],
+        calls: &[("popcount", 1), ("min", 2), ("frobnicate", 1)],
+        dynamic_bounds: true,
+    };
+
+    const NESTED: &str = "\
+for i in 0 ..= 2 {
+    if i == 1 {
+        y = i;
+    }
+    z = i;
+}
+return z;";
+
+    fn debugger(source: &str) -> Debugger {
+        let (spec, spans) = parse_with_spans(source).unwrap();
+        Debugger::new(spec, &spans, Context::new(), ManaCosts::default(), IntrinsicSet::all(), 1000)
+    }
+
+    fn line(debugger: &Debugger) -> Option<usize> {
+        debugger.current_span().map(|span| span.start.line)
+    }
+
+    fn value(debugger: &Debugger, name: &str) -> Option<Value> {
+        debugger.context().get(&Variable::new(name)).cloned()
+    }
+
+    // Stepping into every statement has to end up where the interpreter does.
+    fn assert_same(spec: &Spec, context: &Context, intrinsics: &IntrinsicSet, budget: Mana) {
+        let costs = ManaCosts::default();
+        let mut expected_context = context.clone();
+        let expected = interpret_metered(spec, &mut expected_context, &costs, intrinsics, budget);
+        let mut debugger = Debugger::new(
+            spec.clone(), &SpanTable::default(), context.clone(), costs, intrinsics.clone(), budget);
+        while !debugger.is_finished() {
+            debugger.step_into();
+        }
+        assert_eq!(debugger.result(), Some(&expected.result), "{:?}", spec);
+        assert_eq!(debugger.mana_spent(), expected.mana_spent, "{:?}", spec);
+        assert_eq!(debugger.context(), &expected_context, "{:?}", spec);
+    }
+
+    #[test]
+    fn matches_interpreter_on_examples() {
+        let spells = [
+            "c = 0; for i in 0 ..= 31 { c = c + ((x >> i) & 1); } return c;",
+            "i = 5; for i in 1 ..= 3 { y = i; } return i;",
+            "for i in 0 ..= 2 { for i in 5 ..= 6 { y = i; } z = i; } return z;",
+            "for i in 0 ..= 4294967295 { } return x;",
+            "if x < 10 { y = x * 2; } else { y = x / (x - x); } return y;",
+            "y = popcount(x) + min(x, 3); return y;",
+            "y = frobnicate(x); return y;",
+            "return undefined;",
+            "{ { } } { }",
+            "",
+        ];
+        let context: Context = [(Variable::new("x"), 37)].into_iter().collect();
+        for source in spells {
+            let spec = parse(source).unwrap();
+            for budget in [0, 1, 5, 17, 30, 100, 100_000] {
+                assert_same(&spec, &context, &IntrinsicSet::all(), budget);
+                assert_same(&spec, &context, &IntrinsicSet::for_run(3), budget);
+            }
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_on_random_spells() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0 .. 2000 {
+            let spec = SPELLS.spec(&mut rng, 4);
+            let mut context = Context::new();
+            for name in SPELLS.names {
+                if rng.gen_bool(0.5) {
+                    context.insert(Variable::new(name), rng.gen_range(0 .. 40));
+                }
+            }
+            let intrinsics = IntrinsicSet::for_run(rng.gen());
+            let budget = rng.gen_range(0 .. 400);
+            assert_same(&spec, &context, &intrinsics, budget);
+            assert_same(&spec, &context, &IntrinsicSet::all(), 10_000);
+        }
+    }
+
+    #[test]
+    fn running_out_of_mana_stops_inside_the_loop() {
+        let spec = parse("c = 0; for i in 0 ..= 31 { c = c + i; } return c;").unwrap();
+        let mut debugger = Debugger::new(
+            spec, &SpanTable::default(), Context::new(), ManaCosts::default(), IntrinsicSet::all(), 30);
+        while !debugger.is_finished() {
+            debugger.step_into();
+        }
+        assert_eq!(debugger.result(), Some(&Err(SpellError::OutOfMana)));
+        assert_eq!(debugger.mana_spent(), 30);
+        assert!(value(&debugger, "i").is_some());
+    }
+
+    #[test]
+    fn step_over_runs_whole_statements() {
+        let mut debugger = debugger(NESTED);
+        assert_eq!(line(&debugger), Some(1));
+        debugger.step_over();
+        assert_eq!(line(&debugger), Some(7));
+        assert_eq!(value(&debugger, "y"), Some(1));
+        assert_eq!(value(&debugger, "z"), Some(2));
+        assert_eq!(value(&debugger, "i"), None);
+    }
+
+    #[test]
+    fn step_over_stays_inside_nested_blocks() {
+        let mut debugger = debugger(NESTED);
+        debugger.step_into();
+        assert_eq!(line(&debugger), Some(2));
+        let depth = debugger.depth();
+
+        // The `if` isn't taken the first time round.
+        debugger.step_over();
+        assert_eq!(line(&debugger), Some(5));
+        debugger.step_over();
+        assert_eq!((line(&debugger), value(&debugger, "i")), (Some(2), Some(1)));
+        assert_eq!(debugger.depth(), depth);
+
+        // The second time, it runs its body and stops after it.
+        debugger.step_over();
+        assert_eq!(line(&debugger), Some(5));
+        assert_eq!(value(&debugger, "y"), Some(1));
+        assert_eq!(debugger.depth(), depth);
+    }
+
+    #[test]
+    fn resume_stops_at_breakpoints() {
+        let mut debugger = debugger(NESTED);
+        debugger.toggle_breakpoint(3);
+        debugger.resume();
+        assert_eq!((line(&debugger), value(&debugger, "i")), (Some(3), Some(1)));
+        debugger.resume();
+        assert_eq!(debugger.result(), Some(&Ok(Some(2))));
+
+        let mut debugger = self::debugger(NESTED);
+        debugger.toggle_breakpoint(5);
+        debugger.step_over();
+        assert_eq!((line(&debugger), value(&debugger, "i")), (Some(5), Some(0)));
+        debugger.toggle_breakpoint(5);
+        debugger.resume();
+        assert!(debugger.is_finished());
+    }
+}
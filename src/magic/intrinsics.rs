@@ -0,0 +1,180 @@
+use bevy::prelude::Resource;
+use crate::magic::Value;
+use crate::magic::mana::Mana;
+use rand::SeedableRng;
+use rand::seq::SliceRandom;
+use std::collections::BTreeMap;
+
+/// How many intrinsics a run grants.
+pub const INTRINSICS_PER_RUN: usize = 4;
+
+pub struct Intrinsic {
+    pub name: &'static str,
+    pub arity: usize,
+    /// Mana charged per call, on top of evaluating the arguments.
+    pub cost: Mana,
+    pub function: fn(&[Value]) -> Value,
+}
+
+impl std::fmt::Debug for Intrinsic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}/{}", self.name, self.arity)
+    }
+}
+
+/// Every intrinsic that can ever be granted. Arguments are always checked
+/// against `arity` before `function` is called.
+pub const INTRINSICS: &[Intrinsic] = &[
+    Intrinsic {
+        name: "popcount",
+        arity: 1,
+        cost: 2,
+        function: |args| args[0].count_ones(),
+    },
+    Intrinsic {
+        name: "parity",
+        arity: 1,
+        cost: 2,
+        function: |args| args[0].count_ones() & 1,
+    },
+    Intrinsic {
+        name: "reverse_bits",
+        arity: 1,
+        cost: 2,
+        function: |args| args[0].reverse_bits(),
+    },
+    Intrinsic {
+        name: "swap_bytes",
+        arity: 1,
+        cost: 1,
+        function: |args| args[0].swap_bytes(),
+    },
+    Intrinsic {
+        name: "leading_zeros",
+        arity: 1,
+        cost: 2,
+        function: |args| args[0].leading_zeros(),
+    },
+    Intrinsic {
+        name: "trailing_zeros",
+        arity: 1,
+        cost: 2,
+        function: |args| args[0].trailing_zeros(),
+    },
+    Intrinsic {
+        name: "rotate_left",
+        arity: 2,
+        cost: 1,
+        function: |args| args[0].rotate_left(args[1] % Value::BITS),
+    },
+    Intrinsic {
+        name: "rotate_right",
+        arity: 2,
+        cost: 1,
+        function: |args| args[0].rotate_right(args[1] % Value::BITS),
+    },
+    Intrinsic {
+        name: "min",
+        arity: 2,
+        cost: 1,
+        function: |args| std::cmp::min(args[0], args[1]),
+    },
+    Intrinsic {
+        name: "max",
+        arity: 2,
+        cost: 1,
+        function: |args| std::cmp::max(args[0], args[1]),
+    },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Intrinsic> {
+    INTRINSICS.iter().find(|intrinsic| intrinsic.name == name)
+}
+
+/// The intrinsics granted to the player for the current run.
+#[derive(Clone, Debug, Default, Resource)]
+pub struct IntrinsicSet {
+    granted: BTreeMap<&'static str, &'static Intrinsic>,
+}
+
+impl IntrinsicSet {
+    /// Picks `INTRINSICS_PER_RUN` intrinsics. The same level seed always
+    /// yields the same set.
+    pub fn for_run(seed: u64) -> Self {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+        let granted = INTRINSICS
+            .choose_multiple(&mut rng, INTRINSICS_PER_RUN)
+            .map(|intrinsic| (intrinsic.name, intrinsic))
+            .collect();
+        IntrinsicSet { granted }
+    }
+
+    /// Grants every intrinsic; useful for testing spells.
+    pub fn all() -> Self {
+        IntrinsicSet {
+            granted: INTRINSICS.iter()
+                .map(|intrinsic| (intrinsic.name, intrinsic))
+                .collect(),
+        }
+    }
+
+    /// Grants the intrinsics called `names`, skipping any that don't exist.
+    pub fn from_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Self {
+        IntrinsicSet {
+            granted: names.into_iter()
+                .filter_map(lookup)
+                .map(|intrinsic| (intrinsic.name, intrinsic))
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&'static Intrinsic> {
+        self.granted.get(name).cloned()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &'static Intrinsic> + '_ {
+        self.granted.values().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(intrinsics: &IntrinsicSet) -> Vec<&'static str> {
+        intrinsics.iter().map(|intrinsic| intrinsic.name).collect()
+    }
+
+    fn call(name: &str, args: &[Value]) -> Value {
+        (lookup(name).unwrap().function)(args)
+    }
+
+    #[test]
+    fn runs_grant_the_same_distinct_intrinsics_for_a_seed() {
+        let sets: Vec<Vec<&str>> = (0 .. 20).map(|seed| names(&IntrinsicSet::for_run(seed))).collect();
+        for (seed, set) in sets.iter().enumerate() {
+            assert_eq!(set, &names(&IntrinsicSet::for_run(seed as u64)));
+            // Granting one twice would leave fewer names.
+            assert_eq!(set.len(), INTRINSICS_PER_RUN);
+        }
+        assert!(sets.iter().any(|set| *set != sets[0]));
+    }
+
+    #[test]
+    fn from_names_skips_unknown_names() {
+        let intrinsics = IntrinsicSet::from_names(["min", "frobnicate", "popcount", "min", ""]);
+        assert_eq!(names(&intrinsics), vec!["min", "popcount"]);
+        assert!(intrinsics.get("frobnicate").is_none());
+        assert_eq!(names(&IntrinsicSet::from_names(names(&IntrinsicSet::all()))), names(&IntrinsicSet::all()));
+    }
+
+    #[test]
+    fn rotations_wrap_the_amount() {
+        let x: Value = 0x8000_0001;
+        assert_eq!(call("rotate_left", &[x, 1]), 0x0000_0003);
+        assert_eq!(call("rotate_left", &[x, 33]), call("rotate_left", &[x, 1]));
+        assert_eq!(call("rotate_right", &[x, 32]), x);
+        assert_eq!(call("rotate_right", &[x, Value::MAX]), x.rotate_right(31));
+        assert_eq!(call("rotate_left", &[x, Value::MAX]), x.rotate_left(31));
+    }
+}
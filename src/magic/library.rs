@@ -0,0 +1,259 @@
+use bevy::prelude::Resource;
+use crate::checksum::fnv1a64;
+use crate::magic::Spec;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+pub const LIBRARY_PATH: &str = "saves/spells.bin";
+
+const LIBRARY_VERSION: u32 = 2;
+
+/// Identifies a spell by its contents. Spells that parse to the same tree
+/// hash the same, regardless of how their source was formatted or
+/// commented, and the hash doesn't change between runs or machines.
+/// Different spells are unlikely to collide, but it isn't impossible.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct SpellHash(pub u64);
+
+impl fmt::Display for SpellHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+impl Spec {
+    pub fn content_hash(&self) -> SpellHash {
+        // Printing would be shorter, but different trees can print the same.
+        let bytes = bincode::serialize(self).expect("specs always serialize");
+        SpellHash(fnv1a64(&bytes))
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("couldn't access the spell library: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("the spell library is corrupt: {0}")]
+    Encoding(#[from] bincode::Error),
+    #[error("the spell library has version {0}, expected {}", LIBRARY_VERSION)]
+    WrongVersion(u32),
+    #[error("a different spell already has the hash {0}")]
+    Collision(SpellHash),
+}
+
+// What actually goes on disk. Hashes aren't stored at all, so that they're
+// always recomputed with the current hash function, and names refer to
+// spells by their position in `spells` instead.
+#[derive(Serialize, Deserialize)]
+struct LibraryFile {
+    version: u32,
+    spells: Vec<Spec>,
+    names: BTreeMap<String, usize>,
+}
+
+/// Named spells, saved to a file. Each distinct spell is only stored once,
+/// however many names refer to it.
+#[derive(Clone, Debug, Resource)]
+pub struct SpellLibrary {
+    path: PathBuf,
+    spells: BTreeMap<SpellHash, Spec>,
+    names: BTreeMap<String, SpellHash>,
+}
+
+impl SpellLibrary {
+    /// An empty library that will be saved to `path`.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        SpellLibrary {
+            path: path.into(),
+            spells: BTreeMap::new(),
+            names: BTreeMap::new(),
+        }
+    }
+
+    /// Loads the library at `path`, or starts an empty one if there isn't a
+    /// file there yet.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self, LibraryError> {
+        let mut library = SpellLibrary::new(path);
+        let bytes = match std::fs::read(&library.path) {
+            Ok(bytes) => bytes,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound =>
+                return Ok(library),
+            Err(error) => return Err(error.into()),
+        };
+        let file: LibraryFile = bincode::deserialize(&bytes)?;
+        if file.version != LIBRARY_VERSION {
+            return Err(LibraryError::WrongVersion(file.version));
+        }
+        let hashes = file.spells.into_iter()
+            .map(|spec| library.add(spec))
+            .collect::<Result<Vec<SpellHash>, _>>()?;
+        library.names = file.names.into_iter()
+            .filter_map(|(name, index)| Some((name, *hashes.get(index)?)))
+            .collect();
+        Ok(library)
+    }
+
+    /// Writes the library out. The old file is only replaced once the new
+    /// one has been written in full.
+    pub fn save(&self) -> Result<(), LibraryError> {
+        let index: BTreeMap<SpellHash, usize> = self.spells.keys()
+            .enumerate()
+            .map(|(index, hash)| (*hash, index))
+            .collect();
+        let file = LibraryFile {
+            version: LIBRARY_VERSION,
+            spells: self.spells.values().cloned().collect(),
+            names: self.names.iter()
+                .map(|(name, hash)| (name.clone(), index[hash]))
+                .collect(),
+        };
+        let bytes = bincode::serialize(&file)?;
+        if let Some(directory) = self.path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        let temporary = self.path.with_extension("tmp");
+        std::fs::write(&temporary, bytes)?;
+        std::fs::rename(&temporary, &self.path)?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Stores `spec` without naming it. Fails, leaving the library as it
+    /// was, if a different spell already has the same hash.
+    pub fn add(&mut self, spec: Spec) -> Result<SpellHash, LibraryError> {
+        let hash = spec.content_hash();
+        match self.spells.entry(hash) {
+            Entry::Occupied(entry) if *entry.get() != spec =>
+                return Err(LibraryError::Collision(hash)),
+            Entry::Occupied(_) => {},
+            Entry::Vacant(entry) => {
+                entry.insert(spec);
+            },
+        }
+        Ok(hash)
+    }
+
+    /// Stores `spec` under `name`, replacing whatever had that name before.
+    pub fn insert(&mut self, name: &str, spec: Spec) -> Result<SpellHash, LibraryError> {
+        let hash = self.add(spec)?;
+        self.names.insert(name.to_string(), hash);
+        Ok(hash)
+    }
+
+    /// Forgets a name. The spell itself stays in the library.
+    pub fn remove_name(&mut self, name: &str) -> Option<SpellHash> {
+        self.names.remove(name)
+    }
+
+    pub fn get(&self, hash: SpellHash) -> Option<&Spec> {
+        self.spells.get(&hash)
+    }
+
+    pub fn hash_of(&self, name: &str) -> Option<SpellHash> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn by_name(&self, name: &str) -> Option<&Spec> {
+        self.get(self.hash_of(name)?)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = (&str, SpellHash)> + '_ {
+        self.names.iter().map(|(name, hash)| (name.as_str(), *hash))
+    }
+
+    pub fn spells(&self) -> impl Iterator<Item = (SpellHash, &Spec)> + '_ {
+        self.spells.iter().map(|(hash, spec)| (*hash, spec))
+    }
+
+    pub fn len(&self) -> usize {
+        self.spells.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.spells.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::parser::parse;
+    use crate::magic::printer::print_program;
+    use crate::magic::{Expr, Variable};
+
+    #[test]
+    fn names_and_hashes_find_spells() {
+        let mut library = SpellLibrary::new("unused.bin");
+        let fireball = parse("x = 1;\nreturn x;").unwrap();
+        let hash = library.insert("fireball", fireball.clone()).unwrap();
+        assert_eq!(hash, parse("x   = 1; // again\nreturn x;").unwrap().content_hash());
+        assert_eq!(library.insert("fireball again", fireball.clone()).unwrap(), hash);
+        assert_eq!(library.add(fireball.clone()).unwrap(), hash);
+        assert_eq!(library.len(), 1);
+        assert_eq!(library.get(hash), Some(&fireball));
+        assert_eq!(library.by_name("fireball again"), Some(&fireball));
+        assert_eq!(library.remove_name("fireball"), Some(hash));
+        assert_eq!(library.by_name("fireball"), None);
+        assert_eq!(library.get(hash), Some(&fireball));
+    }
+
+    #[test]
+    fn different_spells_that_print_the_same_are_kept_apart() {
+        let x = Variable::new("x");
+        let bare = Spec::For(x.clone(), Expr::Const(0), Expr::Const(1), Box::new(Spec::Return(x.clone())));
+        let block = Spec::For(
+            x.clone(), Expr::Const(0), Expr::Const(1),
+            Box::new(Spec::Block(vec![Spec::Return(x)])));
+        assert_eq!(print_program(&bare), print_program(&block));
+        assert_ne!(bare.content_hash(), block.content_hash());
+
+        let mut library = SpellLibrary::new("unused.bin");
+        library.insert("bare", bare.clone()).unwrap();
+        library.insert("block", block.clone()).unwrap();
+        assert_eq!(library.len(), 2);
+        assert_eq!(library.by_name("bare"), Some(&bare));
+        assert_eq!(library.by_name("block"), Some(&block));
+    }
+
+    #[test]
+    fn collisions_are_reported() {
+        let mut library = SpellLibrary::new("unused.bin");
+        let fireball = parse("return x;").unwrap();
+        let antidote = parse("return k;").unwrap();
+        // A real collision would take a long search, so fake one.
+        library.spells.insert(antidote.content_hash(), fireball.clone());
+        library.insert("fireball", fireball.clone()).unwrap();
+        assert!(matches!(library.insert("antidote", antidote.clone()),
+                         Err(LibraryError::Collision(hash)) if hash == antidote.content_hash()));
+        assert_eq!(library.hash_of("antidote"), None);
+        assert_eq!(library.get(antidote.content_hash()), Some(&fireball));
+    }
+
+    #[test]
+    fn saved_libraries_load_back() {
+        let root = std::env::temp_dir().join(format!("deeper-library-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        let path = root.join("spells.bin");
+        assert!(SpellLibrary::load(&path).unwrap().is_empty());
+
+        let mut library = SpellLibrary::new(&path);
+        let fireball = library.insert("fireball", parse("return x;").unwrap()).unwrap();
+        library.insert("antidote", parse("y = x + 1;\nreturn y;").unwrap()).unwrap();
+        library.add(parse("return k;").unwrap()).unwrap();
+        library.save().unwrap();
+
+        let loaded = SpellLibrary::load(&path).unwrap();
+        assert_eq!(loaded.len(), 3);
+        assert_eq!(loaded.names().collect::<Vec<_>>(), library.names().collect::<Vec<_>>());
+        assert_eq!(loaded.hash_of("fireball"), Some(fireball));
+        assert_eq!(loaded.by_name("antidote"), library.by_name("antidote"));
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,321 @@
+use crate::magic::{Context, EvalError, Expr, Spec, Value};
+use crate::magic::{check_arity, lookup_variable};
+use crate::magic::intrinsics::{self, IntrinsicSet};
+use crate::magic::world::{self, NOWHERE, QueryCosts, WorldQuery, WorldView};
+use thiserror::Error;
+
+pub type Mana = u64;
+
+/// How much mana each kind of operation costs in the metered interpreter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManaCosts {
+    /// Charged for every node of every expression that gets evaluated.
+    pub expr_node: Mana,
+    pub assign: Mana,
+    pub loop_iteration: Mana,
+    pub branch: Mana,
+    /// Charged per bundle when a spell is compiled for a VLIW instead.
+    pub bundle: Mana,
+    pub queries: QueryCosts,
+}
+
+impl Default for ManaCosts {
+    fn default() -> Self {
+        ManaCosts {
+            expr_node: 1,
+            assign: 1,
+            loop_iteration: 2,
+            branch: 2,
+            bundle: 4,
+            queries: QueryCosts::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum SpellError {
+    #[error("out of mana")]
+    OutOfMana,
+    #[error("`{0}` was not granted this run")]
+    IntrinsicNotGranted(String),
+    #[error(transparent)]
+    Eval(#[from] EvalError),
+}
+
+/// The outcome of running a spell under a mana budget.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Execution {
+    /// `Ok(Some(value))` if the spell hit a `return`, `Ok(None)` if it ran
+    /// off the end.
+    pub result: Result<Option<Value>, SpellError>,
+    pub mana_spent: Mana,
+}
+
+enum Halt {
+    Return(Value),
+    Error(SpellError),
+}
+
+impl From<SpellError> for Halt {
+    fn from(error: SpellError) -> Halt {
+        Halt::Error(error)
+    }
+}
+
+impl From<EvalError> for Halt {
+    fn from(error: EvalError) -> Halt {
+        Halt::Error(SpellError::Eval(error))
+    }
+}
+
+pub struct Meter<'a> {
+    costs: &'a ManaCosts,
+    intrinsics: &'a IntrinsicSet,
+    world: &'a WorldView,
+    budget: Mana,
+    spent: Mana,
+}
+
+pub fn check_query_arity(query: WorldQuery, args: &[Expr]) -> Result<(), EvalError> {
+    if query.arity() != args.len() {
+        return Err(EvalError::WrongNumberOfArguments {
+            name: query.name().to_string(),
+            expected: query.arity(),
+            found: args.len(),
+        });
+    }
+    Ok(())
+}
+
+impl<'a> Meter<'a> {
+    pub fn new(
+        costs: &'a ManaCosts,
+        intrinsics: &'a IntrinsicSet,
+        budget: Mana,
+    ) -> Self {
+        Meter { costs, intrinsics, world: &NOWHERE, budget, spent: 0 }
+    }
+
+    /// What world queries are answered from, instead of `NOWHERE`.
+    pub fn in_world(mut self, world: &'a WorldView) -> Self {
+        self.world = world;
+        self
+    }
+
+    pub fn spent(&self) -> Mana {
+        self.spent
+    }
+
+    pub fn remaining(&self) -> Mana {
+        self.budget - self.spent
+    }
+
+    /// Deducts `amount` from the budget. If the budget can't cover it, the
+    /// rest of the budget is burned and the spell aborts.
+    pub fn charge(&mut self, amount: Mana) -> Result<(), SpellError> {
+        if amount > self.remaining() {
+            self.spent = self.budget;
+            return Err(SpellError::OutOfMana);
+        }
+        self.spent += amount;
+        Ok(())
+    }
+
+    pub fn eval_expr(&mut self, expr: &Expr, context: &Context) -> Result<Value, SpellError> {
+        self.charge(self.costs.expr_node)?;
+        match expr {
+            Expr::Var(ref var) => Ok(lookup_variable(var, context)?),
+            Expr::Const(value) => Ok(*value),
+            Expr::Not(ref x) => Ok(!self.eval_expr(x, context)?),
+            Expr::Call(ref name, ref args) => {
+                if let Some(query) = world::lookup(name) {
+                    return self.query(query, args, context);
+                }
+                let Some(intrinsic) = self.intrinsics.get(name) else {
+                    return Err(if intrinsics::lookup(name).is_some() {
+                        SpellError::IntrinsicNotGranted(name.clone())
+                    } else {
+                        EvalError::UnknownIntrinsic(name.clone()).into()
+                    });
+                };
+                check_arity(intrinsic, args)?;
+                self.charge(intrinsic.cost)?;
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(self.eval_expr(arg, context)?);
+                }
+                Ok((intrinsic.function)(&values))
+            },
+            _ => {
+                let (op, x, y) = expr.as_binary().unwrap();
+                let x = self.eval_expr(x, context)?;
+                let y = self.eval_expr(y, context)?;
+                Ok(op.apply(x, y)?)
+            },
+        }
+    }
+
+    fn query(
+        &mut self,
+        query: WorldQuery,
+        args: &[Expr],
+        context: &Context,
+    ) -> Result<Value, SpellError> {
+        check_query_arity(query, args)?;
+        self.charge(self.costs.queries.get(query))?;
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval_expr(arg, context)?);
+        }
+        Ok(self.world.answer(query, &values))
+    }
+
+    fn exec(&mut self, spec: &Spec, context: &mut Context) -> Result<(), Halt> {
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                self.charge(self.costs.assign)?;
+                let value = self.eval_expr(expr, context)?;
+                context.insert(var.clone(), value);
+            },
+            Spec::Block(specs) => {
+                for s in specs {
+                    self.exec(s, context)?;
+                }
+            },
+            Spec::For(ref variable, ref lower_expr, ref upper_expr, ref s) => {
+                let lower = self.eval_expr(lower_expr, context)?;
+                let upper = self.eval_expr(upper_expr, context)?;
+                let shadowed: Option<Value> = context.get(variable).cloned();
+                for i in lower ..= upper {
+                    self.charge(self.costs.loop_iteration)?;
+                    context.insert(variable.clone(), i);
+                    self.exec(s, context)?;
+                }
+                if let Some(x) = shadowed {
+                    context.insert(variable.clone(), x);
+                } else {
+                    context.remove(variable);
+                }
+            },
+            Spec::If(ref cond_expr, ref if_true, ref if_false) => {
+                self.charge(self.costs.branch)?;
+                let cond = self.eval_expr(cond_expr, context)?;
+                if cond == 0 {
+                    self.exec(if_false, context)?;
+                } else {
+                    self.exec(if_true, context)?;
+                }
+            },
+            Spec::Return(ref variable) => {
+                return Err(Halt::Return(lookup_variable(variable, context)?));
+            },
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self, spec: &Spec, context: &mut Context) -> Result<Option<Value>, SpellError> {
+        match self.exec(spec, context) {
+            Ok(()) => Ok(None),
+            Err(Halt::Return(value)) => Ok(Some(value)),
+            Err(Halt::Error(error)) => Err(error),
+        }
+    }
+}
+
+/// Like `interpret_spec`, but every operation is paid for out of `budget`
+/// according to `costs`, and only the intrinsics in `intrinsics` may be
+/// called.
+pub fn interpret_metered(
+    spec: &Spec,
+    context: &mut Context,
+    costs: &ManaCosts,
+    intrinsics: &IntrinsicSet,
+    budget: Mana,
+) -> Execution {
+    let mut meter = Meter::new(costs, intrinsics, budget);
+    let result = meter.run(spec, context);
+    Execution { result, mana_spent: meter.spent() }
+}
+
+/// Like `interpret_metered`, but world queries are answered from `world`.
+pub fn interpret_in_world(
+    spec: &Spec,
+    context: &mut Context,
+    costs: &ManaCosts,
+    intrinsics: &IntrinsicSet,
+    world: &WorldView,
+    budget: Mana,
+) -> Execution {
+    let mut meter = Meter::new(costs, intrinsics, budget).in_world(world);
+    let result = meter.run(spec, context);
+    Execution { result, mana_spent: meter.spent() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::Variable;
+    use crate::magic::parser::parse;
+
+    fn run_with(source: &str, intrinsics: &IntrinsicSet, budget: Mana) -> (Execution, Context) {
+        let mut context: Context = [(Variable::new("x"), 37)].into_iter().collect();
+        let spec = parse(source).unwrap();
+        let execution = interpret_metered(&spec, &mut context, &ManaCosts::default(), intrinsics, budget);
+        (execution, context)
+    }
+
+    fn run(source: &str, budget: Mana) -> Execution {
+        run_with(source, &IntrinsicSet::all(), budget).0
+    }
+
+    #[test]
+    fn charges_for_each_operation() {
+        let cost = |source| run(source, 1000).mana_spent;
+        assert_eq!(cost(""), 0);
+        assert_eq!(cost("return x;"), 0);
+        // 1 for the assignment and 1 for each expression node.
+        assert_eq!(cost("y = x + 1;"), 4);
+        assert_eq!(cost("y = ~x;"), 3);
+        // 2 for each iteration, after evaluating the bounds.
+        assert_eq!(cost("for i in 0 ..= 2 { }"), 2 + 3 * 2);
+        assert_eq!(cost("for i in 1 ..= 0 { y = 1; }"), 2);
+        // 2 for each branch, and only the branch taken is paid for.
+        assert_eq!(cost("if x { y = 1; } else { y = 1 + 1; }"), 3 + 2);
+        assert_eq!(cost("if x - x { y = 1; } else { y = 1 + 1; }"), 5 + 4);
+        // An intrinsic's own cost is on top of its node and arguments.
+        assert_eq!(cost("y = popcount(x);"), 2 + 2 + 1);
+        assert_eq!(cost("y = min(x, 3);"), 2 + 1 + 2);
+    }
+
+    #[test]
+    fn runs_out_of_mana_exactly_at_the_budget() {
+        // 2 to set c, 2 for the bounds, then 6 for each of 4 iterations.
+        let source = "c = 0; for i in 0 ..= 3 { c = c + i; } return c;";
+        assert_eq!(run(source, 28), Execution { result: Ok(Some(6)), mana_spent: 28 });
+        let (execution, context) = run_with(source, &IntrinsicSet::all(), 27);
+        assert_eq!(execution, Execution { result: Err(SpellError::OutOfMana), mana_spent: 27 });
+        assert_eq!(context.get(&Variable::new("c")), Some(&3));
+        assert_eq!(context.get(&Variable::new("i")), Some(&3));
+    }
+
+    #[test]
+    fn running_out_burns_the_rest_of_the_budget() {
+        // popcount costs 2, but only 1 is left after its node.
+        assert_eq!(run("y = popcount(x);", 3),
+                   Execution { result: Err(SpellError::OutOfMana), mana_spent: 3 });
+    }
+
+    #[test]
+    fn reports_mana_spent_on_failure() {
+        assert_eq!(run("y = 1; z = x / (y - 1);", 1000),
+                   Execution { result: Err(EvalError::DivisionByZero.into()), mana_spent: 2 + 6 });
+        assert_eq!(run("y = x + nope;", 1000), Execution {
+            result: Err(EvalError::UndefinedVariable(Variable::new("nope")).into()),
+            mana_spent: 4,
+        });
+        assert_eq!(run_with("y = popcount(x);", &IntrinsicSet::default(), 1000).0, Execution {
+            result: Err(SpellError::IntrinsicNotGranted("popcount".to_string())),
+            mana_spent: 2,
+        });
+    }
+}
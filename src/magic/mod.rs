@@ -0,0 +1,418 @@
+use std::collections::BTreeSet;
+use std::collections::BTreeMap;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+pub mod checker;
+pub mod bytecode;
+pub mod compiler;
+pub mod debugger;
+pub mod intrinsics;
+pub mod library;
+pub mod mana;
+pub mod optimizer;
+pub mod parser;
+pub mod printer;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod vliw;
+pub mod world;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum StatusEffect {
+    Fire,
+    Poison,
+    Stinging,
+    Regeneration,
+    Vitality,
+    Frailty,
+    Hardening,
+    Softening,
+    Levitation,
+    Antigravity,
+    WallWalking,
+}
+
+impl StatusEffect {
+    /// In the order spells number them.
+    pub const ALL: &'static [StatusEffect] = &[
+        StatusEffect::Fire,
+        StatusEffect::Poison,
+        StatusEffect::Stinging,
+        StatusEffect::Regeneration,
+        StatusEffect::Vitality,
+        StatusEffect::Frailty,
+        StatusEffect::Hardening,
+        StatusEffect::Softening,
+        StatusEffect::Levitation,
+        StatusEffect::Antigravity,
+        StatusEffect::WallWalking,
+    ];
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Variable(String);
+
+impl Variable {
+    pub fn new(name: &str) -> Self {
+        Variable(name.to_string())
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Expr {
+    Var(Variable),
+    Const(Value),
+    Or(Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Xor(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    ShiftLeft(Box<Expr>, Box<Expr>),
+    ShiftRight(Box<Expr>, Box<Expr>),
+    Add(Box<Expr>, Box<Expr>),
+    Sub(Box<Expr>, Box<Expr>),
+    Mul(Box<Expr>, Box<Expr>),
+    Div(Box<Expr>, Box<Expr>),
+    Mod(Box<Expr>, Box<Expr>),
+    Eq(Box<Expr>, Box<Expr>),
+    Ne(Box<Expr>, Box<Expr>),
+    Lt(Box<Expr>, Box<Expr>),
+    Le(Box<Expr>, Box<Expr>),
+    Gt(Box<Expr>, Box<Expr>),
+    Ge(Box<Expr>, Box<Expr>),
+    /// A call to one of the intrinsics in `intrinsics::INTRINSICS`.
+    Call(String, Vec<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Xor,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Or => "|",
+            BinaryOp::And => "&",
+            BinaryOp::Xor => "^",
+            BinaryOp::ShiftLeft => "<<",
+            BinaryOp::ShiftRight => ">>",
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Mod => "%",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+        }
+    }
+
+    pub fn is_comparison(self) -> bool {
+        matches!(self,
+                 BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt |
+                 BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge)
+    }
+
+    /// Arithmetic wraps around on overflow, comparisons yield 1 or 0, and
+    /// shifting by `Value::BITS` or more is an error rather than a panic.
+    pub fn apply(self, x: Value, y: Value) -> Result<Value, EvalError> {
+        Ok(match self {
+            BinaryOp::Or => x | y,
+            BinaryOp::And => x & y,
+            BinaryOp::Xor => x ^ y,
+            BinaryOp::ShiftLeft =>
+                x.checked_shl(y).ok_or(EvalError::OverShift(y))?,
+            BinaryOp::ShiftRight =>
+                x.checked_shr(y).ok_or(EvalError::OverShift(y))?,
+            BinaryOp::Add => x.wrapping_add(y),
+            BinaryOp::Sub => x.wrapping_sub(y),
+            BinaryOp::Mul => x.wrapping_mul(y),
+            BinaryOp::Div => x.checked_div(y).ok_or(EvalError::DivisionByZero)?,
+            BinaryOp::Mod => x.checked_rem(y).ok_or(EvalError::DivisionByZero)?,
+            BinaryOp::Eq => (x == y) as Value,
+            BinaryOp::Ne => (x != y) as Value,
+            BinaryOp::Lt => (x < y) as Value,
+            BinaryOp::Le => (x <= y) as Value,
+            BinaryOp::Gt => (x > y) as Value,
+            BinaryOp::Ge => (x >= y) as Value,
+        })
+    }
+}
+
+impl Expr {
+    pub fn binary(op: BinaryOp, x: Expr, y: Expr) -> Expr {
+        let (x, y) = (Box::new(x), Box::new(y));
+        match op {
+            BinaryOp::Or => Expr::Or(x, y),
+            BinaryOp::And => Expr::And(x, y),
+            BinaryOp::Xor => Expr::Xor(x, y),
+            BinaryOp::ShiftLeft => Expr::ShiftLeft(x, y),
+            BinaryOp::ShiftRight => Expr::ShiftRight(x, y),
+            BinaryOp::Add => Expr::Add(x, y),
+            BinaryOp::Sub => Expr::Sub(x, y),
+            BinaryOp::Mul => Expr::Mul(x, y),
+            BinaryOp::Div => Expr::Div(x, y),
+            BinaryOp::Mod => Expr::Mod(x, y),
+            BinaryOp::Eq => Expr::Eq(x, y),
+            BinaryOp::Ne => Expr::Ne(x, y),
+            BinaryOp::Lt => Expr::Lt(x, y),
+            BinaryOp::Le => Expr::Le(x, y),
+            BinaryOp::Gt => Expr::Gt(x, y),
+            BinaryOp::Ge => Expr::Ge(x, y),
+        }
+    }
+
+    /// Splits a binary operator node into its operator and operands, so that
+    /// passes over `Expr` don't have to list every operator.
+    pub fn as_binary(&self) -> Option<(BinaryOp, &Expr, &Expr)> {
+        let (op, x, y) = match self {
+            Expr::Or(ref x, ref y) => (BinaryOp::Or, x, y),
+            Expr::And(ref x, ref y) => (BinaryOp::And, x, y),
+            Expr::Xor(ref x, ref y) => (BinaryOp::Xor, x, y),
+            Expr::ShiftLeft(ref x, ref y) => (BinaryOp::ShiftLeft, x, y),
+            Expr::ShiftRight(ref x, ref y) => (BinaryOp::ShiftRight, x, y),
+            Expr::Add(ref x, ref y) => (BinaryOp::Add, x, y),
+            Expr::Sub(ref x, ref y) => (BinaryOp::Sub, x, y),
+            Expr::Mul(ref x, ref y) => (BinaryOp::Mul, x, y),
+            Expr::Div(ref x, ref y) => (BinaryOp::Div, x, y),
+            Expr::Mod(ref x, ref y) => (BinaryOp::Mod, x, y),
+            Expr::Eq(ref x, ref y) => (BinaryOp::Eq, x, y),
+            Expr::Ne(ref x, ref y) => (BinaryOp::Ne, x, y),
+            Expr::Lt(ref x, ref y) => (BinaryOp::Lt, x, y),
+            Expr::Le(ref x, ref y) => (BinaryOp::Le, x, y),
+            Expr::Gt(ref x, ref y) => (BinaryOp::Gt, x, y),
+            Expr::Ge(ref x, ref y) => (BinaryOp::Ge, x, y),
+            Expr::Var(_) | Expr::Const(_) | Expr::Not(_) | Expr::Call(_, _) =>
+                return None,
+        };
+        Some((op, &**x, &**y))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Spec {
+    Assign(Variable, Expr),
+    Block(Vec<Spec>),
+    For(Variable, Expr, Expr, Box<Spec>),
+    If(Expr, Box<Spec>, Box<Spec>),
+    Return(Variable),
+}
+
+pub type Value = u32;
+
+pub type Context = BTreeMap<Variable, Value>;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum EvalError {
+    #[error("undefined variable `{}`", .0.name())]
+    UndefinedVariable(Variable),
+    #[error("division by zero")]
+    DivisionByZero,
+    #[error("shift by {0} is too large")]
+    OverShift(Value),
+    #[error("no intrinsic named `{0}`")]
+    UnknownIntrinsic(String),
+    #[error("`{name}` takes {expected} arguments but {found} were given")]
+    WrongNumberOfArguments {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+pub fn lookup_variable(var: &Variable, context: &Context) -> Result<Value, EvalError> {
+    context.get(var).cloned()
+        .ok_or_else(|| EvalError::UndefinedVariable(var.clone()))
+}
+
+pub fn check_arity(
+    intrinsic: &intrinsics::Intrinsic,
+    args: &[Expr],
+) -> Result<(), EvalError> {
+    if intrinsic.arity != args.len() {
+        return Err(EvalError::WrongNumberOfArguments {
+            name: intrinsic.name.to_string(),
+            expected: intrinsic.arity,
+            found: args.len(),
+        });
+    }
+    Ok(())
+}
+
+pub fn interpret_expr(expr: &Expr, context: &Context) -> Result<Value, EvalError> {
+    match expr {
+        Expr::Var(ref var) => lookup_variable(var, context),
+        Expr::Const(value) => Ok(*value),
+        Expr::Not(ref x) => Ok(!interpret_expr(x, context)?),
+        Expr::Call(ref name, ref args) => {
+            let intrinsic = intrinsics::lookup(name)
+                .ok_or_else(|| EvalError::UnknownIntrinsic(name.clone()))?;
+            check_arity(intrinsic, args)?;
+            let values = args.iter()
+                .map(|arg| interpret_expr(arg, context))
+                .collect::<Result<Vec<Value>, EvalError>>()?;
+            Ok((intrinsic.function)(&values))
+        },
+        _ => {
+            let (op, x, y) = expr.as_binary().unwrap();
+            op.apply(interpret_expr(x, context)?, interpret_expr(y, context)?)
+        },
+    }
+}
+
+enum Halt {
+    Return(Value),
+    Error(EvalError),
+}
+
+impl From<EvalError> for Halt {
+    fn from(error: EvalError) -> Halt {
+        Halt::Error(error)
+    }
+}
+
+fn execute_spec(spec: &Spec, context: &mut Context) -> Result<(), Halt> {
+    match spec {
+        Spec::Assign(ref var, ref expr) => {
+            let value = interpret_expr(expr, context)?;
+            context.insert(var.clone(), value);
+        },
+        Spec::Block(specs) => {
+            for s in specs {
+                execute_spec(s, context)?;
+            }
+        },
+        Spec::For(ref variable, ref lower_expr, ref upper_expr, ref s) => {
+            let lower = interpret_expr(lower_expr, context)?;
+            let upper = interpret_expr(upper_expr, context)?;
+            let shadowed: Option<Value> = context.get(variable).cloned();
+            for i in lower ..= upper {
+                context.insert(variable.clone(), i);
+                execute_spec(s, context)?;
+            }
+            if let Some(x) = shadowed {
+                context.insert(variable.clone(), x);
+            } else {
+                context.remove(variable);
+            }
+        },
+        Spec::If(ref cond_expr, ref if_true, ref if_false) => {
+            let cond = interpret_expr(cond_expr, context)?;
+            if cond == 0 {
+                execute_spec(if_false, context)?;
+            } else {
+                execute_spec(if_true, context)?;
+            }
+        },
+        Spec::Return(ref variable) => {
+            return Err(Halt::Return(lookup_variable(variable, context)?));
+        },
+    }
+    Ok(())
+}
+
+/// Runs a spell to completion. Returns `Ok(Some(value))` if it hit a
+/// `return`, and `Ok(None)` if it ran off the end.
+pub fn interpret_spec(spec: &Spec, context: &mut Context) -> Result<Option<Value>, EvalError> {
+    match execute_spec(spec, context) {
+        Ok(()) => Ok(None),
+        Err(Halt::Return(value)) => Ok(Some(value)),
+        Err(Halt::Error(error)) => Err(error),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LaneOp {
+    Or,
+    And,
+    Xor,
+    Not,
+    /// Shifts left by the second operand read as an `i32`; negative amounts
+    /// shift right. Shifting by 32 or more in either direction gives 0.
+    Shift,
+    Add,
+    Negate,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    LessThan,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CrossLaneOp {
+    Rotate,
+    AndReduce,
+    OrReduce,
+    AddReduce,
+    XorReduce,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Operand {
+    Register(usize),
+    /// Broadcast to every lane.
+    Immediate(Value),
+}
+
+/// A lane op applied element-wise across every lane of its operands. Unary
+/// ops ignore `rhs`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LaneInstruction {
+    pub op: LaneOp,
+    pub destination: usize,
+    pub lhs: Operand,
+    pub rhs: Operand,
+}
+
+/// Reductions broadcast their result to every lane of `destination`.
+/// `Rotate` moves lane `i` of `source` to lane `i + amount`, wrapping around.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CrossLaneInstruction {
+    pub op: CrossLaneOp,
+    pub destination: usize,
+    pub source: usize,
+    pub amount: usize,
+}
+
+/// Everything issued in a single cycle. All operands are read before any
+/// results are written.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Bundle {
+    pub lane_ops: Vec<LaneInstruction>,
+    pub cross_lane_op: Option<CrossLaneInstruction>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VLIW {
+    pub number_of_registers: usize,
+    pub number_of_lanes: usize,
+    /// The maximum number of lane ops in a bundle.
+    pub issue_width: usize,
+    pub lane_ops: BTreeSet<LaneOp>,
+    pub cross_lane_ops: BTreeSet<CrossLaneOp>,
+}
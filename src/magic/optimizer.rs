@@ -0,0 +1,673 @@
+// Rewrites spells into cheaper ones that compute the same thing, so that the
+// engine doesn't charge for redundancy that any compiler would remove.
+//
+// Every pass keeps the outcome of every run the same, including the error a
+// failing spell fails with; only the mana spent can go down, so a spell that
+// used to run out of mana may now finish. To make that hold, nothing that
+// could fail is ever removed or moved: reading a variable that might not be
+// assigned yet, dividing by something that might be zero, shifting by
+// something that might be too far, or calling an intrinsic that can't be
+// called.
+
+use crate::magic::{BinaryOp, Expr, Spec, Value, Variable};
+use crate::magic::checker::assigned_in;
+use crate::magic::intrinsics::IntrinsicSet;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// `optimize` stops once a round of passes changes nothing, or after this
+/// many rounds.
+pub const MAX_ROUNDS: usize = 8;
+
+type Variables = BTreeSet<Variable>;
+
+fn uses(expr: &Expr, variables: &mut Variables) {
+    match expr {
+        Expr::Var(ref var) => {
+            variables.insert(var.clone());
+        },
+        Expr::Const(_) => {},
+        Expr::Not(ref x) => uses(x, variables),
+        Expr::Call(_, ref args) => {
+            for arg in args {
+                uses(arg, variables);
+            }
+        },
+        _ => {
+            let (_, x, y) = expr.as_binary().unwrap();
+            uses(x, variables);
+            uses(y, variables);
+        },
+    }
+}
+
+fn uses_of(exprs: &[&Expr]) -> Variables {
+    let mut variables = Variables::new();
+    for expr in exprs {
+        uses(expr, &mut variables);
+    }
+    variables
+}
+
+fn assigned(spec: &Spec) -> Variables {
+    let mut variables = Variables::new();
+    assigned_in(spec, &mut variables);
+    variables
+}
+
+// Whether evaluating `expr` could fail, when only the variables in `defined`
+// are sure to be assigned.
+fn can_fail(expr: &Expr, defined: &Variables, intrinsics: &IntrinsicSet) -> bool {
+    match expr {
+        Expr::Var(ref var) => !defined.contains(var),
+        Expr::Const(_) => false,
+        Expr::Not(ref x) => can_fail(x, defined, intrinsics),
+        Expr::Call(ref name, ref args) => {
+            let callable = matches!(intrinsics.get(name),
+                                    Some(intrinsic) if intrinsic.arity == args.len());
+            !callable || args.iter().any(|arg| can_fail(arg, defined, intrinsics))
+        },
+        _ => {
+            let (op, x, y) = expr.as_binary().unwrap();
+            let total = match op {
+                BinaryOp::Div | BinaryOp::Mod => matches!(y, Expr::Const(c) if *c != 0),
+                BinaryOp::ShiftLeft | BinaryOp::ShiftRight =>
+                    matches!(y, Expr::Const(c) if *c < Value::BITS),
+                _ => true,
+            };
+            !total || can_fail(x, defined, intrinsics) || can_fail(y, defined, intrinsics)
+        },
+    }
+}
+
+// The variables that are sure to be assigned after `spec` runs, if it
+// finishes without returning. A variable that was read must have been
+// assigned, or the spell would have failed.
+fn defined_after(spec: &Spec, defined: &Variables) -> Variables {
+    let mut after = defined.clone();
+    match spec {
+        Spec::Assign(ref var, ref expr) => {
+            uses(expr, &mut after);
+            after.insert(var.clone());
+        },
+        Spec::Block(ref specs) => {
+            for s in specs {
+                after = defined_after(s, &after);
+            }
+        },
+        Spec::For(_, ref lower, ref upper, _) => {
+            uses(lower, &mut after);
+            uses(upper, &mut after);
+        },
+        Spec::If(ref cond, ref if_true, ref if_false) => {
+            uses(cond, &mut after);
+            let if_true = defined_after(if_true, &after);
+            let if_false = defined_after(if_false, &after);
+            after = if_true.intersection(&if_false).cloned().collect();
+        },
+        Spec::Return(ref var) => {
+            after.insert(var.clone());
+        },
+    }
+    after
+}
+
+fn map_exprs(spec: &Spec, f: &mut impl FnMut(&Expr) -> Expr) -> Spec {
+    match spec {
+        Spec::Assign(ref var, ref expr) => Spec::Assign(var.clone(), f(expr)),
+        Spec::Block(ref specs) => Spec::Block(specs.iter().map(|s| map_exprs(s, f)).collect()),
+        Spec::For(ref var, ref lower, ref upper, ref body) =>
+            Spec::For(var.clone(), f(lower), f(upper), Box::new(map_exprs(body, f))),
+        Spec::If(ref cond, ref if_true, ref if_false) =>
+            Spec::If(f(cond), Box::new(map_exprs(if_true, f)), Box::new(map_exprs(if_false, f))),
+        Spec::Return(ref var) => Spec::Return(var.clone()),
+    }
+}
+
+fn statements(spec: Spec) -> Vec<Spec> {
+    match spec {
+        Spec::Block(specs) => specs,
+        spec => vec![spec],
+    }
+}
+
+struct Folder<'a> {
+    intrinsics: &'a IntrinsicSet,
+    // Variables known to hold a particular value at this point.
+    constants: BTreeMap<Variable, Value>,
+}
+
+impl<'a> Folder<'a> {
+    fn expr(&self, expr: &Expr) -> Expr {
+        match expr {
+            Expr::Var(ref var) => match self.constants.get(var) {
+                Some(value) => Expr::Const(*value),
+                None => expr.clone(),
+            },
+            Expr::Const(_) => expr.clone(),
+            Expr::Not(ref x) => match self.expr(x) {
+                Expr::Const(value) => Expr::Const(!value),
+                x => Expr::Not(Box::new(x)),
+            },
+            Expr::Call(ref name, ref args) => {
+                let args: Vec<Expr> = args.iter().map(|arg| self.expr(arg)).collect();
+                let values: Option<Vec<Value>> = args.iter()
+                    .map(|arg| match arg {
+                        Expr::Const(value) => Some(*value),
+                        _ => None,
+                    })
+                    .collect();
+                match (self.intrinsics.get(name), values) {
+                    (Some(intrinsic), Some(values)) if intrinsic.arity == values.len() =>
+                        Expr::Const((intrinsic.function)(&values)),
+                    _ => Expr::Call(name.clone(), args),
+                }
+            },
+            _ => {
+                let (op, x, y) = expr.as_binary().unwrap();
+                let (x, y) = (self.expr(x), self.expr(y));
+                if let (Expr::Const(a), Expr::Const(b)) = (&x, &y) {
+                    // Operations that fail are left for the spell to fail on.
+                    if let Ok(value) = op.apply(*a, *b) {
+                        return Expr::Const(value);
+                    }
+                }
+                // Identities that drop a constant, never a variable.
+                match (op, &x, &y) {
+                    (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Or | BinaryOp::Xor |
+                     BinaryOp::ShiftLeft | BinaryOp::ShiftRight, _, Expr::Const(0)) |
+                    (BinaryOp::Mul | BinaryOp::Div, _, Expr::Const(1)) |
+                    (BinaryOp::And, _, Expr::Const(Value::MAX)) => x,
+                    (BinaryOp::Add | BinaryOp::Or | BinaryOp::Xor, Expr::Const(0), _) |
+                    (BinaryOp::Mul, Expr::Const(1), _) |
+                    (BinaryOp::And, Expr::Const(Value::MAX), _) => y,
+                    _ => Expr::binary(op, x, y),
+                }
+            },
+        }
+    }
+
+    fn forget(&mut self, variables: &Variables) {
+        for var in variables {
+            self.constants.remove(var);
+        }
+    }
+
+    fn spec(&mut self, spec: &Spec) -> Spec {
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                let expr = self.expr(expr);
+                match expr {
+                    Expr::Const(value) => self.constants.insert(var.clone(), value),
+                    _ => self.constants.remove(var),
+                };
+                Spec::Assign(var.clone(), expr)
+            },
+            Spec::Block(ref specs) => {
+                let mut result = Vec::new();
+                for s in specs {
+                    result.extend(statements(self.spec(s)));
+                    // Nothing after a return can run.
+                    if let Some(Spec::Return(_)) = result.last() {
+                        break;
+                    }
+                }
+                Spec::Block(result)
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                let (lower, upper) = (self.expr(lower), self.expr(upper));
+                if let (Expr::Const(a), Expr::Const(b)) = (&lower, &upper) {
+                    if a > b {
+                        return Spec::Block(Vec::new());
+                    }
+                }
+                // The loop variable is put back when the loop ends, so what
+                // was known about it before still holds afterwards.
+                let changed = assigned(body);
+                let before = self.constants.clone();
+                self.forget(&changed);
+                self.constants.remove(var);
+                let body = self.spec(body);
+                self.constants = before;
+                self.forget(&changed);
+                Spec::For(var.clone(), lower, upper, Box::new(body))
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                match self.expr(cond) {
+                    Expr::Const(0) => self.spec(if_false),
+                    Expr::Const(_) => self.spec(if_true),
+                    cond => {
+                        let before = self.constants.clone();
+                        let if_true = self.spec(if_true);
+                        let after_true = std::mem::replace(&mut self.constants, before);
+                        let if_false = self.spec(if_false);
+                        self.constants.retain(|var, value| after_true.get(var) == Some(value));
+                        Spec::If(cond, Box::new(if_true), Box::new(if_false))
+                    },
+                }
+            },
+            Spec::Return(ref var) => Spec::Return(var.clone()),
+        }
+    }
+}
+
+/// Evaluates whatever can be worked out without running the spell, including
+/// branches on known conditions and loops that never run. Variables assigned
+/// a constant are replaced by it until they might change.
+pub fn fold_constants(spec: &Spec, intrinsics: &IntrinsicSet) -> Spec {
+    Folder { intrinsics, constants: BTreeMap::new() }.spec(spec)
+}
+
+fn power_of_two(expr: &Expr) -> Option<Value> {
+    match expr {
+        Expr::Const(value) if value.is_power_of_two() => Some(value.trailing_zeros()),
+        _ => None,
+    }
+}
+
+fn reduce_expr(expr: &Expr) -> Expr {
+    match expr {
+        Expr::Var(_) | Expr::Const(_) => expr.clone(),
+        Expr::Not(ref x) => Expr::Not(Box::new(reduce_expr(x))),
+        Expr::Call(ref name, ref args) => Expr::Call(name.clone(), args.iter().map(reduce_expr).collect()),
+        _ => {
+            let (op, x, y) = expr.as_binary().unwrap();
+            let (x, y) = (reduce_expr(x), reduce_expr(y));
+            match (op, power_of_two(&x), power_of_two(&y)) {
+                (BinaryOp::Mul, _, Some(shift)) =>
+                    return Expr::ShiftLeft(Box::new(x), Box::new(Expr::Const(shift))),
+                (BinaryOp::Mul, Some(shift), _) =>
+                    return Expr::ShiftLeft(Box::new(y), Box::new(Expr::Const(shift))),
+                (BinaryOp::Div, _, Some(shift)) =>
+                    return Expr::ShiftRight(Box::new(x), Box::new(Expr::Const(shift))),
+                (BinaryOp::Mod, _, Some(shift)) =>
+                    return Expr::And(Box::new(x), Box::new(Expr::Const((1 << shift) - 1))),
+                _ => {},
+            }
+            // Two shifts the same way by constants are one shift, as long as
+            // the total isn't too far to shift by.
+            if let (Some((inner, z, Expr::Const(a))), Expr::Const(b)) = (x.as_binary(), &y) {
+                let shift = matches!(op, BinaryOp::ShiftLeft | BinaryOp::ShiftRight);
+                if let (true, Some(total)) = (inner == op && shift, a.checked_add(*b)) {
+                    if total < Value::BITS {
+                        return Expr::binary(op, z.clone(), Expr::Const(total));
+                    }
+                }
+            }
+            Expr::binary(op, x, y)
+        },
+    }
+}
+
+/// Replaces multiplication, division and remainder by powers of two with
+/// shifts and masks, and merges shifts by constants.
+pub fn reduce_shifts(spec: &Spec) -> Spec {
+    map_exprs(spec, &mut reduce_expr)
+}
+
+fn hoist(spec: &Spec, defined: &Variables, intrinsics: &IntrinsicSet) -> Spec {
+    match spec {
+        Spec::Assign(_, _) | Spec::Return(_) => spec.clone(),
+        Spec::Block(ref specs) => {
+            let mut defined = defined.clone();
+            let mut result = Vec::new();
+            for s in specs {
+                result.push(hoist(s, &defined, intrinsics));
+                defined = defined_after(s, &defined);
+            }
+            Spec::Block(result)
+        },
+        Spec::If(ref cond, ref if_true, ref if_false) => {
+            let mut defined = defined.clone();
+            uses(cond, &mut defined);
+            Spec::If(
+                cond.clone(),
+                Box::new(hoist(if_true, &defined, intrinsics)),
+                Box::new(hoist(if_false, &defined, intrinsics)))
+        },
+        Spec::For(ref var, ref lower, ref upper, ref body) => {
+            let mut inside = defined.clone();
+            uses(lower, &mut inside);
+            uses(upper, &mut inside);
+            inside.insert(var.clone());
+            let body = hoist(body, &inside, intrinsics);
+            // A hoisted assignment happens even if the loop never runs, so
+            // only loops sure to run are touched.
+            let runs = matches!((lower, upper), (Expr::Const(a), Expr::Const(b)) if a <= b);
+            if !runs {
+                return Spec::For(var.clone(), lower.clone(), upper.clone(), Box::new(body));
+            }
+            let mut changed = assigned(&body);
+            changed.insert(var.clone());
+            let mut counts: BTreeMap<Variable, usize> = BTreeMap::new();
+            count_assignments(&body, &mut counts);
+
+            // Only the assignments at the start of the body qualify, so that
+            // the first iteration is sure to reach them: everything before
+            // them is an assignment that can't fail.
+            let mut hoisted = Vec::new();
+            let mut kept = Vec::new();
+            let mut read = Variables::new();
+            let mut prefix = true;
+            for s in statements(body) {
+                if prefix {
+                    if let Spec::Assign(ref target, ref expr) = s {
+                        let invariant = target != var
+                            && counts.get(target) == Some(&1)
+                            && !read.contains(target)
+                            && uses_of(&[expr]).is_disjoint(&changed);
+                        if can_fail(expr, &inside, intrinsics) {
+                            prefix = false;
+                        } else if invariant {
+                            hoisted.push(s);
+                            continue;
+                        }
+                    } else {
+                        prefix = false;
+                    }
+                }
+                reads_in(&s, &mut read);
+                kept.push(s);
+            }
+            if hoisted.is_empty() {
+                return Spec::For(var.clone(), lower.clone(), upper.clone(), Box::new(Spec::Block(kept)));
+            }
+            hoisted.push(Spec::For(var.clone(), lower.clone(), upper.clone(), Box::new(Spec::Block(kept))));
+            Spec::Block(hoisted)
+        },
+    }
+}
+
+fn count_assignments(spec: &Spec, counts: &mut BTreeMap<Variable, usize>) {
+    match spec {
+        Spec::Assign(ref var, _) => *counts.entry(var.clone()).or_insert(0) += 1,
+        Spec::Block(ref specs) => {
+            for s in specs {
+                count_assignments(s, counts);
+            }
+        },
+        Spec::For(ref var, _, _, ref body) => {
+            *counts.entry(var.clone()).or_insert(0) += 1;
+            count_assignments(body, counts);
+        },
+        Spec::If(_, ref if_true, ref if_false) => {
+            count_assignments(if_true, counts);
+            count_assignments(if_false, counts);
+        },
+        Spec::Return(_) => {},
+    }
+}
+
+fn reads_in(spec: &Spec, variables: &mut Variables) {
+    match spec {
+        Spec::Assign(_, ref expr) => uses(expr, variables),
+        Spec::Block(ref specs) => {
+            for s in specs {
+                reads_in(s, variables);
+            }
+        },
+        Spec::For(_, ref lower, ref upper, ref body) => {
+            uses(lower, variables);
+            uses(upper, variables);
+            reads_in(body, variables);
+        },
+        Spec::If(ref cond, ref if_true, ref if_false) => {
+            uses(cond, variables);
+            reads_in(if_true, variables);
+            reads_in(if_false, variables);
+        },
+        Spec::Return(ref var) => {
+            variables.insert(var.clone());
+        },
+    }
+}
+
+/// Moves assignments that compute the same thing on every iteration out in
+/// front of their loop. `inputs` are the variables the spell is given.
+pub fn hoist_loop_invariants(spec: &Spec, inputs: &[Variable], intrinsics: &IntrinsicSet) -> Spec {
+    hoist(spec, &inputs.iter().cloned().collect(), intrinsics)
+}
+
+struct Eliminator<'a> {
+    intrinsics: &'a IntrinsicSet,
+}
+
+impl<'a> Eliminator<'a> {
+    // Returns the rewritten statement and the variables live before it.
+    fn spec(&self, spec: &Spec, defined: &Variables, live: &Variables) -> (Spec, Variables) {
+        match spec {
+            Spec::Assign(ref var, ref expr) => {
+                if !live.contains(var) && !can_fail(expr, defined, self.intrinsics) {
+                    return (Spec::Block(Vec::new()), live.clone());
+                }
+                let mut before = live.clone();
+                before.remove(var);
+                uses(expr, &mut before);
+                (spec.clone(), before)
+            },
+            Spec::Block(ref specs) => {
+                let mut defined_before = Vec::with_capacity(specs.len());
+                let mut defined = defined.clone();
+                for s in specs {
+                    defined_before.push(defined.clone());
+                    defined = defined_after(s, &defined);
+                }
+                let mut live = live.clone();
+                let mut result = Vec::with_capacity(specs.len());
+                for (s, defined) in specs.iter().zip(&defined_before).rev() {
+                    let (s, before) = self.spec(s, defined, &live);
+                    result.push(s);
+                    live = before;
+                }
+                result.reverse();
+                let result = result.into_iter().flat_map(statements).collect();
+                (Spec::Block(result), live)
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                let mut inside = defined.clone();
+                uses(lower, &mut inside);
+                uses(upper, &mut inside);
+                inside.insert(var.clone());
+                // Live at the end of the body is whatever is live at the
+                // top of the loop, except the loop variable, which is about
+                // to be overwritten either way.
+                let mut end = live.clone();
+                end.remove(var);
+                let (body, body_live) = loop {
+                    let (body, body_live) = self.spec(body, &inside, &end);
+                    let mut next = live.union(&body_live).cloned().collect::<Variables>();
+                    next.remove(var);
+                    if next == end {
+                        break (body, body_live);
+                    }
+                    end = next;
+                };
+                let mut after_lower = defined.clone();
+                uses(lower, &mut after_lower);
+                let bounds_can_fail = can_fail(lower, defined, self.intrinsics)
+                    || can_fail(upper, &after_lower, self.intrinsics);
+                if !bounds_can_fail && body == Spec::Block(Vec::new()) {
+                    return (body, live.clone());
+                }
+                let mut before = end;
+                before.extend(body_live.into_iter().filter(|v| v != var));
+                // The variable's old value comes back when the loop ends.
+                if live.contains(var) {
+                    before.insert(var.clone());
+                }
+                uses(lower, &mut before);
+                uses(upper, &mut before);
+                (Spec::For(var.clone(), lower.clone(), upper.clone(), Box::new(body)), before)
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                let mut inside = defined.clone();
+                uses(cond, &mut inside);
+                let (if_true, true_live) = self.spec(if_true, &inside, live);
+                let (if_false, false_live) = self.spec(if_false, &inside, live);
+                let empty = Spec::Block(Vec::new());
+                if if_true == empty && if_false == empty && !can_fail(cond, defined, self.intrinsics) {
+                    return (empty, live.clone());
+                }
+                let mut before: Variables = true_live.union(&false_live).cloned().collect();
+                uses(cond, &mut before);
+                (Spec::If(cond.clone(), Box::new(if_true), Box::new(if_false)), before)
+            },
+            Spec::Return(ref var) => (spec.clone(), [var.clone()].into_iter().collect()),
+        }
+    }
+}
+
+/// Removes assignments whose value is never read, and loops and branches
+/// left with nothing to do. `inputs` are the variables the spell is given.
+pub fn eliminate_dead_stores(spec: &Spec, inputs: &[Variable], intrinsics: &IntrinsicSet) -> Spec {
+    let defined = inputs.iter().cloned().collect();
+    Eliminator { intrinsics }.spec(spec, &defined, &Variables::new()).0
+}
+
+/// Runs every pass until they stop finding anything to improve.
+pub fn optimize(spec: &Spec, inputs: &[Variable], intrinsics: &IntrinsicSet) -> Spec {
+    let mut spec = spec.clone();
+    for _ in 0 .. MAX_ROUNDS {
+        let next = fold_constants(&spec, intrinsics);
+        let next = reduce_shifts(&next);
+        let next = hoist_loop_invariants(&next, inputs, intrinsics);
+        let next = eliminate_dead_stores(&next, inputs, intrinsics);
+        if next == spec {
+            break;
+        }
+        spec = next;
+    }
+    spec
+}
+
+/// The variables `spec` might read before assigning them, which are what it
+/// expects to be given.
+pub fn free_variables(spec: &Spec) -> Vec<Variable> {
+    fn walk(spec: &Spec, defined: &Variables, free: &mut Variables) -> Variables {
+        let read = |exprs: &[&Expr], free: &mut Variables| {
+            free.extend(uses_of(exprs).difference(defined).cloned());
+        };
+        match spec {
+            Spec::Assign(_, ref expr) => read(&[expr], free),
+            Spec::Block(ref specs) => {
+                let mut defined = defined.clone();
+                for s in specs {
+                    defined = walk(s, &defined, free);
+                }
+                return defined;
+            },
+            Spec::For(ref var, ref lower, ref upper, ref body) => {
+                read(&[lower, upper], free);
+                let mut inside = defined.clone();
+                inside.insert(var.clone());
+                walk(body, &inside, free);
+            },
+            Spec::If(ref cond, ref if_true, ref if_false) => {
+                read(&[cond], free);
+                walk(if_true, defined, free);
+                walk(if_false, defined, free);
+            },
+            Spec::Return(ref var) => {
+                if !defined.contains(var) {
+                    free.insert(var.clone());
+                }
+            },
+        }
+        defined_after(spec, defined)
+    }
+    let mut free = Variables::new();
+    walk(spec, &Variables::new(), &mut free);
+    free.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::Context;
+    use crate::magic::mana::{interpret_metered, Execution, Mana, ManaCosts, SpellError};
+    use crate::magic::parser::parse;
+    use crate::magic::testing::Generator;
+    use rand::{Rng, SeedableRng};
+    use rand::seq::SliceRandom;
+    use rand_chacha::ChaCha8Rng;
+
+    const SPELLS: Generator = Generator {
+        names: &["a", "b", "i", "x", "y"],
+        constants: &[0, 1, 2, 4, 7, 31, 32, Value::MAX],
+        calls: &[("popcount", 1), ("min", 2), ("frobnicate", 1)],
+        dynamic_bounds: true,
+    };
+    // Enough for any spell that doesn't loop over a dynamic range.
+    const BUDGET: Mana = 1_000_000;
+
+    fn run(spec: &Spec, context: &Context, intrinsics: &IntrinsicSet, budget: Mana) -> (Execution, Context) {
+        let mut context = context.clone();
+        let execution = interpret_metered(spec, &mut context, &ManaCosts::default(), intrinsics, budget);
+        (execution, context)
+    }
+
+    // The optimized spell has to return the same thing and cost no more.
+    // Dead stores are dropped, so its variables may end up with older
+    // values, but it mustn't leave behind any the spell wouldn't have.
+    fn assert_same(spec: &Spec, context: &Context, intrinsics: &IntrinsicSet) {
+        let inputs: Vec<Variable> = context.keys().cloned().collect();
+        let optimized = optimize(spec, &inputs, intrinsics);
+        let (expected, expected_context) = run(spec, context, intrinsics, BUDGET);
+        if expected.result == Err(SpellError::OutOfMana) {
+            return;
+        }
+        let (actual, actual_context) = run(&optimized, context, intrinsics, BUDGET);
+        assert_eq!(actual.result, expected.result, "{:?}\n{:?}", spec, optimized);
+        assert!(actual.mana_spent <= expected.mana_spent, "{:?}\n{:?}", spec, optimized);
+        if expected.result.is_ok() {
+            for variable in actual_context.keys() {
+                assert!(expected_context.contains_key(variable), "{:?}\n{:?}", spec, optimized);
+            }
+        }
+
+        // Running out of mana partway can't hide a different answer.
+        let budget = expected.mana_spent / 2;
+        let (actual, _) = run(&optimized, context, intrinsics, budget);
+        if actual.result != Err(SpellError::OutOfMana) {
+            assert_eq!(actual.result, expected.result, "{:?}\n{:?}", spec, optimized);
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_on_examples() {
+        let spells = [
+            "c = 0; for i in 0 ..= 31 { c = c + ((x >> i) & 1); } return c;",
+            "y = x * 8 + x / 4 + x % 16; return y;",
+            "for i in 0 ..= 3 { k = x * 2; y = k + i; } return y;",
+            "for i in 1 ..= 0 { k = x / 0; } return x;",
+            "y = 3; if y > 2 { z = x << 40; } return y;",
+            "unused = a / b; return x;",
+            "if x { return undefined; } return x;",
+            "y = frobnicate(x); return x;",
+        ];
+        let context: Context = [("x", 37), ("a", 4), ("b", 0)].iter()
+            .map(|(name, value)| (Variable::new(name), *value))
+            .collect();
+        for source in spells {
+            let spec = parse(source).unwrap();
+            assert_same(&spec, &context, &IntrinsicSet::all());
+            assert_same(&spec, &context, &IntrinsicSet::for_run(3));
+        }
+    }
+
+    #[test]
+    fn matches_interpreter_on_random_spells() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0 .. 2000 {
+            let spec = SPELLS.spec(&mut rng, 4);
+            let mut context = Context::new();
+            for name in SPELLS.names {
+                if rng.gen_bool(0.6) {
+                    context.insert(Variable::new(name), *[0, 1, 3, 32, 40, Value::MAX].choose(&mut rng).unwrap());
+                }
+            }
+            let intrinsics = IntrinsicSet::for_run(rng.gen());
+            assert_same(&spec, &context, &intrinsics);
+            assert_same(&spec, &context, &IntrinsicSet::all());
+        }
+    }
+}
@@ -0,0 +1,573 @@
+// A spell is a sequence of statements, e.g.
+//
+//     y = x ^ (x >> k);   // comments run to the end of the line
+//     for i in lo ..= hi {
+//         y = y | (y << i);
+//     }
+//     if y < 0x100 { return y; } else { z = popcount(x) * 3; return z; }
+//
+// Operator precedence follows Rust, and as in Rust comparisons can't be
+// chained without parentheses.
+
+use crate::editor::Document;
+use crate::magic::{BinaryOp, Expr, Spec, Value, Variable};
+use thiserror::Error;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum ParseErrorKind {
+    #[error("unexpected character {0:?}")]
+    UnexpectedCharacter(char),
+    #[error("invalid number `{0}`")]
+    InvalidNumber(String),
+    #[error("comparisons can't be chained; add parentheses")]
+    ChainedComparison,
+    #[error("expected {expected}, found {found}")]
+    Unexpected {
+        expected: &'static str,
+        found: String,
+    },
+}
+
+/// A parse failure. Lines and columns are 1-based and count characters.
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+#[error("{line}:{column}: {kind}")]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+/// A place in the source. Lines and columns are 1-based and count
+/// characters.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// The source text from `start` up to but not including `end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+/// Where every node of a parsed spell came from.
+///
+/// Each `Spec` and `Expr` node has one span, listed in post-order: a node
+/// comes right after its last child, and children are in the order of the
+/// node's fields. Parentheses don't make nodes of their own. The empty
+/// `else` block of an `if` without one gets an empty span where it would
+/// have been.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpanTable {
+    pub spans: Vec<Span>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Number(Value),
+    For,
+    In,
+    If,
+    Else,
+    Return,
+    Assign,
+    Semicolon,
+    Comma,
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    InclusiveRange,
+    Binary(BinaryOp),
+    Not,
+    EndOfInput,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Token::Identifier(name) => format!("identifier `{}`", name),
+            Token::Number(value) => format!("number `{}`", value),
+            Token::For => "`for`".to_string(),
+            Token::In => "`in`".to_string(),
+            Token::If => "`if`".to_string(),
+            Token::Else => "`else`".to_string(),
+            Token::Return => "`return`".to_string(),
+            Token::Assign => "`=`".to_string(),
+            Token::Semicolon => "`;`".to_string(),
+            Token::Comma => "`,`".to_string(),
+            Token::LeftBrace => "`{`".to_string(),
+            Token::RightBrace => "`}`".to_string(),
+            Token::LeftParen => "`(`".to_string(),
+            Token::RightParen => "`)`".to_string(),
+            Token::InclusiveRange => "`..=`".to_string(),
+            Token::Binary(op) => format!("`{}`", op.symbol()),
+            Token::Not => "`~`".to_string(),
+            Token::EndOfInput => "end of input".to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Located {
+    token: Token,
+    line: usize,
+    column: usize,
+    length: usize,
+}
+
+impl Located {
+    fn start(&self) -> Position {
+        Position { line: self.line, column: self.column }
+    }
+
+    fn end(&self) -> Position {
+        Position { line: self.line, column: self.column + self.length }
+    }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Located>, ParseError> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+    let mut line = 1;
+    let mut column = 1;
+    while index < chars.len() {
+        let c = chars[index];
+        let next = chars.get(index + 1).cloned();
+        let (start_line, start_column) = (line, column);
+        if c == '\n' {
+            index += 1;
+            line += 1;
+            column = 1;
+            continue;
+        }
+        if c.is_whitespace() {
+            index += 1;
+            column += 1;
+            continue;
+        }
+        if c == '/' && next == Some('/') {
+            while index < chars.len() && chars[index] != '\n' {
+                index += 1;
+                column += 1;
+            }
+            continue;
+        }
+        let (token, length) = if c.is_ascii_alphabetic() || c == '_' {
+            let word = take_word(&chars[index..]);
+            let length = word.len();
+            let token = match word.as_str() {
+                "for" => Token::For,
+                "in" => Token::In,
+                "if" => Token::If,
+                "else" => Token::Else,
+                "return" => Token::Return,
+                _ => Token::Identifier(word),
+            };
+            (token, length)
+        } else if c.is_ascii_digit() {
+            let word = take_word(&chars[index..]);
+            let Some(value) = parse_number(&word) else {
+                return Err(ParseError {
+                    line,
+                    column,
+                    kind: ParseErrorKind::InvalidNumber(word),
+                });
+            };
+            (Token::Number(value), word.len())
+        } else {
+            match (c, next) {
+                ('<', Some('<')) => (Token::Binary(BinaryOp::ShiftLeft), 2),
+                ('>', Some('>')) => (Token::Binary(BinaryOp::ShiftRight), 2),
+                ('<', Some('=')) => (Token::Binary(BinaryOp::Le), 2),
+                ('>', Some('=')) => (Token::Binary(BinaryOp::Ge), 2),
+                ('=', Some('=')) => (Token::Binary(BinaryOp::Eq), 2),
+                ('!', Some('=')) => (Token::Binary(BinaryOp::Ne), 2),
+                ('.', Some('.')) if chars.get(index + 2) == Some(&'=') =>
+                    (Token::InclusiveRange, 3),
+                ('<', _) => (Token::Binary(BinaryOp::Lt), 1),
+                ('>', _) => (Token::Binary(BinaryOp::Gt), 1),
+                ('=', _) => (Token::Assign, 1),
+                (';', _) => (Token::Semicolon, 1),
+                (',', _) => (Token::Comma, 1),
+                ('{', _) => (Token::LeftBrace, 1),
+                ('}', _) => (Token::RightBrace, 1),
+                ('(', _) => (Token::LeftParen, 1),
+                (')', _) => (Token::RightParen, 1),
+                ('|', _) => (Token::Binary(BinaryOp::Or), 1),
+                ('&', _) => (Token::Binary(BinaryOp::And), 1),
+                ('^', _) => (Token::Binary(BinaryOp::Xor), 1),
+                ('+', _) => (Token::Binary(BinaryOp::Add), 1),
+                ('-', _) => (Token::Binary(BinaryOp::Sub), 1),
+                ('*', _) => (Token::Binary(BinaryOp::Mul), 1),
+                ('/', _) => (Token::Binary(BinaryOp::Div), 1),
+                ('%', _) => (Token::Binary(BinaryOp::Mod), 1),
+                ('~', _) => (Token::Not, 1),
+                _ => {
+                    return Err(ParseError {
+                        line,
+                        column,
+                        kind: ParseErrorKind::UnexpectedCharacter(c),
+                    });
+                },
+            }
+        };
+        tokens.push(Located { token, line: start_line, column: start_column, length });
+        index += length;
+        column += length;
+    }
+    tokens.push(Located { token: Token::EndOfInput, line, column, length: 0 });
+    Ok(tokens)
+}
+
+// The longest prefix of `chars` that could be an identifier or a number.
+fn take_word(chars: &[char]) -> String {
+    chars.iter()
+        .take_while(|c| c.is_ascii_alphanumeric() || **c == '_')
+        .collect()
+}
+
+// Accepts decimal, `0x` hexadecimal and `0b` binary literals, with optional
+// `_` separators.
+fn parse_number(word: &str) -> Option<Value> {
+    let digits = word.replace('_', "");
+    let (digits, radix) = if let Some(hex) = digits.strip_prefix("0x") {
+        (hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        (binary, 2)
+    } else {
+        (&digits[..], 10)
+    };
+    Value::from_str_radix(digits, radix).ok()
+}
+
+/// How tightly each binary operator binds; higher binds tighter. Unary `~`
+/// binds tighter than all of them.
+pub fn precedence(op: BinaryOp) -> u8 {
+    match op {
+        BinaryOp::Eq | BinaryOp::Ne | BinaryOp::Lt |
+        BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 1,
+        BinaryOp::Or => 2,
+        BinaryOp::Xor => 3,
+        BinaryOp::And => 4,
+        BinaryOp::ShiftLeft | BinaryOp::ShiftRight => 5,
+        BinaryOp::Add | BinaryOp::Sub => 6,
+        BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 7,
+    }
+}
+
+struct Parser {
+    tokens: Vec<Located>,
+    index: usize,
+    spans: Vec<Span>,
+    // The end of the last token consumed.
+    end: Position,
+}
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, ParseError> {
+        let tokens = tokenize(source)?;
+        let end = tokens[0].start();
+        Ok(Parser { tokens, index: 0, spans: Vec::new(), end })
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index].token
+    }
+
+    fn advance(&mut self) -> Located {
+        let located = self.tokens[self.index].clone();
+        if self.index + 1 < self.tokens.len() {
+            self.index += 1;
+        }
+        self.end = located.end();
+        located
+    }
+
+    fn start(&self) -> Position {
+        self.tokens[self.index].start()
+    }
+
+    // Records the span of a node that began at `start` and has just been
+    // parsed.
+    fn finish(&mut self, start: Position) {
+        self.spans.push(Span { start, end: self.end });
+    }
+
+    fn error(&self, expected: &'static str) -> ParseError {
+        let located = &self.tokens[self.index];
+        ParseError {
+            line: located.line,
+            column: located.column,
+            kind: ParseErrorKind::Unexpected {
+                expected,
+                found: located.token.describe(),
+            },
+        }
+    }
+
+    fn expect(&mut self, token: Token, expected: &'static str) -> Result<(), ParseError> {
+        if *self.peek() != token {
+            return Err(self.error(expected));
+        }
+        self.advance();
+        Ok(())
+    }
+
+    fn variable(&mut self) -> Result<Variable, ParseError> {
+        if let Token::Identifier(name) = self.peek().clone() {
+            self.advance();
+            Ok(Variable(name))
+        } else {
+            Err(self.error("a variable"))
+        }
+    }
+
+    fn program(&mut self) -> Result<Spec, ParseError> {
+        let start = self.start();
+        let mut statements = Vec::new();
+        while *self.peek() != Token::EndOfInput {
+            statements.push(self.statement()?);
+        }
+        self.finish(start);
+        Ok(Spec::Block(statements))
+    }
+
+    fn block(&mut self) -> Result<Spec, ParseError> {
+        let start = self.start();
+        self.expect(Token::LeftBrace, "`{`")?;
+        let mut statements = Vec::new();
+        while *self.peek() != Token::RightBrace {
+            if *self.peek() == Token::EndOfInput {
+                return Err(self.error("`}`"));
+            }
+            statements.push(self.statement()?);
+        }
+        self.advance();
+        self.finish(start);
+        Ok(Spec::Block(statements))
+    }
+
+    fn statement(&mut self) -> Result<Spec, ParseError> {
+        let start = self.start();
+        match self.peek() {
+            Token::LeftBrace => self.block(),
+            Token::For => {
+                self.advance();
+                let variable = self.variable()?;
+                self.expect(Token::In, "`in`")?;
+                let lower = self.expr()?;
+                self.expect(Token::InclusiveRange, "`..=`")?;
+                let upper = self.expr()?;
+                let body = self.block()?;
+                self.finish(start);
+                Ok(Spec::For(variable, lower, upper, Box::new(body)))
+            },
+            Token::If => self.if_statement(),
+            Token::Return => {
+                self.advance();
+                let variable = self.variable()?;
+                self.expect(Token::Semicolon, "`;`")?;
+                self.finish(start);
+                Ok(Spec::Return(variable))
+            },
+            Token::Identifier(_) => {
+                let variable = self.variable()?;
+                self.expect(Token::Assign, "`=`")?;
+                let expr = self.expr()?;
+                self.expect(Token::Semicolon, "`;`")?;
+                self.finish(start);
+                Ok(Spec::Assign(variable, expr))
+            },
+            _ => Err(self.error("a statement")),
+        }
+    }
+
+    fn if_statement(&mut self) -> Result<Spec, ParseError> {
+        let start = self.start();
+        self.expect(Token::If, "`if`")?;
+        let condition = self.expr()?;
+        let if_true = self.block()?;
+        let if_false = if *self.peek() == Token::Else {
+            self.advance();
+            if *self.peek() == Token::If {
+                self.if_statement()?
+            } else {
+                self.block()?
+            }
+        } else {
+            self.spans.push(Span { start: self.end, end: self.end });
+            Spec::Block(Vec::new())
+        };
+        self.finish(start);
+        Ok(Spec::If(condition, Box::new(if_true), Box::new(if_false)))
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        self.binary_expr(1)
+    }
+
+    // Precedence climbing over the operators that bind at least as tightly
+    // as `minimum_precedence`.
+    fn binary_expr(&mut self, minimum_precedence: u8) -> Result<Expr, ParseError> {
+        let start = self.start();
+        let mut lhs = self.unary_expr()?;
+        while let Token::Binary(op) = *self.peek() {
+            if precedence(op) < minimum_precedence {
+                break;
+            }
+            self.advance();
+            let rhs = self.binary_expr(precedence(op) + 1)?;
+            lhs = Expr::binary(op, lhs, rhs);
+            self.finish(start);
+            if let Token::Binary(next) = *self.peek() {
+                if op.is_comparison() && next.is_comparison() {
+                    let located = &self.tokens[self.index];
+                    return Err(ParseError {
+                        line: located.line,
+                        column: located.column,
+                        kind: ParseErrorKind::ChainedComparison,
+                    });
+                }
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn unary_expr(&mut self) -> Result<Expr, ParseError> {
+        let start = self.start();
+        match self.peek() {
+            Token::Not => {
+                self.advance();
+                let x = self.unary_expr()?;
+                self.finish(start);
+                Ok(Expr::Not(Box::new(x)))
+            },
+            Token::Number(value) => {
+                let value = *value;
+                self.advance();
+                self.finish(start);
+                Ok(Expr::Const(value))
+            },
+            Token::LeftParen => {
+                self.advance();
+                let expr = self.expr()?;
+                self.expect(Token::RightParen, "`)`")?;
+                Ok(expr)
+            },
+            Token::Identifier(_) => {
+                let variable = self.variable()?;
+                if *self.peek() == Token::LeftParen {
+                    let call = self.call(variable.0)?;
+                    self.finish(start);
+                    Ok(call)
+                } else {
+                    self.finish(start);
+                    Ok(Expr::Var(variable))
+                }
+            },
+            _ => Err(self.error("an expression")),
+        }
+    }
+
+    fn call(&mut self, name: String) -> Result<Expr, ParseError> {
+        self.expect(Token::LeftParen, "`(`")?;
+        let mut args = Vec::new();
+        while *self.peek() != Token::RightParen {
+            args.push(self.expr()?);
+            if *self.peek() != Token::Comma {
+                break;
+            }
+            self.advance();
+        }
+        self.expect(Token::RightParen, "`,` or `)`")?;
+        Ok(Expr::Call(name, args))
+    }
+}
+
+/// Parses a whole spell. The statements are wrapped in a top-level
+/// `Spec::Block`, which is what `printer::print_program` expects back.
+pub fn parse(source: &str) -> Result<Spec, ParseError> {
+    parse_with_spans(source).map(|(spec, _)| spec)
+}
+
+/// Like `parse`, but also returns where each node came from.
+pub fn parse_with_spans(source: &str) -> Result<(Spec, SpanTable), ParseError> {
+    let mut parser = Parser::new(source)?;
+    let spec = parser.program()?;
+    Ok((spec, SpanTable { spans: parser.spans }))
+}
+
+pub fn parse_expr(source: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser::new(source)?;
+    let expr = parser.expr()?;
+    parser.expect(Token::EndOfInput, "end of input")?;
+    Ok(expr)
+}
+
+pub fn parse_document(document: &Document) -> Result<Spec, ParseError> {
+    parse(&document.contents())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_at(source: &str) -> (usize, usize, ParseErrorKind) {
+        let error = parse(source).unwrap_err();
+        (error.line, error.column, error.kind)
+    }
+
+    #[test]
+    fn example_in_the_header_parses() {
+        let header: String = include_str!("parser.rs").lines()
+            .skip(2)
+            .take_while(|line| line.starts_with("//    "))
+            .map(|line| format!("{}\n", &line[2..]))
+            .collect();
+        assert!(header.contains("popcount(x) * 3"));
+        parse(&header).unwrap();
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        assert_eq!(error_at("x = 1;\ny = $;"), (2, 5, ParseErrorKind::UnexpectedCharacter('$')));
+        assert_eq!(error_at("x = 0x;"), (1, 5, ParseErrorKind::InvalidNumber("0x".to_string())));
+        assert_eq!(error_at("y = a < b < c;"), (1, 11, ParseErrorKind::ChainedComparison));
+        assert_eq!(error_at("for i in 0 .. 3 { }"), (1, 12, ParseErrorKind::UnexpectedCharacter('.')));
+        assert_eq!(error_at("// fine\n  return x"), (2, 11, ParseErrorKind::Unexpected {
+            expected: "`;`",
+            found: "end of input".to_string(),
+        }));
+        assert_eq!(error_at("if x {\n    y = 1;\n"), (3, 1, ParseErrorKind::Unexpected {
+            expected: "`}`",
+            found: "end of input".to_string(),
+        }));
+        assert_eq!(error_at("return x + 1;"), (1, 10, ParseErrorKind::Unexpected {
+            expected: "`;`",
+            found: "`+`".to_string(),
+        }));
+    }
+
+    #[test]
+    fn spans_cover_their_nodes() {
+        let (_, spans) = parse_with_spans("y = x + 1;\nreturn y;").unwrap();
+        let span = |line, column, end_line, end_column| Span {
+            start: Position { line, column },
+            end: Position { line: end_line, column: end_column },
+        };
+        // `x`, `1`, `x + 1`, the assignment, the return and the program.
+        assert_eq!(spans.spans, vec![
+            span(1, 5, 1, 6),
+            span(1, 9, 1, 10),
+            span(1, 5, 1, 10),
+            span(1, 1, 1, 11),
+            span(2, 1, 2, 10),
+            span(1, 1, 2, 10),
+        ]);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::magic::{Expr, Spec, Variable};
+use crate::magic::parser::precedence;
+use std::fmt;
+
+const INDENT: &str = "    ";
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Expr {
+    // Higher binds tighter; binary operators use the parser's precedences.
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Not(_) => 8,
+            Expr::Var(_) | Expr::Const(_) | Expr::Call(_, _) => 9,
+            _ => precedence(self.as_binary().unwrap().0),
+        }
+    }
+}
+
+fn write_operand(
+    f: &mut fmt::Formatter,
+    expr: &Expr,
+    minimum_precedence: u8,
+) -> fmt::Result {
+    if expr.precedence() < minimum_precedence {
+        write!(f, "({})", expr)
+    } else {
+        write!(f, "{}", expr)
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Var(ref var) => write!(f, "{}", var),
+            Expr::Const(value) => write!(f, "{}", value),
+            Expr::Call(ref name, ref args) => {
+                write!(f, "{}(", name)?;
+                for (i, arg) in args.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", arg)?;
+                }
+                write!(f, ")")
+            },
+            Expr::Not(ref x) => {
+                write!(f, "~")?;
+                write_operand(f, x, self.precedence())
+            },
+            _ => {
+                let (op, x, y) = self.as_binary().unwrap();
+                // Binary operators are left associative, so only the right
+                // operand needs parentheses at equal precedence. Comparisons
+                // don't associate at all.
+                let left_precedence = if op.is_comparison() {
+                    self.precedence() + 1
+                } else {
+                    self.precedence()
+                };
+                write_operand(f, x, left_precedence)?;
+                write!(f, " {} ", op.symbol())?;
+                write_operand(f, y, self.precedence() + 1)
+            },
+        }
+    }
+}
+
+fn write_indent(f: &mut fmt::Formatter, depth: usize) -> fmt::Result {
+    write!(f, "{}", INDENT.repeat(depth))
+}
+
+fn write_statements(
+    f: &mut fmt::Formatter,
+    specs: &[Spec],
+    depth: usize,
+) -> fmt::Result {
+    for spec in specs {
+        write_indent(f, depth)?;
+        write_spec(f, spec, depth)?;
+        writeln!(f)?;
+    }
+    Ok(())
+}
+
+// Writes `spec` as a braced block, wrapping it if it isn't a block already.
+fn write_block(f: &mut fmt::Formatter, spec: &Spec, depth: usize) -> fmt::Result {
+    let specs = match spec {
+        Spec::Block(ref specs) => &specs[..],
+        _ => std::slice::from_ref(spec),
+    };
+    if specs.is_empty() {
+        return write!(f, "{{}}");
+    }
+    writeln!(f, "{{")?;
+    write_statements(f, specs, depth + 1)?;
+    write_indent(f, depth)?;
+    write!(f, "}}")
+}
+
+// Writes a single statement, assuming the cursor is already indented.
+fn write_spec(f: &mut fmt::Formatter, spec: &Spec, depth: usize) -> fmt::Result {
+    match spec {
+        Spec::Assign(ref var, ref expr) => write!(f, "{} = {};", var, expr),
+        Spec::Block(_) => write_block(f, spec, depth),
+        Spec::For(ref var, ref lower, ref upper, ref body) => {
+            write!(f, "for {} in {} ..= {} ", var, lower, upper)?;
+            write_block(f, body, depth)
+        },
+        Spec::If(ref cond, ref if_true, ref if_false) => {
+            write!(f, "if {} ", cond)?;
+            write_block(f, if_true, depth)?;
+            match **if_false {
+                Spec::Block(ref specs) if specs.is_empty() => Ok(()),
+                Spec::If(_, _, _) => {
+                    write!(f, " else ")?;
+                    write_spec(f, if_false, depth)
+                },
+                _ => {
+                    write!(f, " else ")?;
+                    write_block(f, if_false, depth)
+                },
+            }
+        },
+        Spec::Return(ref var) => write!(f, "return {};", var),
+    }
+}
+
+impl fmt::Display for Spec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_spec(f, self, 0)
+    }
+}
+
+struct Program<'a>(&'a Spec);
+
+impl fmt::Display for Program<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Spec::Block(ref specs) => write_statements(f, specs, 0),
+            spec => writeln!(f, "{}", spec),
+        }
+    }
+}
+
+/// Pretty-prints a whole spell. This is the inverse of `parser::parse`: the
+/// top-level block is printed without braces, so that
+/// `parse(&print_program(&spec)) == Ok(spec)` for any parsed `spec`.
+pub fn print_program(spec: &Spec) -> String {
+    Program(spec).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::Value;
+    use crate::magic::parser::{parse, parse_expr};
+    use crate::magic::testing::Generator;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    const EXPRESSIONS: Generator = Generator {
+        names: &["x", "k", "i"],
+        constants: &[0, 1, 31, Value::MAX],
+        calls: &[("min", 2)],
+        dynamic_bounds: true,
+    };
+
+    #[test]
+    fn expressions_round_trip() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        for _ in 0 .. 2000 {
+            let expr = EXPRESSIONS.expr(&mut rng, 4);
+            assert_eq!(parse_expr(&expr.to_string()), Ok(expr.clone()), "{}", expr);
+        }
+    }
+
+    #[test]
+    fn precedence_decides_parentheses() {
+        let printed = |source: &str| parse_expr(source).unwrap().to_string();
+        assert_eq!(printed("(a + b) * c"), "(a + b) * c");
+        assert_eq!(printed("a + (b * c)"), "a + b * c");
+        assert_eq!(printed("a - (b - c)"), "a - (b - c)");
+        assert_eq!(printed("(a - b) - c"), "a - b - c");
+        assert_eq!(printed("(a < b) == c"), "(a < b) == c");
+        assert_eq!(printed("~(a | b) & c"), "~(a | b) & c");
+        assert_eq!(printed("a << b + c"), "a << b + c");
+    }
+
+    #[test]
+    fn programs_round_trip() {
+        let spells = [
+            "",
+            "return x;",
+            "y = x ^ (x >> k); return y;",
+            "{ { y = 1; } { } } return y;",
+            "for i in 0 ..= 31 { c = c + ((x >> i) & 1); for j in i ..= min(i, 3) { } } return c;",
+            "if x < 2 { y = 1; } else if x < 4 { y = 2; } else { if x == 5 { } y = 3; } return y;",
+            "if x { } else { } if y { { z = 1; } } return z;",
+        ];
+        for source in spells {
+            let spec = parse(source).unwrap();
+            let printed = print_program(&spec);
+            assert_eq!(parse(&printed), Ok(spec), "{}", printed);
+            assert_eq!(print_program(&parse(&printed).unwrap()), printed);
+        }
+    }
+}
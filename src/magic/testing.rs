@@ -0,0 +1,72 @@
+// Random spells for the differential tests, which check that every way of
+// running a spell agrees with `mana::interpret_metered`.
+
+use crate::magic::{BinaryOp, Expr, Spec, Value, Variable};
+use rand::Rng;
+use rand::seq::SliceRandom;
+use rand_chacha::ChaCha8Rng;
+
+pub(crate) const OPS: &[BinaryOp] = &[
+    BinaryOp::Or, BinaryOp::And, BinaryOp::Xor, BinaryOp::ShiftLeft,
+    BinaryOp::ShiftRight, BinaryOp::Add, BinaryOp::Sub, BinaryOp::Mul,
+    BinaryOp::Div, BinaryOp::Mod, BinaryOp::Eq, BinaryOp::Ne,
+    BinaryOp::Lt, BinaryOp::Le, BinaryOp::Gt, BinaryOp::Ge,
+];
+
+/// What random spells are made of.
+pub(crate) struct Generator {
+    pub names: &'static [&'static str],
+    pub constants: &'static [Value],
+    /// Intrinsics to call, with how many arguments to pass them, which
+    /// needn't be the right number.
+    pub calls: &'static [(&'static str, usize)],
+    /// Whether a loop's upper bound can be an expression, rather than always
+    /// a constant.
+    pub dynamic_bounds: bool,
+}
+
+impl Generator {
+    pub fn expr(&self, rng: &mut ChaCha8Rng, depth: usize) -> Expr {
+        if depth == 0 || rng.gen_bool(0.3) {
+            return if rng.gen_bool(0.5) {
+                Expr::Var(Variable::new(self.names.choose(rng).unwrap()))
+            } else {
+                Expr::Const(*self.constants.choose(rng).unwrap())
+            };
+        }
+        match rng.gen_range(0 .. 10) {
+            0 => Expr::Not(Box::new(self.expr(rng, depth - 1))),
+            1 if !self.calls.is_empty() => {
+                let (name, arity) = *self.calls.choose(rng).unwrap();
+                let args = (0 .. arity).map(|_| self.expr(rng, depth - 1)).collect();
+                Expr::Call(name.to_string(), args)
+            },
+            _ => Expr::binary(
+                *OPS.choose(rng).unwrap(),
+                self.expr(rng, depth - 1),
+                self.expr(rng, depth - 1)),
+        }
+    }
+
+    pub fn spec(&self, rng: &mut ChaCha8Rng, depth: usize) -> Spec {
+        let variable = Variable::new(self.names.choose(rng).unwrap());
+        match rng.gen_range(0 .. if depth == 0 { 2 } else { 6 }) {
+            0 => Spec::Assign(variable, self.expr(rng, 3)),
+            1 => Spec::Return(variable),
+            2 => Spec::Block((0 .. rng.gen_range(0 .. 4)).map(|_| self.spec(rng, depth - 1)).collect()),
+            3 => {
+                let lower = Expr::Const(rng.gen_range(0 .. 3));
+                let upper = if self.dynamic_bounds && rng.gen_bool(0.2) {
+                    self.expr(rng, 2)
+                } else {
+                    Expr::Const(rng.gen_range(0 .. 6))
+                };
+                Spec::For(variable, lower, upper, Box::new(self.spec(rng, depth - 1)))
+            },
+            _ => Spec::If(
+                self.expr(rng, 2),
+                Box::new(self.spec(rng, depth - 1)),
+                Box::new(self.spec(rng, depth - 1))),
+        }
+    }
+}
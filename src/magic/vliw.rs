@@ -0,0 +1,459 @@
+// A cycle-accurate simulator for the VLIW machines described by `VLIW`.
+//
+// Every register is a vector with one `Value` per lane, and each lane op in a
+// bundle operates on all lanes at once. One bundle issues per cycle, in
+// order. Results become visible `latency` cycles after their bundle issues;
+// a bundle that reads or overwrites a register whose result isn't ready yet
+// stalls until it is.
+
+use crate::magic::{Bundle, CrossLaneInstruction, CrossLaneOp, LaneInstruction};
+use crate::magic::{LaneOp, Operand, Value, VLIW};
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+pub type Cycles = u64;
+
+#[derive(Clone, Debug, PartialEq, Eq, Error)]
+pub enum VliwError {
+    #[error("a machine needs at least one lane and one register")]
+    EmptyMachine,
+    #[error("bundle {bundle} issues {found} lane ops, but only {width} fit in a bundle")]
+    TooManyLaneOps { bundle: usize, found: usize, width: usize },
+    #[error("bundle {bundle} uses {op:?}, which this machine doesn't have")]
+    LaneOpNotAllowed { bundle: usize, op: LaneOp },
+    #[error("bundle {bundle} uses {op:?}, which this machine doesn't have")]
+    CrossLaneOpNotAllowed { bundle: usize, op: CrossLaneOp },
+    #[error("bundle {bundle} uses register {register}, but there are only {count}")]
+    RegisterOutOfRange { bundle: usize, register: usize, count: usize },
+    #[error("bundle {bundle} writes register {register} more than once")]
+    DuplicateDestination { bundle: usize, register: usize },
+    #[error("{found} copies of the spell don't fit in {lanes} lanes")]
+    TooManyContexts { found: usize, lanes: usize },
+    #[error("division by zero in lane {lane} of bundle {bundle}")]
+    DivisionByZero { bundle: usize, lane: usize },
+}
+
+impl LaneOp {
+    pub const ALL: &'static [LaneOp] = &[
+        LaneOp::Or,
+        LaneOp::And,
+        LaneOp::Xor,
+        LaneOp::Not,
+        LaneOp::Shift,
+        LaneOp::Add,
+        LaneOp::Negate,
+        LaneOp::Subtract,
+        LaneOp::Multiply,
+        LaneOp::Divide,
+        LaneOp::Remainder,
+        LaneOp::Equal,
+        LaneOp::LessThan,
+    ];
+
+    pub fn is_unary(self) -> bool {
+        matches!(self, LaneOp::Not | LaneOp::Negate)
+    }
+
+    pub fn latency(self) -> Cycles {
+        match self {
+            LaneOp::Multiply => 3,
+            LaneOp::Divide | LaneOp::Remainder => 10,
+            _ => 1,
+        }
+    }
+
+    /// Applies the op to a single lane. `None` means division by zero.
+    pub fn apply(self, x: Value, y: Value) -> Option<Value> {
+        Some(match self {
+            LaneOp::Or => x | y,
+            LaneOp::And => x & y,
+            LaneOp::Xor => x ^ y,
+            LaneOp::Not => !x,
+            LaneOp::Shift => {
+                let amount = y as i32;
+                let magnitude = amount.unsigned_abs();
+                if magnitude >= Value::BITS {
+                    0
+                } else if amount >= 0 {
+                    x << magnitude
+                } else {
+                    x >> magnitude
+                }
+            },
+            LaneOp::Add => x.wrapping_add(y),
+            LaneOp::Negate => x.wrapping_neg(),
+            LaneOp::Subtract => x.wrapping_sub(y),
+            LaneOp::Multiply => x.wrapping_mul(y),
+            LaneOp::Divide => x.checked_div(y)?,
+            LaneOp::Remainder => x.checked_rem(y)?,
+            LaneOp::Equal => (x == y) as Value,
+            LaneOp::LessThan => (x < y) as Value,
+        })
+    }
+}
+
+impl CrossLaneOp {
+    pub const ALL: &'static [CrossLaneOp] = &[
+        CrossLaneOp::Rotate,
+        CrossLaneOp::AndReduce,
+        CrossLaneOp::OrReduce,
+        CrossLaneOp::AddReduce,
+        CrossLaneOp::XorReduce,
+    ];
+
+    /// Reductions are done as a tree, so they take longer on wider machines.
+    pub fn latency(self, number_of_lanes: usize) -> Cycles {
+        match self {
+            CrossLaneOp::Rotate => 1,
+            _ => {
+                let depth = usize::BITS - number_of_lanes.saturating_sub(1).leading_zeros();
+                Cycles::from(depth.max(1))
+            },
+        }
+    }
+}
+
+impl VLIW {
+    pub fn new(
+        number_of_registers: usize,
+        number_of_lanes: usize,
+        issue_width: usize,
+        lane_ops: BTreeSet<LaneOp>,
+        cross_lane_ops: BTreeSet<CrossLaneOp>,
+    ) -> Result<Self, VliwError> {
+        let vliw = VLIW {
+            number_of_registers,
+            number_of_lanes,
+            issue_width,
+            lane_ops,
+            cross_lane_ops,
+        };
+        vliw.check()?;
+        Ok(vliw)
+    }
+
+    /// A machine with every op available.
+    pub fn with_all_ops(
+        number_of_registers: usize,
+        number_of_lanes: usize,
+        issue_width: usize,
+    ) -> Result<Self, VliwError> {
+        VLIW::new(
+            number_of_registers,
+            number_of_lanes,
+            issue_width,
+            LaneOp::ALL.iter().cloned().collect(),
+            CrossLaneOp::ALL.iter().cloned().collect())
+    }
+
+    /// Checks that the machine can run anything at all. The fields are public,
+    /// so machines built without `new` are checked again before running.
+    pub fn check(&self) -> Result<(), VliwError> {
+        if self.number_of_lanes == 0 || self.number_of_registers == 0 {
+            return Err(VliwError::EmptyMachine);
+        }
+        Ok(())
+    }
+
+    /// Checks that every bundle fits this machine.
+    pub fn validate(&self, program: &[Bundle]) -> Result<(), VliwError> {
+        self.check()?;
+        for (index, bundle) in program.iter().enumerate() {
+            if bundle.lane_ops.len() > self.issue_width {
+                return Err(VliwError::TooManyLaneOps {
+                    bundle: index,
+                    found: bundle.lane_ops.len(),
+                    width: self.issue_width,
+                });
+            }
+            let mut registers = BTreeSet::new();
+            for instruction in &bundle.lane_ops {
+                if !self.lane_ops.contains(&instruction.op) {
+                    return Err(VliwError::LaneOpNotAllowed {
+                        bundle: index,
+                        op: instruction.op,
+                    });
+                }
+                registers.extend(instruction.registers());
+            }
+            if let Some(ref instruction) = bundle.cross_lane_op {
+                if !self.cross_lane_ops.contains(&instruction.op) {
+                    return Err(VliwError::CrossLaneOpNotAllowed {
+                        bundle: index,
+                        op: instruction.op,
+                    });
+                }
+                registers.insert(instruction.destination);
+                registers.insert(instruction.source);
+            }
+            let mut destinations = BTreeSet::new();
+            let cross_lane_destination = bundle.cross_lane_op.as_ref()
+                .map(|instruction| instruction.destination);
+            for register in bundle.lane_ops.iter()
+                .map(|instruction| instruction.destination)
+                .chain(cross_lane_destination)
+            {
+                if !destinations.insert(register) {
+                    return Err(VliwError::DuplicateDestination { bundle: index, register });
+                }
+            }
+            if let Some(&register) = registers.range(self.number_of_registers ..).next() {
+                return Err(VliwError::RegisterOutOfRange {
+                    bundle: index,
+                    register,
+                    count: self.number_of_registers,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl LaneInstruction {
+    /// The registers this instruction reads.
+    pub fn sources(&self) -> impl Iterator<Item = usize> + '_ {
+        let rhs = if self.op.is_unary() { None } else { Some(&self.rhs) };
+        std::iter::once(&self.lhs).chain(rhs).filter_map(|operand| match operand {
+            Operand::Register(register) => Some(*register),
+            Operand::Immediate(_) => None,
+        })
+    }
+
+    fn registers(&self) -> impl Iterator<Item = usize> + '_ {
+        self.sources().chain(std::iter::once(self.destination))
+    }
+}
+
+pub struct Machine<'a> {
+    vliw: &'a VLIW,
+    registers: Vec<Vec<Value>>,
+    // The cycle at which each register's pending result becomes readable.
+    ready_at: Vec<Cycles>,
+    // The cycle at which the next bundle may issue.
+    cycle: Cycles,
+}
+
+impl<'a> Machine<'a> {
+    /// A machine with every register zeroed.
+    pub fn new(vliw: &'a VLIW) -> Self {
+        Machine {
+            vliw,
+            registers: vec![vec![0; vliw.number_of_lanes]; vliw.number_of_registers],
+            ready_at: vec![0; vliw.number_of_registers],
+            cycle: 0,
+        }
+    }
+
+    pub fn register(&self, register: usize) -> &[Value] {
+        &self.registers[register]
+    }
+
+    /// Panics if `values` doesn't have one entry per lane.
+    pub fn set_register(&mut self, register: usize, values: &[Value]) {
+        self.registers[register].copy_from_slice(values);
+    }
+
+    /// The number of cycles until every issued bundle has finished.
+    pub fn cycles(&self) -> Cycles {
+        self.ready_at.iter().cloned().fold(self.cycle, Cycles::max)
+    }
+
+    fn operand(&self, operand: &Operand, lane: usize) -> Value {
+        match operand {
+            Operand::Register(register) => self.registers[*register][lane],
+            Operand::Immediate(value) => *value,
+        }
+    }
+
+    fn lane_op(
+        &self,
+        index: usize,
+        instruction: &LaneInstruction,
+    ) -> Result<Vec<Value>, VliwError> {
+        (0 .. self.vliw.number_of_lanes)
+            .map(|lane| {
+                let x = self.operand(&instruction.lhs, lane);
+                let y = self.operand(&instruction.rhs, lane);
+                instruction.op.apply(x, y)
+                    .ok_or(VliwError::DivisionByZero { bundle: index, lane })
+            })
+            .collect()
+    }
+
+    fn cross_lane_op(&self, instruction: &CrossLaneInstruction) -> Vec<Value> {
+        let source = &self.registers[instruction.source];
+        let lanes = source.len();
+        let reduce = |identity: Value, f: fn(Value, Value) -> Value| {
+            vec![source.iter().cloned().fold(identity, f); lanes]
+        };
+        match instruction.op {
+            CrossLaneOp::Rotate => {
+                let mut result = vec![0; lanes];
+                for (lane, value) in source.iter().enumerate() {
+                    result[(lane + instruction.amount) % lanes] = *value;
+                }
+                result
+            },
+            CrossLaneOp::AndReduce => reduce(Value::MAX, |x, y| x & y),
+            CrossLaneOp::OrReduce => reduce(0, |x, y| x | y),
+            CrossLaneOp::AddReduce => reduce(0, Value::wrapping_add),
+            CrossLaneOp::XorReduce => reduce(0, |x, y| x ^ y),
+        }
+    }
+
+    /// Issues one bundle, stalling first if any register it touches is still
+    /// waiting on an earlier result. `index` is only used in errors. The
+    /// bundle is assumed to have passed `VLIW::validate`.
+    pub fn step(&mut self, index: usize, bundle: &Bundle) -> Result<(), VliwError> {
+        let mut touched: Vec<usize> = Vec::new();
+        for instruction in &bundle.lane_ops {
+            touched.extend(instruction.registers());
+        }
+        if let Some(ref instruction) = bundle.cross_lane_op {
+            touched.push(instruction.source);
+            touched.push(instruction.destination);
+        }
+        let issue = touched.iter()
+            .map(|register| self.ready_at[*register])
+            .fold(self.cycle, Cycles::max);
+
+        // Read everything before writing anything.
+        let mut writes = Vec::new();
+        for instruction in &bundle.lane_ops {
+            let latency = instruction.op.latency();
+            writes.push((instruction.destination, latency, self.lane_op(index, instruction)?));
+        }
+        if let Some(ref instruction) = bundle.cross_lane_op {
+            let latency = instruction.op.latency(self.vliw.number_of_lanes);
+            writes.push((instruction.destination, latency, self.cross_lane_op(instruction)));
+        }
+        for (register, latency, values) in writes {
+            self.registers[register] = values;
+            self.ready_at[register] = issue + latency;
+        }
+        self.cycle = issue + 1;
+        Ok(())
+    }
+
+    /// Runs `program` to completion and returns the total cycle count,
+    /// including stalls and the time for the last results to land.
+    pub fn run(&mut self, program: &[Bundle]) -> Result<Cycles, VliwError> {
+        self.vliw.validate(program)?;
+        for (index, bundle) in program.iter().enumerate() {
+            self.step(index, bundle)?;
+        }
+        Ok(self.cycles())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lane(op: LaneOp, destination: usize, lhs: Operand, rhs: Operand) -> LaneInstruction {
+        LaneInstruction { op, destination, lhs, rhs }
+    }
+
+    fn bundle(lane_ops: Vec<LaneInstruction>) -> Bundle {
+        Bundle { lane_ops, cross_lane_op: None }
+    }
+
+    #[test]
+    fn stalls_until_results_are_ready() {
+        let vliw = VLIW::with_all_ops(2, 4, 2).unwrap();
+        let mut machine = Machine::new(&vliw);
+        machine.set_register(0, &[1, 2, 3, 4]);
+        let program = [
+            bundle(vec![lane(LaneOp::Add, 0, Operand::Register(0), Operand::Immediate(5))]),
+            // Waits a cycle for r0, then takes three.
+            bundle(vec![lane(LaneOp::Multiply, 1, Operand::Register(0), Operand::Immediate(3))]),
+            // Waits for r1.
+            bundle(vec![lane(LaneOp::Add, 0, Operand::Register(1), Operand::Immediate(1))]),
+        ];
+        assert_eq!(machine.run(&program), Ok(5));
+        assert_eq!(machine.register(1), &[18, 21, 24, 27]);
+        assert_eq!(machine.register(0), &[19, 22, 25, 28]);
+    }
+
+    #[test]
+    fn bundles_read_before_writing() {
+        let vliw = VLIW::with_all_ops(2, 2, 2).unwrap();
+        let mut machine = Machine::new(&vliw);
+        machine.set_register(0, &[1, 2]);
+        machine.set_register(1, &[3, 4]);
+        let swap = bundle(vec![
+            lane(LaneOp::Or, 0, Operand::Register(1), Operand::Immediate(0)),
+            lane(LaneOp::Or, 1, Operand::Register(0), Operand::Immediate(0)),
+        ]);
+        assert_eq!(machine.run(&[swap]), Ok(1));
+        assert_eq!(machine.register(0), &[3, 4]);
+        assert_eq!(machine.register(1), &[1, 2]);
+    }
+
+    #[test]
+    fn cross_lane_ops() {
+        let vliw = VLIW::with_all_ops(2, 4, 1).unwrap();
+        let run = |op, amount| {
+            let mut machine = Machine::new(&vliw);
+            machine.set_register(0, &[1, 2, 4, 8]);
+            let program = [Bundle {
+                lane_ops: Vec::new(),
+                cross_lane_op: Some(CrossLaneInstruction { op, destination: 1, source: 0, amount }),
+            }];
+            let cycles = machine.run(&program).unwrap();
+            (machine.register(1).to_vec(), cycles)
+        };
+        assert_eq!(run(CrossLaneOp::Rotate, 1), (vec![8, 1, 2, 4], 1));
+        assert_eq!(run(CrossLaneOp::Rotate, 6), (vec![4, 8, 1, 2], 1));
+        assert_eq!(run(CrossLaneOp::AddReduce, 0), (vec![15; 4], 2));
+        assert_eq!(run(CrossLaneOp::AndReduce, 0), (vec![0; 4], 2));
+        assert_eq!(run(CrossLaneOp::XorReduce, 0), (vec![15; 4], 2));
+    }
+
+    #[test]
+    fn shifts_saturate_and_division_by_zero_fails() {
+        assert_eq!(LaneOp::Shift.apply(1, 31), Some(1 << 31));
+        assert_eq!(LaneOp::Shift.apply(1, 32), Some(0));
+        assert_eq!(LaneOp::Shift.apply(8, (-2i32) as Value), Some(2));
+        assert_eq!(LaneOp::Remainder.apply(7, 0), None);
+
+        let vliw = VLIW::with_all_ops(1, 2, 1).unwrap();
+        let mut machine = Machine::new(&vliw);
+        machine.set_register(0, &[1, 0]);
+        let divide = bundle(vec![lane(LaneOp::Divide, 0, Operand::Immediate(6), Operand::Register(0))]);
+        assert_eq!(machine.run(&[divide]), Err(VliwError::DivisionByZero { bundle: 0, lane: 1 }));
+    }
+
+    #[test]
+    fn rejects_programs_that_dont_fit() {
+        assert_eq!(VLIW::with_all_ops(1, 0, 1), Err(VliwError::EmptyMachine));
+        assert_eq!(VLIW::with_all_ops(0, 1, 1), Err(VliwError::EmptyMachine));
+        let empty = VLIW { number_of_lanes: 0, ..VLIW::with_all_ops(1, 1, 1).unwrap() };
+        assert_eq!(Machine::new(&empty).run(&[]), Err(VliwError::EmptyMachine));
+
+        let mut vliw = VLIW::with_all_ops(2, 2, 2).unwrap();
+        vliw.lane_ops.remove(&LaneOp::Multiply);
+        let add = |destination| lane(LaneOp::Add, destination, Operand::Register(0), Operand::Immediate(1));
+        assert_eq!(
+            vliw.validate(&[bundle(vec![add(0), add(1), add(1)])]),
+            Err(VliwError::TooManyLaneOps { bundle: 0, found: 3, width: 2 }));
+        assert_eq!(
+            vliw.validate(&[bundle(vec![add(0)]), bundle(vec![add(0), add(0)])]),
+            Err(VliwError::DuplicateDestination { bundle: 1, register: 0 }));
+        assert_eq!(
+            vliw.validate(&[bundle(vec![add(2)])]),
+            Err(VliwError::RegisterOutOfRange { bundle: 0, register: 2, count: 2 }));
+        assert_eq!(
+            vliw.validate(&[bundle(vec![lane(LaneOp::Multiply, 0, Operand::Register(0), Operand::Register(1))])]),
+            Err(VliwError::LaneOpNotAllowed { bundle: 0, op: LaneOp::Multiply }));
+        let rotate_into_lane_op = Bundle {
+            lane_ops: vec![add(1)],
+            cross_lane_op: Some(CrossLaneInstruction {
+                op: CrossLaneOp::Rotate, destination: 1, source: 0, amount: 1,
+            }),
+        };
+        assert_eq!(
+            vliw.validate(&[rotate_into_lane_op]),
+            Err(VliwError::DuplicateDestination { bundle: 0, register: 1 }));
+    }
+}
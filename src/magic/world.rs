@@ -0,0 +1,233 @@
+// Questions a spell can ask about the world it's cast into, such as how much
+// armor its target has. Unlike intrinsics they're always available, but they
+// cost a lot more. A spell that isn't cast at anything, like one being
+// checked against puzzles, sees `NOWHERE`.
+
+use crate::magic::{StatusEffect, Value};
+use crate::magic::mana::Mana;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum WorldQuery {
+    TargetArmor,
+    TargetHealth,
+    TargetDistance,
+    /// Takes the index of a status effect in `StatusEffect::ALL`.
+    TargetHasStatus,
+    /// Takes a radius.
+    NearbyEnemies,
+}
+
+impl WorldQuery {
+    pub const ALL: &'static [WorldQuery] = &[
+        WorldQuery::TargetArmor,
+        WorldQuery::TargetHealth,
+        WorldQuery::TargetDistance,
+        WorldQuery::TargetHasStatus,
+        WorldQuery::NearbyEnemies,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WorldQuery::TargetArmor => "target_armor",
+            WorldQuery::TargetHealth => "target_health",
+            WorldQuery::TargetDistance => "target_distance",
+            WorldQuery::TargetHasStatus => "target_has_status",
+            WorldQuery::NearbyEnemies => "nearby_enemies",
+        }
+    }
+
+    pub fn arity(self) -> usize {
+        match self {
+            WorldQuery::TargetHasStatus | WorldQuery::NearbyEnemies => 1,
+            _ => 0,
+        }
+    }
+}
+
+pub fn lookup(name: &str) -> Option<WorldQuery> {
+    WorldQuery::ALL.iter().cloned().find(|query| query.name() == name)
+}
+
+/// Mana charged per query, on top of evaluating the arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueryCosts {
+    pub target_armor: Mana,
+    pub target_health: Mana,
+    pub target_distance: Mana,
+    pub target_has_status: Mana,
+    pub nearby_enemies: Mana,
+}
+
+impl Default for QueryCosts {
+    fn default() -> Self {
+        QueryCosts {
+            target_armor: 50,
+            target_health: 50,
+            target_distance: 25,
+            target_has_status: 50,
+            nearby_enemies: 100,
+        }
+    }
+}
+
+impl QueryCosts {
+    pub fn get(&self, query: WorldQuery) -> Mana {
+        match query {
+            WorldQuery::TargetArmor => self.target_armor,
+            WorldQuery::TargetHealth => self.target_health,
+            WorldQuery::TargetDistance => self.target_distance,
+            WorldQuery::TargetHasStatus => self.target_has_status,
+            WorldQuery::NearbyEnemies => self.nearby_enemies,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TargetView {
+    pub armor: f32,
+    pub health: f32,
+    pub distance: f32,
+    pub status_effects: BTreeSet<StatusEffect>,
+}
+
+/// What the world looked like from the caster when the spell was cast.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct WorldView {
+    pub target: Option<TargetView>,
+    /// How far away each of the caster's enemies is.
+    pub enemy_distances: Vec<f32>,
+}
+
+/// No target and nobody around.
+pub static NOWHERE: WorldView = WorldView {
+    target: None,
+    enemy_distances: Vec::new(),
+};
+
+impl WorldView {
+    /// Measurements are rounded down. Without a target, its distance is
+    /// `Value::MAX` and everything else about it is 0. Arguments must already
+    /// have been checked against `arity`.
+    pub fn answer(&self, query: WorldQuery, args: &[Value]) -> Value {
+        let target = self.target.as_ref();
+        match query {
+            WorldQuery::TargetArmor => target.map_or(0, |target| target.armor as Value),
+            WorldQuery::TargetHealth => target.map_or(0, |target| target.health as Value),
+            WorldQuery::TargetDistance =>
+                target.map_or(Value::MAX, |target| target.distance as Value),
+            WorldQuery::TargetHasStatus => {
+                let effect = StatusEffect::ALL.get(args[0] as usize);
+                matches!((target, effect), (Some(target), Some(effect))
+                         if target.status_effects.contains(effect)) as Value
+            },
+            WorldQuery::NearbyEnemies => self.enemy_distances.iter()
+                .filter(|distance| **distance <= args[0] as f32)
+                .count() as Value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::{Context, EvalError, Variable};
+    use crate::magic::intrinsics::IntrinsicSet;
+    use crate::magic::mana::{Execution, ManaCosts, SpellError, interpret_in_world};
+    use crate::magic::parser::parse;
+
+    fn world() -> WorldView {
+        WorldView {
+            target: Some(TargetView {
+                armor: 12.9,
+                health: 80.5,
+                distance: 7.2,
+                status_effects: [StatusEffect::Poison, StatusEffect::WallWalking].into_iter().collect(),
+            }),
+            enemy_distances: vec![3.0, 10.5, 20.0],
+        }
+    }
+
+    fn run(source: &str, world: &WorldView, budget: Mana) -> (Execution, Context) {
+        let mut context: Context = [(Variable::new("x"), 1)].into_iter().collect();
+        let spec = parse(source).unwrap();
+        let execution = interpret_in_world(
+            &spec, &mut context, &ManaCosts::default(), &IntrinsicSet::all(), world, budget);
+        (execution, context)
+    }
+
+    #[test]
+    fn answers_questions_about_the_target() {
+        let world = world();
+        assert_eq!(world.answer(WorldQuery::TargetArmor, &[]), 12);
+        assert_eq!(world.answer(WorldQuery::TargetHealth, &[]), 80);
+        assert_eq!(world.answer(WorldQuery::TargetDistance, &[]), 7);
+        assert_eq!(world.answer(WorldQuery::TargetHasStatus, &[1]), 1);
+        assert_eq!(world.answer(WorldQuery::TargetHasStatus, &[0]), 0);
+        assert_eq!(world.answer(WorldQuery::TargetHasStatus, &[10]), 1);
+    }
+
+    #[test]
+    fn status_indices_out_of_range_are_never_present() {
+        let world = world();
+        let count = StatusEffect::ALL.len() as Value;
+        assert_eq!(world.answer(WorldQuery::TargetHasStatus, &[count]), 0);
+        assert_eq!(world.answer(WorldQuery::TargetHasStatus, &[Value::MAX]), 0);
+    }
+
+    #[test]
+    fn counts_enemies_within_the_radius() {
+        let world = world();
+        assert_eq!(world.answer(WorldQuery::NearbyEnemies, &[0]), 0);
+        assert_eq!(world.answer(WorldQuery::NearbyEnemies, &[10]), 1);
+        assert_eq!(world.answer(WorldQuery::NearbyEnemies, &[20]), 3);
+        assert_eq!(NOWHERE.answer(WorldQuery::NearbyEnemies, &[Value::MAX]), 0);
+    }
+
+    #[test]
+    fn nowhere_has_no_target() {
+        assert_eq!(NOWHERE.answer(WorldQuery::TargetArmor, &[]), 0);
+        assert_eq!(NOWHERE.answer(WorldQuery::TargetHealth, &[]), 0);
+        assert_eq!(NOWHERE.answer(WorldQuery::TargetDistance, &[]), Value::MAX);
+        for index in 0 .. StatusEffect::ALL.len() as Value {
+            assert_eq!(NOWHERE.answer(WorldQuery::TargetHasStatus, &[index]), 0);
+        }
+    }
+
+    #[test]
+    fn spells_are_charged_for_each_query() {
+        let costs = QueryCosts::default();
+        let world = world();
+        for (source, query, answer) in [
+            ("y = target_armor();", WorldQuery::TargetArmor, 12),
+            ("y = target_health();", WorldQuery::TargetHealth, 80),
+            ("y = target_distance();", WorldQuery::TargetDistance, 7),
+            ("y = target_has_status(x);", WorldQuery::TargetHasStatus, 1),
+            ("y = nearby_enemies(x);", WorldQuery::NearbyEnemies, 0),
+        ] {
+            let (execution, context) = run(source, &world, 1000);
+            // 1 for the assignment, 1 for the call and 1 for each argument.
+            let nodes = 2 + query.arity() as Mana;
+            assert_eq!(execution, Execution { result: Ok(None), mana_spent: nodes + costs.get(query) },
+                       "{source}");
+            assert_eq!(context.get(&Variable::new("y")), Some(&answer), "{source}");
+        }
+    }
+
+    #[test]
+    fn queries_are_paid_for_before_they_are_answered() {
+        let world = world();
+        // One short of the 100 that nearby_enemies costs, after the
+        // assignment and the call.
+        let (execution, context) = run("y = nearby_enemies(x);", &world, 2 + 99);
+        assert_eq!(execution, Execution { result: Err(SpellError::OutOfMana), mana_spent: 101 });
+        assert_eq!(context.get(&Variable::new("y")), None);
+        // The arguments are evaluated after the query is paid for.
+        let (execution, _) = run("y = target_has_status(nope);", &world, 1000);
+        assert_eq!(execution, Execution {
+            result: Err(EvalError::UndefinedVariable(Variable::new("nope")).into()),
+            mana_spent: 2 + 50 + 1,
+        });
+    }
+}
@@ -30,6 +30,11 @@ pub mod postprocessing;
 pub mod terminal_key;
 pub mod key_translator;
 pub mod magic;
+pub mod puzzle;
+pub mod status_effects;
+pub mod character;
+pub mod damage;
+pub mod casting;
 pub mod level;
 pub mod ui;
 pub mod assets;
@@ -86,6 +91,10 @@ pub fn main() {
         .add_plugin(crate::crt::CrtPlugin)
         .add_plugin(crate::projectile::ProjectilePlugin)
         .add_plugin(crate::enemies::EnemiesPlugin)
+        .add_plugin(crate::status_effects::StatusEffectsPlugin)
+        .add_plugin(crate::character::CharacterPlugin)
+        .add_plugin(crate::damage::DamagePlugin)
+        .add_plugin(crate::casting::CastingPlugin)
         .add_plugin(crate::circles::CirclePlugin)
         .add_plugin(crate::self_destruct::SelfDestructPlugin)
         .add_plugin(crate::importable_shaders::ImportableShadersPlugin)
@@ -604,31 +613,43 @@ fn spawn_level(
 
     use rand::Rng;
     let mut rng = rand::thread_rng();
-    spawn_voxels(rng.gen(), &mut commands, &mut meshes, &mut materials,
+    let seed: u64 = rng.gen();
+    commands.insert_resource(crate::level::LevelSeed(seed));
+    commands.insert_resource(
+        crate::magic::intrinsics::IntrinsicSet::for_run(seed));
+    spawn_voxels(seed, &mut commands, &mut meshes, &mut materials,
                  &Some(image_assets.stone.clone()), &room1, &rooms);
 
-    commands.spawn((
-        Interactable,
-        Item { item_type: ItemType::Potion },
-        PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Cube { size: 0.05 })),
-            material: materials.add(Color::rgb(1.0, 0.2, 0.2).into()),
-            transform: Transform::from_xyz(1.5, 0.75, 1.5),
-            ..default()
-        }))
-        .insert(Collider::cuboid(0.025, 0.025, 0.025))
-        .insert(bevy_mod_outline::OutlineBundle {
-            outline: bevy_mod_outline::OutlineVolume {
-                colour: Color::WHITE,
-                width: 5.0,
+    // Staffs and books have to be found before spells can be bound to them.
+    let pickups = [
+        (ItemType::Potion, Color::rgb(1.0, 0.2, 0.2), Vec3::new(1.5, 0.75, 1.5)),
+        (ItemType::Staff, Color::rgb(0.6, 0.4, 0.2), Vec3::new(2.0, 0.75, 1.5)),
+        (ItemType::Book, Color::rgb(0.2, 0.3, 0.8), Vec3::new(2.5, 0.75, 1.5)),
+    ];
+    for (item_type, color, translation) in pickups {
+        commands.spawn((
+            Interactable,
+            Item { item_type },
+            PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Cube { size: 0.05 })),
+                material: materials.add(color.into()),
+                transform: Transform::from_translation(translation),
                 ..default()
-            },
-            stencil: bevy_mod_outline::OutlineStencil {
-                offset: 0.0,
+            }))
+            .insert(Collider::cuboid(0.025, 0.025, 0.025))
+            .insert(bevy_mod_outline::OutlineBundle {
+                outline: bevy_mod_outline::OutlineVolume {
+                    colour: Color::WHITE,
+                    width: 5.0,
+                    ..default()
+                },
+                stencil: bevy_mod_outline::OutlineStencil {
+                    offset: 0.0,
+                    ..default()
+                },
                 ..default()
-            },
-            ..default()
-        });
+            });
+    }
 
     commands.spawn((
         PbrBundle {
@@ -717,12 +738,17 @@ fn spawn_projectiles(
     mut materials: ResMut<Assets<StandardMaterial>>,
     keyboard: Res<Input<KeyCode>>,
     player: Query<&GlobalTransform, With<RenderPlayer>>,
+    shooter: Query<Entity, (With<LogicalPlayer>, Without<Peer>)>,
 ) {
     if keyboard.just_pressed(KeyCode::F) {
         let camera = player.single();
         let velocity = 0.1 * camera.forward();
         commands.spawn((
-            Projectile { velocity },
+            Projectile {
+                velocity,
+                damage: crate::projectile::DEFAULT_DAMAGE,
+                owner: shooter.get_single().ok(),
+            },
             PbrBundle {
                 mesh: meshes.add(Mesh::from(shape::Cube { size: 0.05 })),
                 material: materials.add(Color::rgb(1.0, 0.2, 0.2).into()),
@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use bevy::ecs::system::SystemParam;
 use bevy::prelude::*;
 use bevy_rapier3d::{
     prelude::{RapierContext, Sleeping},
@@ -11,6 +12,8 @@ use serde::{Serialize, Deserialize};
 use bytemuck::{Pod, Zeroable};
 
 use crate::assets::GameState;
+use crate::casting::{CastRecord, ReplayCast};
+use crate::enemies::EnemyId;
 use crate::fps_controller::{FpsControllerInput, LogicalPlayer};
 
 pub struct NetcodePlugin;
@@ -79,6 +82,44 @@ pub struct ServerState {
 pub enum Message {
     ClientInput(ClientInput),
     ServerState(ServerState),
+    /// Sent by a peer for each spell it casts, so everyone else can replay
+    /// it.
+    SpellCast(CastRecord),
+}
+
+/// Names a character the same way on every peer, unlike an `Entity`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CharacterId {
+    Player(Peer),
+    Enemy(EnemyId),
+}
+
+/// Converts between entities and `CharacterId`s. The local player is the one
+/// player without a `Peer`, so it goes by `local`.
+#[derive(SystemParam)]
+pub struct CharacterIds<'w, 's> {
+    players: Query<'w, 's, (Entity, Option<&'static Peer>), With<LogicalPlayer>>,
+    enemies: Query<'w, 's, (Entity, &'static EnemyId)>,
+}
+
+impl CharacterIds<'_, '_> {
+    pub fn id(&self, entity: Entity, local: Option<&Peer>) -> Option<CharacterId> {
+        if let Ok((_, peer)) = self.players.get(entity) {
+            return peer.or(local).cloned().map(CharacterId::Player);
+        }
+        self.enemies.get(entity).ok().map(|(_, id)| CharacterId::Enemy(*id))
+    }
+
+    pub fn entity(&self, id: &CharacterId, local: Option<&Peer>) -> Option<Entity> {
+        match id {
+            CharacterId::Player(peer) => self.players.iter()
+                .find(|(_, player_peer)| player_peer.or(local) == Some(peer))
+                .map(|(entity, _)| entity),
+            CharacterId::Enemy(enemy) => self.enemies.iter()
+                .find(|(_, id)| *id == enemy)
+                .map(|(entity, _)| entity),
+        }
+    }
 }
 
 pub struct SessionInfo {
@@ -221,6 +262,7 @@ pub fn handle_messages(
     mut frames: ResMut<PeerFrames>,
     mut rapier_context: ResMut<RapierContext>,
     mut logical_players: Query<(Entity, &mut Transform, Option<&Peer>), With<LogicalPlayer>>,
+    mut replays: EventWriter<ReplayCast>,
 ) {
     let Some(info) = &mut session.info else {
         return;
@@ -248,6 +290,12 @@ pub fn handle_messages(
                     }
                 }
             },
+            Message::SpellCast(record) => {
+                // Peers only get to cast their own spells.
+                if record.caster == CharacterId::Player(peer) {
+                    replays.send(ReplayCast(record));
+                }
+            },
         }
     }
 
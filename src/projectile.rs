@@ -4,6 +4,8 @@ use bevy_rapier3d::prelude::{
 };
 use crate::fps_controller::LogicalPlayer;
 
+pub const DEFAULT_DAMAGE: f32 = 10.0;
+
 pub struct ProjectilePlugin;
 
 impl Plugin for ProjectilePlugin {
@@ -16,7 +18,10 @@ impl Plugin for ProjectilePlugin {
 
 #[derive(Component)]
 pub struct Projectile {
-    pub velocity: Vec3
+    pub velocity: Vec3,
+    pub damage: f32,
+    /// Whoever fired it, who gets the credit for what it kills.
+    pub owner: Option<Entity>,
 }
 
 impl Projectile {
@@ -0,0 +1,335 @@
+// Spells are cast by solving computational puzzles. Each puzzle is a set of
+// input variables for the spell together with the value it should return,
+// and the family of the puzzle decides what the spell does when cast.
+
+use crate::magic::{Context, Spec, StatusEffect, Value, Variable, VLIW};
+use crate::magic::bytecode;
+use crate::magic::compiler::Compiled;
+use crate::magic::intrinsics::IntrinsicSet;
+use crate::magic::mana::{Mana, ManaCosts};
+use crate::magic::optimizer;
+use rand::Rng;
+use rand::SeedableRng;
+use serde::{Deserialize, Serialize};
+
+/// How many numbers a sorting puzzle asks about.
+pub const SORTING_LENGTH: usize = 4;
+/// How many puzzles of each family `classify` tries a spell on.
+pub const CLASSIFICATION_SAMPLES: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Family {
+    /// Return the number of set bits in `x`.
+    Popcount,
+    /// Return 1 if `x` has an odd number of set bits, otherwise 0.
+    Parity,
+    /// Return `x` with the order of its bits reversed.
+    BitReversal,
+    /// Return the `k`th smallest of `a0`, `a1`, `a2` and `a3`, counting
+    /// from 0.
+    Sorting,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Effect {
+    FleshCircle,
+    BubblesCircle,
+    Status(StatusEffect),
+}
+
+impl Family {
+    pub const ALL: &'static [Family] = &[
+        Family::Popcount,
+        Family::Parity,
+        Family::BitReversal,
+        Family::Sorting,
+    ];
+
+    pub fn effect(self) -> Effect {
+        match self {
+            Family::Popcount => Effect::BubblesCircle,
+            Family::Parity => Effect::FleshCircle,
+            Family::BitReversal => Effect::Status(StatusEffect::Fire),
+            Family::Sorting => Effect::Status(StatusEffect::Poison),
+        }
+    }
+
+    /// The variables a spell for this family is given.
+    pub fn inputs(self) -> Vec<String> {
+        match self {
+            Family::Sorting => (0 .. SORTING_LENGTH)
+                .map(|i| format!("a{}", i))
+                .chain(std::iter::once("k".to_string()))
+                .collect(),
+            _ => vec!["x".to_string()],
+        }
+    }
+
+    /// `spell` as it's run on puzzles of this family, with whatever
+    /// redundancy the optimizer can find removed so it isn't charged for.
+    pub fn optimize(self, spell: &Spec, intrinsics: &IntrinsicSet) -> Spec {
+        let inputs: Vec<Variable> = self.inputs().iter().map(|name| Variable::new(name)).collect();
+        optimizer::optimize(spell, &inputs, intrinsics)
+    }
+
+    pub fn generate(self, rng: &mut impl Rng) -> Puzzle {
+        let mut inputs = Context::new();
+        let answer = match self {
+            Family::Popcount | Family::Parity | Family::BitReversal => {
+                // Mask a few random words together so that sparse inputs
+                // come up as often as dense ones.
+                let mut x: Value = rng.gen();
+                for _ in 0 .. rng.gen_range(0 .. 3) {
+                    x &= rng.gen::<Value>();
+                }
+                inputs.insert(Variable::new("x"), x);
+                match self {
+                    Family::Popcount => x.count_ones(),
+                    Family::Parity => x.count_ones() & 1,
+                    _ => x.reverse_bits(),
+                }
+            },
+            Family::Sorting => {
+                let mut numbers: Vec<Value> = (0 .. SORTING_LENGTH)
+                    .map(|_| rng.gen_range(0 .. 100))
+                    .collect();
+                for (i, number) in numbers.iter().enumerate() {
+                    inputs.insert(Variable::new(&format!("a{}", i)), *number);
+                }
+                let k = rng.gen_range(0 .. SORTING_LENGTH);
+                inputs.insert(Variable::new("k"), k as Value);
+                numbers.sort();
+                numbers[k]
+            },
+        };
+        Puzzle { inputs, answer }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Puzzle {
+    pub inputs: Context,
+    pub answer: Value,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PuzzleSet {
+    pub family: Family,
+    pub puzzles: Vec<Puzzle>,
+}
+
+impl PuzzleSet {
+    /// The same seed always yields the same puzzles.
+    pub fn generate(family: Family, seed: u64, count: usize) -> Self {
+        let mut rng = rand_chacha::ChaCha8Rng::seed_from_u64(seed);
+        PuzzleSet {
+            family,
+            puzzles: (0 .. count).map(|_| family.generate(&mut rng)).collect(),
+        }
+    }
+}
+
+/// How well a spell did on a `PuzzleSet`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Verdict {
+    pub solved: usize,
+    pub attempted: usize,
+    pub mana_spent: Mana,
+}
+
+impl Verdict {
+    pub fn solved_per_mana(&self) -> f32 {
+        self.solved as f32 / self.mana_spent.max(1) as f32
+    }
+
+    pub fn solved_all(&self) -> bool {
+        self.solved == self.attempted
+    }
+}
+
+/// Runs the interpreted spell on every puzzle, each with its own budget of
+/// `budget` mana, after optimizing it for their family. Puzzles the spell
+/// fails on still cost what was spent.
+pub fn verify(
+    spell: &Spec,
+    puzzles: &PuzzleSet,
+    costs: &ManaCosts,
+    intrinsics: &IntrinsicSet,
+    budget: Mana,
+) -> Verdict {
+    let spell = puzzles.family.optimize(spell, intrinsics);
+    let program = bytecode::compile(&spell, costs, intrinsics);
+    let mut verdict = Verdict::default();
+    for puzzle in &puzzles.puzzles {
+        let mut context = puzzle.inputs.clone();
+        let execution = program.run(&mut context, budget);
+        verdict.attempted += 1;
+        verdict.mana_spent += execution.mana_spent;
+        if execution.result == Ok(Some(puzzle.answer)) {
+            verdict.solved += 1;
+        }
+    }
+    verdict
+}
+
+/// Like `verify`, but runs a compiled spell on one puzzle per lane, paying
+/// for every bundle issued.
+pub fn verify_compiled(
+    spell: &Compiled,
+    vliw: &VLIW,
+    puzzles: &PuzzleSet,
+    costs: &ManaCosts,
+) -> Verdict {
+    let mut verdict = Verdict::default();
+    if vliw.check().is_err() {
+        verdict.attempted = puzzles.puzzles.len();
+        return verdict;
+    }
+    for batch in puzzles.puzzles.chunks(vliw.number_of_lanes) {
+        let contexts: Vec<Context> = batch.iter()
+            .map(|puzzle| puzzle.inputs.clone())
+            .collect();
+        verdict.attempted += batch.len();
+        verdict.mana_spent += spell.mana_cost(costs);
+        if let Ok((results, _)) = spell.run(vliw, &contexts) {
+            verdict.solved += batch.iter()
+                .zip(results)
+                .filter(|(puzzle, result)| *result == Some(puzzle.answer))
+                .count();
+        }
+    }
+    verdict
+}
+
+/// The family `spell` is a solution to, if any: the one it solves every
+/// puzzle of most cheaply, on a sample generated from `seed`.
+pub fn classify(
+    spell: &Spec,
+    seed: u64,
+    costs: &ManaCosts,
+    intrinsics: &IntrinsicSet,
+    budget: Mana,
+) -> Option<(Family, Verdict)> {
+    Family::ALL.iter()
+        .map(|family| {
+            let puzzles = PuzzleSet::generate(*family, seed, CLASSIFICATION_SAMPLES);
+            (*family, verify(spell, &puzzles, costs, intrinsics, budget))
+        })
+        .filter(|(_, verdict)| verdict.solved_all())
+        .min_by_key(|(_, verdict)| verdict.mana_spent)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::magic::compiler::compile;
+    use crate::magic::parser::parse;
+
+    const POPCOUNT: &str = "c = 0; for i in 0 ..= 31 { c = c + ((x >> i) & 1); } return c;";
+
+    fn input(puzzle: &Puzzle, name: &str) -> Value {
+        puzzle.inputs[&Variable::new(name)]
+    }
+
+    #[test]
+    fn puzzles_depend_only_on_the_seed() {
+        for family in Family::ALL {
+            let puzzles = PuzzleSet::generate(*family, 7, 32);
+            assert_eq!(puzzles, PuzzleSet::generate(*family, 7, 32));
+            assert_ne!(puzzles, PuzzleSet::generate(*family, 8, 32));
+        }
+    }
+
+    #[test]
+    fn answers_match_the_family() {
+        for family in Family::ALL {
+            for puzzle in PuzzleSet::generate(*family, 0, 64).puzzles {
+                let inputs: Vec<String> = puzzle.inputs.keys().map(|var| var.name().to_string()).collect();
+                assert_eq!(inputs, family.inputs());
+                let answer = match family {
+                    Family::Popcount => input(&puzzle, "x").count_ones(),
+                    Family::Parity => input(&puzzle, "x").count_ones() % 2,
+                    Family::BitReversal => input(&puzzle, "x").reverse_bits(),
+                    Family::Sorting => {
+                        let mut numbers: Vec<Value> = (0 .. SORTING_LENGTH)
+                            .map(|i| input(&puzzle, &format!("a{}", i)))
+                            .collect();
+                        numbers.sort();
+                        numbers[input(&puzzle, "k") as usize]
+                    },
+                };
+                assert_eq!(puzzle.answer, answer, "{:?} {:?}", family, puzzle);
+            }
+        }
+    }
+
+    #[test]
+    fn verify_charges_for_failed_puzzles() {
+        let spell = parse(POPCOUNT).unwrap();
+        let puzzles = PuzzleSet::generate(Family::Popcount, 0, 10);
+        let costs = ManaCosts::default();
+        let solved = verify(&spell, &puzzles, &costs, &IntrinsicSet::all(), 10_000);
+        assert_eq!((solved.solved, solved.attempted), (10, 10));
+
+        // Running out of mana burns the whole budget of every puzzle.
+        let starved = verify(&spell, &puzzles, &costs, &IntrinsicSet::all(), 3);
+        assert_eq!(starved, Verdict { solved: 0, attempted: 10, mana_spent: 30 });
+
+        // Wrong answers are paid for just like right ones.
+        let wrong = parse(&POPCOUNT.replace("return c;", "c = c + 1; return c;")).unwrap();
+        let verdict = verify(&wrong, &puzzles, &costs, &IntrinsicSet::all(), 10_000);
+        assert_eq!((verdict.solved, verdict.attempted), (0, 10));
+        assert!(verdict.mana_spent > solved.mana_spent);
+    }
+
+    #[test]
+    fn classify_picks_the_family_solved() {
+        let costs = ManaCosts::default();
+        let intrinsics = IntrinsicSet::all();
+        for source in [POPCOUNT, "c = popcount(x); return c;"] {
+            let spell = parse(source).unwrap();
+            let sample = PuzzleSet::generate(Family::Popcount, 5, CLASSIFICATION_SAMPLES);
+            let verdict = verify(&spell, &sample, &costs, &intrinsics, 10_000);
+            assert_eq!(classify(&spell, 5, &costs, &intrinsics, 10_000), Some((Family::Popcount, verdict)));
+        }
+        let parity = parse("c = popcount(x) & 1; return c;").unwrap();
+        assert_eq!(classify(&parity, 5, &costs, &intrinsics, 10_000).map(|(family, _)| family),
+                   Some(Family::Parity));
+    }
+
+    #[test]
+    fn classify_rejects_non_solutions() {
+        let costs = ManaCosts::default();
+        let intrinsics = IntrinsicSet::all();
+        // Right only for some puzzles.
+        let spell = parse("c = x & 1; return c;").unwrap();
+        assert_eq!(classify(&spell, 5, &costs, &intrinsics, 10_000), None);
+        // Right, but too expensive for the budget.
+        let spell = parse(POPCOUNT).unwrap();
+        assert_eq!(classify(&spell, 5, &costs, &intrinsics, 50), None);
+        // Right, but using an intrinsic that wasn't granted.
+        let spell = parse("c = popcount(x); return c;").unwrap();
+        assert_eq!(classify(&spell, 5, &costs, &IntrinsicSet::default(), 10_000), None);
+    }
+
+    #[test]
+    fn verify_compiled_solves_a_lane_per_puzzle() {
+        let vliw = VLIW::with_all_ops(64, 4, 4).unwrap();
+        let compiled = compile(&parse(POPCOUNT).unwrap(), &vliw, &IntrinsicSet::all()).unwrap();
+        let puzzles = PuzzleSet::generate(Family::Popcount, 0, 10);
+        let costs = ManaCosts::default();
+        let verdict = verify_compiled(&compiled, &vliw, &puzzles, &costs);
+        // Three batches, the last of them partly empty.
+        assert_eq!(verdict, Verdict { solved: 10, attempted: 10, mana_spent: 3 * compiled.mana_cost(&costs) });
+    }
+
+    #[test]
+    fn verify_compiled_fails_every_puzzle_on_an_invalid_machine() {
+        let vliw = VLIW::with_all_ops(64, 4, 4).unwrap();
+        let compiled = compile(&parse(POPCOUNT).unwrap(), &vliw, &IntrinsicSet::all()).unwrap();
+        let puzzles = PuzzleSet::generate(Family::Popcount, 0, 10);
+        let empty = VLIW { number_of_lanes: 0, ..vliw };
+        assert_eq!(verify_compiled(&compiled, &empty, &puzzles, &ManaCosts::default()),
+                   Verdict { solved: 0, attempted: 10, mana_spent: 0 });
+    }
+}
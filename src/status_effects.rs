@@ -0,0 +1,394 @@
+use bevy::prelude::*;
+use crate::assets::GameState;
+use crate::enemies::spellcasting::EnemySpellCast;
+use crate::fps_controller::FpsController;
+use crate::magic::StatusEffect;
+use crate::puzzle::Effect;
+use std::collections::{BTreeMap, HashMap};
+
+/// How often damage and healing over time are dealt.
+pub const TICK_SECONDS: f32 = 1.0;
+/// How long a status effect from an enemy's spell lasts.
+pub const ENEMY_STATUS_SECONDS: f32 = 8.0;
+/// Gravity is multiplied by this while under antigravity.
+pub const ANTIGRAVITY_SCALE: f32 = -0.5;
+/// The traction cutoff while walking on walls; anything short of an overhang
+/// counts as ground.
+pub const WALL_WALKING_TRACTION_CUTOFF: f32 = -0.1;
+
+pub struct StatusEffectsPlugin;
+
+impl Plugin for StatusEffectsPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .add_event::<ApplyStatusEffect>()
+            .add_event::<StatusEffectApplied>()
+            .add_event::<StatusEffectExpired>()
+            .add_event::<StatusEffectTick>()
+            .add_systems(
+                (
+                    apply_enemy_casts.run_if(in_state(GameState::Ready)),
+                    apply_status_effects.run_if(in_state(GameState::Ready)),
+                    tick_status_effects.run_if(in_state(GameState::Ready)),
+                    affect_movement.run_if(in_state(GameState::Ready)),
+                ).chain()
+            );
+    }
+}
+
+/// What happens when an effect is applied to something that already has it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Stacking {
+    /// The duration becomes whichever is longer of the old and new ones.
+    Refresh,
+    /// The new duration is added to what's left.
+    Extend,
+    /// Adds a stack, up to the given number, and refreshes the duration.
+    /// Everything the effect does is multiplied by its stacks.
+    Intensify(u32),
+}
+
+pub fn stacking(effect: StatusEffect) -> Stacking {
+    match effect {
+        StatusEffect::Fire => Stacking::Refresh,
+        StatusEffect::Poison => Stacking::Intensify(5),
+        StatusEffect::Stinging => Stacking::Intensify(10),
+        StatusEffect::Regeneration => Stacking::Extend,
+        StatusEffect::Vitality | StatusEffect::Frailty => Stacking::Intensify(3),
+        StatusEffect::Hardening | StatusEffect::Softening => Stacking::Intensify(3),
+        StatusEffect::Levitation
+            | StatusEffect::Antigravity
+            | StatusEffect::WallWalking => Stacking::Extend,
+    }
+}
+
+/// Health gained per second for each stack; negative for damage.
+pub fn health_per_second(effect: StatusEffect) -> f32 {
+    match effect {
+        StatusEffect::Fire => -4.0,
+        StatusEffect::Poison => -2.0,
+        StatusEffect::Stinging => -1.0,
+        StatusEffect::Regeneration => 2.0,
+        _ => 0.0,
+    }
+}
+
+/// Max health gained for each stack.
+pub fn max_health_per_stack(effect: StatusEffect) -> f32 {
+    match effect {
+        StatusEffect::Vitality => 25.0,
+        StatusEffect::Frailty => -25.0,
+        _ => 0.0,
+    }
+}
+
+/// Armor gained for each stack.
+pub fn armor_per_stack(effect: StatusEffect) -> f32 {
+    match effect {
+        StatusEffect::Hardening => 5.0,
+        StatusEffect::Softening => -5.0,
+        _ => 0.0,
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ActiveEffect {
+    pub stacks: u32,
+    pub remaining_seconds: f32,
+    until_tick: f32,
+}
+
+/// The status effects on an entity. Send `ApplyStatusEffect` rather than
+/// adding them here, so that `StatusEffectApplied` goes out; entities that
+/// don't have this component get one the first time.
+#[derive(Component, Clone, Debug, Default)]
+pub struct StatusEffects {
+    active: BTreeMap<StatusEffect, ActiveEffect>,
+}
+
+impl StatusEffects {
+    pub fn get(&self, effect: StatusEffect) -> Option<&ActiveEffect> {
+        self.active.get(&effect)
+    }
+
+    pub fn has(&self, effect: StatusEffect) -> bool {
+        self.active.contains_key(&effect)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (StatusEffect, &ActiveEffect)> {
+        self.active.iter().map(|(effect, active)| (*effect, active))
+    }
+
+    /// Applies `effect` according to its stacking rule and returns how many
+    /// stacks it now has.
+    pub fn apply(&mut self, effect: StatusEffect, seconds: f32) -> u32 {
+        let active = self.active.entry(effect).or_insert(ActiveEffect {
+            stacks: 0,
+            remaining_seconds: 0.0,
+            until_tick: TICK_SECONDS,
+        });
+        match stacking(effect) {
+            Stacking::Refresh => {
+                active.stacks = 1;
+                active.remaining_seconds = active.remaining_seconds.max(seconds);
+            },
+            Stacking::Extend => {
+                active.stacks = 1;
+                active.remaining_seconds += seconds;
+            },
+            Stacking::Intensify(max_stacks) => {
+                active.stacks = (active.stacks + 1).min(max_stacks);
+                active.remaining_seconds = active.remaining_seconds.max(seconds);
+            },
+        }
+        active.stacks
+    }
+
+    pub fn remove(&mut self, effect: StatusEffect) -> Option<ActiveEffect> {
+        self.active.remove(&effect)
+    }
+
+    /// Moves time on by `dt` seconds. Returns the health change of every
+    /// tick that landed, and the effects that ran out, which are removed.
+    pub fn advance(&mut self, dt: f32) -> (Vec<(StatusEffect, f32)>, Vec<StatusEffect>) {
+        let mut ticks = Vec::new();
+        let mut expired = Vec::new();
+        for (effect, active) in self.active.iter_mut() {
+            let health = health_per_second(*effect);
+            if health != 0.0 {
+                // Ticks that would land after the effect ends don't happen.
+                let mut elapsed = dt.min(active.remaining_seconds);
+                while elapsed >= active.until_tick {
+                    elapsed -= active.until_tick;
+                    active.until_tick = TICK_SECONDS;
+                    ticks.push((*effect, health * active.stacks as f32 * TICK_SECONDS));
+                }
+                active.until_tick -= elapsed;
+            }
+            active.remaining_seconds -= dt;
+            if active.remaining_seconds <= 0.0 {
+                expired.push(*effect);
+            }
+        }
+        for effect in &expired {
+            self.active.remove(effect);
+        }
+        (ticks, expired)
+    }
+
+    fn total(&self, per_stack: fn(StatusEffect) -> f32) -> f32 {
+        self.iter().map(|(effect, active)| per_stack(effect) * active.stacks as f32).sum()
+    }
+
+    pub fn max_health_modifier(&self) -> f32 {
+        self.total(max_health_per_stack)
+    }
+
+    pub fn armor_modifier(&self) -> f32 {
+        self.total(armor_per_stack)
+    }
+
+    /// What gravity is multiplied by. Antigravity beats levitation.
+    pub fn gravity_scale(&self) -> f32 {
+        if self.has(StatusEffect::Antigravity) {
+            ANTIGRAVITY_SCALE
+        } else if self.has(StatusEffect::Levitation) {
+            0.0
+        } else {
+            1.0
+        }
+    }
+
+    pub fn walks_on_walls(&self) -> bool {
+        self.has(StatusEffect::WallWalking)
+    }
+}
+
+/// Send this to put a status effect on an entity.
+#[derive(Clone, Debug)]
+pub struct ApplyStatusEffect {
+    pub target: Entity,
+    pub effect: StatusEffect,
+    pub seconds: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusEffectApplied {
+    pub entity: Entity,
+    pub effect: StatusEffect,
+    pub stacks: u32,
+}
+
+#[derive(Clone, Debug)]
+pub struct StatusEffectExpired {
+    pub entity: Entity,
+    pub effect: StatusEffect,
+}
+
+/// One tick of damage or healing over time.
+#[derive(Clone, Debug)]
+pub struct StatusEffectTick {
+    pub entity: Entity,
+    pub effect: StatusEffect,
+    /// How much health changes by; negative for damage.
+    pub health: f32,
+}
+
+// The movement settings an entity had before any status effects touched
+// them.
+#[derive(Component)]
+struct UnaffectedMovement {
+    gravity: f32,
+    traction_normal_cutoff: f32,
+}
+
+fn apply_enemy_casts(
+    mut casts: EventReader<EnemySpellCast>,
+    mut apply_events: EventWriter<ApplyStatusEffect>,
+) {
+    for cast in casts.iter() {
+        if let Effect::Status(effect) = cast.effect {
+            apply_events.send(ApplyStatusEffect {
+                target: cast.target,
+                effect,
+                seconds: ENEMY_STATUS_SECONDS,
+            });
+        }
+    }
+}
+
+fn apply_status_effects(
+    mut commands: Commands,
+    mut apply_events: EventReader<ApplyStatusEffect>,
+    mut applied_events: EventWriter<StatusEffectApplied>,
+    mut affected: Query<&mut StatusEffects>,
+) {
+    // Entities that are getting their first effects this frame. Their
+    // components only exist once the commands run.
+    let mut new: HashMap<Entity, StatusEffects> = HashMap::new();
+    for event in apply_events.iter() {
+        if commands.get_entity(event.target).is_none() {
+            continue;
+        }
+        let stacks = match affected.get_mut(event.target) {
+            Ok(mut effects) => effects.apply(event.effect, event.seconds),
+            Err(_) => new.entry(event.target).or_default().apply(event.effect, event.seconds),
+        };
+        applied_events.send(StatusEffectApplied {
+            entity: event.target,
+            effect: event.effect,
+            stacks,
+        });
+    }
+    for (entity, effects) in new {
+        commands.entity(entity).insert(effects);
+    }
+}
+
+fn tick_status_effects(
+    time: Res<Time>,
+    mut affected: Query<(Entity, &mut StatusEffects)>,
+    mut tick_events: EventWriter<StatusEffectTick>,
+    mut expired_events: EventWriter<StatusEffectExpired>,
+) {
+    let dt = time.delta_seconds();
+    for (entity, mut effects) in affected.iter_mut() {
+        let (ticks, expired) = effects.advance(dt);
+        for (effect, health) in ticks {
+            tick_events.send(StatusEffectTick { entity, effect, health });
+        }
+        for effect in expired {
+            expired_events.send(StatusEffectExpired { entity, effect });
+        }
+    }
+}
+
+fn affect_movement(
+    mut commands: Commands,
+    mut controllers: Query<(
+        Entity,
+        &StatusEffects,
+        &mut FpsController,
+        Option<&UnaffectedMovement>,
+    )>,
+) {
+    for (entity, effects, mut controller, unaffected) in controllers.iter_mut() {
+        let (gravity, traction_normal_cutoff) = match unaffected {
+            Some(unaffected) => (unaffected.gravity, unaffected.traction_normal_cutoff),
+            None => {
+                commands.entity(entity).insert(UnaffectedMovement {
+                    gravity: controller.gravity,
+                    traction_normal_cutoff: controller.traction_normal_cutoff,
+                });
+                (controller.gravity, controller.traction_normal_cutoff)
+            },
+        };
+        controller.gravity = gravity * effects.gravity_scale();
+        controller.traction_normal_cutoff = if effects.walks_on_walls() {
+            WALL_WALKING_TRACTION_CUTOFF
+        } else {
+            traction_normal_cutoff
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applying_again_follows_the_stacking_rule() {
+        let mut effects = StatusEffects::default();
+        let remaining = |effects: &StatusEffects, effect| effects.get(effect).unwrap().remaining_seconds;
+
+        // Refresh keeps the longer duration.
+        assert_eq!(effects.apply(StatusEffect::Fire, 5.0), 1);
+        assert_eq!(effects.apply(StatusEffect::Fire, 3.0), 1);
+        assert_eq!(remaining(&effects, StatusEffect::Fire), 5.0);
+        effects.apply(StatusEffect::Fire, 8.0);
+        assert_eq!(remaining(&effects, StatusEffect::Fire), 8.0);
+
+        // Extend adds up, but never stacks.
+        effects.apply(StatusEffect::Regeneration, 5.0);
+        assert_eq!(effects.apply(StatusEffect::Regeneration, 3.0), 1);
+        assert_eq!(remaining(&effects, StatusEffect::Regeneration), 8.0);
+
+        // Intensify stacks up to its cap and keeps the longer duration.
+        let stacks: Vec<u32> = (0 .. 7)
+            .map(|i| effects.apply(StatusEffect::Poison, 2.0 + i as f32))
+            .collect();
+        assert_eq!(stacks, vec![1, 2, 3, 4, 5, 5, 5]);
+        assert_eq!(remaining(&effects, StatusEffect::Poison), 8.0);
+        effects.apply(StatusEffect::Poison, 1.0);
+        assert_eq!(remaining(&effects, StatusEffect::Poison), 8.0);
+        assert_eq!(effects.apply(StatusEffect::Hardening, 1.0), 1);
+        assert_eq!(effects.armor_modifier(), 5.0);
+    }
+
+    #[test]
+    fn ticks_accumulate_until_the_effect_runs_out() {
+        let mut effects = StatusEffects::default();
+        effects.apply(StatusEffect::Poison, 10.0);
+        effects.apply(StatusEffect::Poison, 10.0);
+        effects.apply(StatusEffect::Levitation, 4.5);
+
+        // A long frame lands every tick it spans, each for every stack.
+        let poison = (StatusEffect::Poison, -4.0);
+        assert_eq!(effects.advance(3.5), (vec![poison; 3], vec![]));
+        // Short frames add up to the next tick.
+        assert_eq!(effects.advance(0.25), (vec![], vec![]));
+        assert_eq!(effects.advance(0.25), (vec![poison], vec![]));
+        // Effects that don't tick still run out.
+        assert_eq!(effects.advance(0.5), (vec![], vec![StatusEffect::Levitation]));
+        assert!(!effects.has(StatusEffect::Levitation));
+
+        // The last tick lands as the effect ends, and none after it.
+        assert_eq!(effects.advance(6.0), (vec![poison; 6], vec![StatusEffect::Poison]));
+        assert!(effects.iter().next().is_none());
+
+        // Ticks that would land after the effect ends don't.
+        effects.apply(StatusEffect::Fire, 2.5);
+        let fire = (StatusEffect::Fire, -4.0);
+        assert_eq!(effects.advance(10.0), (vec![fire; 2], vec![StatusEffect::Fire]));
+    }
+}
@@ -18,6 +18,18 @@ pub enum Key {
     PageUp,
     /// Page Down key.
     PageDown,
+    /// Shift + left arrow.
+    ShiftLeft,
+    /// Shift + right arrow.
+    ShiftRight,
+    /// Shift + up arrow.
+    ShiftUp,
+    /// Shift + down arrow.
+    ShiftDown,
+    /// Shift + Home key.
+    ShiftHome,
+    /// Shift + End key.
+    ShiftEnd,
     /// Backward Tab key.
     BackTab,
     /// Delete key.
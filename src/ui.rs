@@ -3,6 +3,8 @@ use bevy::input::mouse::{MouseWheel, MouseScrollUnit};
 use bevy::window::{CursorGrabMode, PrimaryWindow, WindowFocused};
 use bevy_egui::{egui, EguiContexts};
 use crate::assets::{GameState, ImageAssets};
+use crate::casting::CastMessage;
+use crate::damage::RunStats;
 use crate::fps_controller::FpsController;
 use crate::netcode::Peer;
 
@@ -30,7 +32,9 @@ impl Plugin for UiPlugin {
             .add_system(show_hotbar.in_schedule(OnEnter(GameState::Ready)))
             .add_system(hotbar
                         .run_if(in_state(GameState::Ready))
-                        .after(show_hotbar));
+                        .after(show_hotbar))
+            .add_system(run_stats.run_if(in_state(GameState::Ready)))
+            .add_system(cast_message.run_if(in_state(GameState::Ready)));
     }
 }
 
@@ -322,6 +326,34 @@ pub fn hotbar(
     }
 }
 
+// Kills and deaths so far, in the corner of the screen.
+pub fn run_stats(
+    mut egui_contexts: EguiContexts,
+    run_stats: Res<RunStats>,
+) {
+    egui::Area::new("run_stats")
+        .anchor(egui::Align2::LEFT_TOP, egui::vec2(8.0, 8.0))
+        .show(egui_contexts.ctx_mut(), |ui| {
+            ui.label(format!("Kills: {}  Deaths: {}", run_stats.kills, run_stats.deaths));
+        });
+}
+
+// Feedback about the last spell bound or cast, above the hotbar.
+pub fn cast_message(
+    mut egui_contexts: EguiContexts,
+    time: Res<Time>,
+    message: Res<CastMessage>,
+) {
+    if message.text.is_empty() || time.elapsed_seconds() - message.shown_at > CastMessage::SECONDS {
+        return;
+    }
+    egui::Area::new("cast_message")
+        .anchor(egui::Align2::CENTER_BOTTOM, egui::vec2(0.0, -96.0))
+        .show(egui_contexts.ctx_mut(), |ui| {
+            ui.label(&message.text);
+        });
+}
+
 pub fn show_create_or_join(
     mut commands: Commands,
     mut egui_contexts: EguiContexts,